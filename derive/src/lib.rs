@@ -0,0 +1,75 @@
+/*!
+    derive macros for [uartcat](https://docs.rs/uartcat), they are reexported by uartcat and should be used from there
+*/
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, DeriveInput, Data, Fields, Expr, LitStr, spanned::Spanned};
+
+
+/**
+    implement `uartcat::master::Mapped` for a struct whose fields are slave registers
+    
+    each field must have one of the following attributes:
+    - `#[uartcat(register = "PATH")]` where `PATH` is the slave register mapped to this field, its type must be the field's type
+    - `#[uartcat(padding)]` for a field not mapped to any register
+    
+    registers are mapped in the field order, so the mapping always matches the struct's packed layout
+*/
+#[proc_macro_derive(Mapped, attributes(uartcat))]
+pub fn derive_mapped(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match mapped(input) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+fn mapped(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let Data::Struct(data) = &input.data
+        else {return Err(syn::Error::new(input.span(), "Mapped can only be derived for structs"))};
+    let Fields::Named(fields) = &data.fields
+        else {return Err(syn::Error::new(data.fields.span(), "Mapped can only be derived for structs with named fields"))};
+    
+    let mut steps = Vec::new();
+    for field in &fields.named {
+        let ty = &field.ty;
+        let mut step = None;
+        for attribute in field.attrs.iter().filter(|attribute| attribute.path().is_ident("uartcat")) {
+            attribute.parse_nested_meta(|meta| {
+                if step.is_some() {
+                    return Err(meta.error("a field can only be mapped once"));
+                }
+                if meta.path.is_ident("register") {
+                    let register: Expr = meta.value()?.parse::<LitStr>()?.parse()?;
+                    // the register type is enforced by the explicit generic parameter
+                    step = Some(quote!{ .register::<#ty>(slave, #register) });
+                    Ok(())
+                }
+                else if meta.path.is_ident("padding") {
+                    step = Some(quote!{ .padding(<<#ty as ::packbytes::FromBytes>::Bytes as ::packbytes::ByteArray>::SIZE as u16) });
+                    Ok(())
+                }
+                else {
+                    Err(meta.error("expected `register = \"...\"` or `padding`"))
+                }
+            })?;
+        }
+        steps.push(step.ok_or_else(|| syn::Error::new(field.span(), "field must have a #[uartcat(register = \"...\")] or #[uartcat(padding)] attribute"))?);
+    }
+    
+    Ok(quote!{
+        impl #impl_generics ::uartcat::master::Mapped for #name #ty_generics #where_clause {
+            fn map(mapping: &mut ::uartcat::master::Mapping, slave: ::uartcat::master::Host) 
+                -> ::core::result::Result<::uartcat::registers::VirtualRegister<Self>, ::uartcat::master::Error> 
+            {
+                ::core::result::Result::Ok(mapping.buffer::<Self>()?
+                    #(#steps)*
+                    .build())
+            }
+        }
+    })
+}
@@ -33,11 +33,9 @@ async fn main() {
             tokio::time::sleep(Duration::from_millis(10)).await;
         }
         // change address to fixed
-        let address = 1;
-        slave.write(registers::ADDRESS, address).await.unwrap();
+        let slave = slave.assign(1).await.unwrap();
         
         // read non standard registers with fixed address
-        let slave = master.slave(Host::Fixed(address));
         for i in 0 .. 10 {
             println!("specific counter register: {}, {:?}", i, slave.read(COUNTER).await.unwrap().any().unwrap());
             tokio::time::sleep(Duration::from_millis(10)).await;
@@ -0,0 +1,544 @@
+use std::{
+    time::Duration,
+    pin::{Pin, pin},
+    future::{Future, poll_fn},
+    task::Poll,
+    };
+use futures_concurrency::future::{Race, Join};
+use futures_core::Stream;
+
+use uartcat::{
+    registers::{self, Register, SlaveRegister},
+    master::*,
+    test_util::{simulate, SimulatedSlave},
+    };
+
+
+/// size of the simulated slaves buffer
+const MEM: usize = 0x600;
+
+const COUNTER: SlaveRegister<u32> = Register::new(0x500).named("COUNTER");
+const OFFSET: SlaveRegister<u16> = Register::new(0x504).named("OFFSET");
+
+fn test<T>(count: usize, test: T)
+where T: AsyncFnOnce(&Master, &[SimulatedSlave<MEM>])
+{
+    tokio::runtime::Runtime::new() 
+    .expect("failed to create runtime")
+    .block_on(async move {
+        let (master, slaves) = simulate::<MEM>(count, device()).expect("failed to simulate bus");
+        (
+            async {
+                tokio::time::timeout(Duration::from_secs(10), test(&master, &slaves))
+                .await.expect("aborted test because took too long");
+            }, 
+            async {
+                master.run()
+                .await.expect("master communication failed");
+            },
+        ).race().await;
+    });
+}
+fn device() -> registers::Device {
+    registers::Device {
+        model: "simulated".try_into().unwrap(),
+        hardware_version: "0".try_into().unwrap(),
+        software_version: "0".try_into().unwrap(),
+        serial: "".try_into().unwrap(),
+    }
+}
+
+
+#[test]
+fn simulated_standard_registers() {
+    test(1, async |master, _| {
+        let slave = master.slave(Host::Topological(0));
+        assert_eq!(slave.read(registers::VERSION).await.unwrap().one().unwrap(), registers::PROTOCOL_VERSION);
+        let device = slave.read(registers::DEVICE).await.unwrap().one().unwrap();
+        assert_eq!(device.model.as_str().unwrap(), "simulated");
+        assert_eq!(slave.buffer_size().await.unwrap().one().unwrap(), MEM as u16);
+    });
+}
+
+#[test]
+fn simulated_read_write() {
+    test(1, async |master, slaves| {
+        slaves[0].slave().lock().await.set(COUNTER, 42);
+        let slave = master.slave(Host::Topological(0));
+        assert_eq!(slave.read(COUNTER).await.unwrap().one().unwrap(), 42);
+        slave.write(COUNTER, 43).await.unwrap().one().unwrap();
+        assert_eq!(slaves[0].slave().lock().await.get(COUNTER), 43);
+    });
+}
+
+#[test]
+fn simulated_exchange_in_place() {
+    test(1, async |master, slaves| {
+        slaves[0].slave().lock().await.set(COUNTER, 42);
+        let slave = master.slave(Host::Topological(0));
+        let mut buffer = 43_u32.to_be_bytes();
+        slave.exchange_in_place(COUNTER, &mut buffer).await.unwrap().one().unwrap();
+        assert_eq!(u32::from_be_bytes(buffer), 42);
+        assert_eq!(slaves[0].slave().lock().await.get(COUNTER), 43);
+    });
+}
+
+#[test]
+fn simulated_buffer_reuse() {
+    test(1, async |master, slaves| {
+        slaves[0].slave().lock().await.set(COUNTER, 0x0102_0304);
+        let slave = master.slave(Host::Topological(0));
+        // answer buffers of bigger commands are reused by smaller ones
+        for _ in 0 .. 20 {
+            let mut device = [0; 128];
+            slave.read_into(registers::DEVICE, &mut device).await.unwrap().one().unwrap();
+            let mut counter = [0; 4];
+            slave.read_into(COUNTER, &mut counter).await.unwrap().one().unwrap();
+            assert_eq!(counter, [1, 2, 3, 4]);
+            master.read_bytes(0, &mut counter).await.unwrap();
+        }
+        // buffers released are taken again rather than accumulating
+        assert!(master.spare_buffers() > 0);
+        assert!(master.spare_buffers() <= 4, "{} spare buffers", master.spare_buffers());
+    });
+}
+
+#[test]
+fn simulated_chain() {
+    test(3, async |master, slaves| {
+        assert_eq!(master.count_slaves().await.unwrap(), 3);
+        for (index, simulated) in slaves.iter().enumerate() {
+            simulated.slave().lock().await.set(OFFSET, 100 + index as u16);
+        }
+        let mut mapping = Mapping::new();
+        let buffer = mapping.buffer::<u64>().unwrap()
+            .register(Host::Topological(0), OFFSET)
+            .register(Host::Topological(1), OFFSET)
+            .register(Host::Topological(2), OFFSET)
+            .padding(2)
+            .build();
+        mapping.configure_all(master).await.unwrap();
+        
+        let answer = master.read(buffer).await.unwrap();
+        assert_eq!(answer.executed, 3);
+        assert_eq!(answer.data >> 16, (100 << 32) | (101 << 16) | 102);
+    });
+}
+
+#[test]
+fn simulated_outstanding() {
+    test(1, async |master, _| {
+        assert!(master.is_idle().await);
+        let stream = master.slave(Host::Topological(0)).stream(COUNTER).await.unwrap();
+        assert_eq!(master.outstanding().await, 1);
+        stream.send_read().await.unwrap();
+        stream.receive().await.unwrap().one().unwrap();
+        drop(stream);
+        assert!(master.is_idle().await);
+    });
+}
+
+#[test]
+fn simulated_cancel() {
+    test(1, async |master, _| {
+        let slave = master.slave(Host::Topological(0));
+        // a task waiting for an answer is woken by the cancellation
+        let stream = slave.stream(COUNTER).await.unwrap();
+        let (received, ()) = (stream.receive(), master.cancel(stream.token())).join().await;
+        assert!(matches!(received, Err(Error::Cancelled)));
+        // cancelling the stream itself releases its token
+        stream.send_read().await.unwrap();
+        stream.cancel().await;
+        assert!(master.is_idle().await);
+        assert_eq!(slave.read(COUNTER).await.unwrap().one().unwrap(), 0);
+    });
+}
+
+#[test]
+fn simulated_drop_in_flight() {
+    test(1, async |master, _| {
+        let slave = master.slave(Host::Topological(0));
+        // a command dropped while in flight releases its token
+        {
+            let mut read = pin!(slave.read(COUNTER));
+            assert!(poll_fn(|context| Poll::Ready(read.as_mut().poll(context).is_pending())).await);
+        }
+        assert!(master.is_idle().await);
+        assert_eq!(slave.read(COUNTER).await.unwrap().one().unwrap(), 0);
+    });
+}
+
+#[test]
+fn simulated_protocol_version() {
+    test(2, async |master, slaves| {
+        assert_eq!(master.check_versions().await.unwrap(), 2);
+        slaves[1].slave().lock().await.set(registers::VERSION, registers::PROTOCOL_VERSION + 1);
+        assert!(master.slave(Host::Topological(0)).check_version().await.is_ok());
+        assert!(matches!(
+            master.check_versions().await, 
+            Err(Error::IncompatibleVersion(version)) if version == registers::PROTOCOL_VERSION + 1,
+            ));
+    });
+}
+
+#[test]
+fn simulated_benchmark() {
+    test(2, async |master, _| {
+        let report = master.benchmark(1 .. 40).await.unwrap();
+        let sizes = report.samples.iter().map(|sample| sample.size).collect::<Vec<_>>();
+        assert_eq!(sizes, [1, 2, 4, 8, 16, 32]);
+        assert!(report.samples.iter().all(|sample| sample.timeouts == 0 && !sample.duration.is_zero()));
+        assert!(report.overhead.is_some());
+    });
+}
+
+#[test]
+fn simulated_timeout_model() {
+    tokio::runtime::Runtime::new() 
+    .expect("failed to create runtime")
+    .block_on(async {
+        let (mut master, _slaves) = simulate::<MEM>(2, device()).expect("failed to simulate bus");
+        let (base, per_hop) = (Duration::from_millis(10), Duration::from_millis(5));
+        master.set_timeout_model(base, per_hop);
+        assert_eq!(master.timeout_for(&Address::Topological(3, 0).command()), base + 3*per_hop);
+        // the chain length is unknown until a virtual command is answered
+        assert_eq!(master.timeout_for(&Address::Fixed(3, 0).command()), base);
+        (
+            async {
+                assert_eq!(master.count_slaves().await.unwrap(), 2);
+                assert_eq!(master.timeout_for(&Address::Fixed(3, 0).command()), base + 2*per_hop);
+                assert_eq!(master.timeout_for(&Address::Virtual(0).command()), base + 2*per_hop);
+            },
+            async {master.run().await.expect("master communication failed");},
+        ).race().await;
+    });
+}
+
+#[test]
+fn simulated_stale_answers() {
+    test(1, async |master, _| {
+        let slave = master.slave(Host::Topological(0));
+        slave.write(OFFSET, 1).await.unwrap().one().unwrap();
+        let stream = slave.stream(OFFSET).await.unwrap();
+        stream.send_exchange(10).await.unwrap();
+        stream.send_exchange(20).await.unwrap();
+        // the answer of the first exchange is outdated once the second is sent
+        assert_eq!(stream.receive().await.unwrap().one().unwrap(), 10);
+        assert!(stream.try_receive().is_none());
+        assert_eq!(stream.outstanding().await, 0);
+    });
+}
+
+#[test]
+fn simulated_assign() {
+    test(2, async |master, _| {
+        master.slave(Host::Topological(1)).write(OFFSET, 42).await.unwrap().one().unwrap();
+        let slave = master.slave(Host::Topological(1)).assign(7).await.unwrap();
+        assert_eq!(slave.address(), Host::Fixed(7));
+        assert_eq!(slave.read(OFFSET).await.unwrap().one().unwrap(), 42);
+        // the fixed handle can assign again
+        let slave = slave.assign(8).await.unwrap();
+        assert_eq!(slave.read(OFFSET).await.unwrap().one().unwrap(), 42);
+        assert!(matches!(master.slave(Host::Fixed(7)).assign(9).await, Err(Error::NoSlave)));
+        assert!(master.slave(Host::Topological(0)).assign(registers::UNASSIGNED).await.is_err());
+    });
+}
+
+#[test]
+fn simulated_update() {
+    test(1, async |master, slaves| {
+        let slave = master.slave(Host::Topological(0));
+        slave.write(OFFSET, 0b1001).await.unwrap().one().unwrap();
+        let previous = slave.update(OFFSET, |value| *value |= 0b10).await.unwrap().one().unwrap();
+        assert_eq!(previous, 0b1001);
+        assert_eq!(slave.read(OFFSET).await.unwrap().one().unwrap(), 0b1011);
+        // a change made by the slave between the read and the write is not lost
+        let mut calls = 0;
+        let previous = slave.update(OFFSET, |value| {
+            if calls == 0 {
+                slaves[0].slave().try_lock().unwrap().set(OFFSET, 0b1111);
+            }
+            calls += 1;
+            *value &= !0b1;
+        }).await.unwrap().one().unwrap();
+        assert_eq!((previous, calls), (0b1111, 2));
+        assert_eq!(slave.read(OFFSET).await.unwrap().one().unwrap(), 0b1110);
+    });
+}
+
+#[test]
+fn simulated_range_of_slaves() {
+    test(4, async |master, slaves| {
+        for (index, slave) in slaves.iter().enumerate() {
+            slave.slave().lock().await.set(OFFSET, index as u16 * 10);
+        }
+        let answers = master.read_range_of_slaves(1 .. 3, OFFSET).await.unwrap();
+        assert_eq!(answers, [(1, Answer {data: 10, executed: 1}), (2, Answer {data: 20, executed: 1})]);
+        // the range stops at the end of the bus
+        let answers = master.read_range_of_slaves(2 .. 8, OFFSET).await.unwrap();
+        assert_eq!(answers.iter().map(|(index, _)| *index).collect::<Vec<_>>(), [2, 3]);
+    });
+}
+
+#[test]
+fn simulated_bus_stall() {
+    tokio::runtime::Runtime::new() 
+    .expect("failed to create runtime")
+    .block_on(async {
+        let (mut master, slaves) = simulate::<MEM>(1, device()).expect("failed to simulate bus");
+        master.set_timeout_model(Duration::from_millis(20), Duration::ZERO);
+        master.set_stall_timeout(Some(Duration::from_millis(100)));
+        (
+            async {
+                // a slave stuck with its buffer locked jams the chain
+                let _stuck = slaves[0].slave().lock().await;
+                let slave = master.slave(Host::Topological(0));
+                assert!(matches!(slave.read(COUNTER).await, Err(Error::Timeout)));
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                assert!(matches!(slave.read(COUNTER).await, Err(Error::BusStall)));
+            },
+            async {master.run().await.expect("master communication failed");},
+        ).race().await;
+    });
+}
+
+#[test]
+fn simulated_direction_control() {
+    // 8 data bits, even parity and one stop bit
+    assert_eq!(SerialConfig::new(100_000).character_time(), Duration::from_micros(110));
+    tokio::runtime::Runtime::new() 
+    .expect("failed to create runtime")
+    .block_on(async {
+        let (mut master, _slaves) = simulate::<MEM>(1, device()).expect("failed to simulate bus");
+        let switches = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = switches.clone();
+        master.set_direction_control(move |transmit| recorded.lock().unwrap().push(transmit), Duration::from_micros(50));
+        (
+            async {
+                master.slave(Host::Topological(0)).read(registers::VERSION).await.unwrap().one().unwrap();
+                assert_eq!(*switches.lock().unwrap(), [true, false]);
+            },
+            async {master.run().await.expect("master communication failed");},
+        ).race().await;
+    });
+}
+
+#[test]
+fn simulated_direction_released() {
+    tokio::runtime::Runtime::new() 
+    .expect("failed to create runtime")
+    .block_on(async {
+        let (mut master, _slaves) = simulate::<MEM>(1, device()).expect("failed to simulate bus");
+        let switches = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = switches.clone();
+        master.set_direction_control(move |transmit| recorded.lock().unwrap().push(transmit), Duration::from_millis(100));
+        // the command is given up while the driver is enabled
+        let slave = master.slave(Host::Topological(0));
+        assert!(tokio::time::timeout(Duration::from_millis(10), slave.read(registers::VERSION)).await.is_err());
+        assert_eq!(*switches.lock().unwrap(), [true, false]);
+    });
+}
+
+#[test]
+fn simulated_read_enum() {
+    test(1, async |master, _| {
+        const STATE: SlaveRegister<registers::CommandError> = Register::new(0x506).named("STATE");
+        let slave = master.slave(Host::Topological(0));
+        slave.write(STATE, registers::CommandError::InvalidSize).await.unwrap().one().unwrap();
+        assert_eq!(slave.read_enum(STATE).await.unwrap().one().unwrap(), registers::CommandError::InvalidSize);
+        // no variant for this value, so plain reads fall back to unknown
+        slave.write(SlaveRegister::<u8>::new(STATE.address()), 42).await.unwrap().one().unwrap();
+        assert_eq!(slave.read(STATE).await.unwrap().one().unwrap(), registers::CommandError::Unknown);
+        assert!(matches!(slave.read_enum(STATE).await, Err(Error::InvalidEnum(42))));
+    });
+}
+
+#[test]
+fn simulated_chunked_reads() {
+    tokio::runtime::Runtime::new() 
+    .expect("failed to create runtime")
+    .block_on(async {
+        let (mut master, _slaves) = simulate::<MEM>(1, device()).expect("failed to simulate bus");
+        master.set_max_command(4).unwrap();
+        (
+            async {
+                let slave = master.slave(Host::Topological(0));
+                slave.write(COUNTER, 0x0102_0304).await.unwrap().one().unwrap();
+                slave.write(OFFSET, 0x0506).await.unwrap().one().unwrap();
+                // commands of exactly the maximum size are sent, bigger ones are rejected
+                assert!(matches!(slave.read_bytes(COUNTER.address(), &mut [0; 5]).await, Err(Error::Master(_))));
+                // several commands of 4 bytes are needed
+                let dump = slave.dump_range(COUNTER.address(), 6).await;
+                assert!(dump.error.is_none());
+                assert_eq!(dump.data, [1, 2, 3, 4, 5, 6]);
+                let dump = slave.dump_range(MEM as u16 - 2, 4).await;
+                assert!(dump.error.is_some());
+                assert_eq!(dump.data.len(), 0);
+            },
+            async {master.run().await.expect("master communication failed");},
+        ).race().await;
+    });
+}
+
+/// next item of a stream
+async fn next<S: Stream + Unpin>(stream: &mut S) -> Option<S::Item> {
+    poll_fn(|context| Pin::new(&mut *stream).poll_next(context)).await
+}
+
+#[test]
+fn simulated_subscribe_stream() {
+    test(1, async |master, _| {
+        let slave = master.slave(Host::Topological(0));
+        slave.write(COUNTER, 42).await.unwrap().one().unwrap();
+        let mut subscription = master.subscribe(Host::Topological(0), COUNTER, Duration::from_millis(5)).await.unwrap();
+        for _ in 0 .. 3 {
+            let answer = next(&mut subscription).await.unwrap().unwrap();
+            assert_eq!(answer.one().unwrap(), 42);
+        }
+        // values can still be awaited directly after being streamed
+        assert_eq!(subscription.next().await.unwrap().one().unwrap(), 42);
+    });
+}
+
+#[test]
+fn simulated_topology_stream() {
+    test(2, async |master, _| {
+        let mut watch = master.watch_topology(Duration::from_millis(5)).await.unwrap();
+        assert_eq!(watch.count(), 2);
+        // an unchanged bus yields no event
+        assert!(tokio::time::timeout(Duration::from_millis(50), next(&mut watch)).await.is_err());
+        assert_eq!(watch.count(), 2);
+    });
+}
+
+#[test]
+fn simulated_subscribe_zero_period() {
+    test(1, async |master, _| {
+        assert!(matches!(
+            master.subscribe(Host::Topological(0), COUNTER, Duration::ZERO).await, 
+            Err(Error::Master(_)),
+            ));
+    });
+}
+
+#[test]
+fn simulated_ping() {
+    test(2, async |master, _| {
+        assert_eq!(master.ping(Host::Topological(1)).await.unwrap(), 1);
+        assert_eq!(master.ping(Host::Topological(2)).await.unwrap(), 0);
+        master.slave(Host::Topological(0)).assign(3).await.unwrap();
+        assert_eq!(master.ping(Host::Fixed(3)).await.unwrap(), 1);
+        assert_eq!(master.ping(Host::Fixed(4)).await.unwrap(), 0);
+    });
+}
+
+#[test]
+fn simulated_probe() {
+    test(2, async |master, _| {
+        master.probe(Host::Topological(1), 3).await.unwrap();
+        assert!(matches!(master.probe(Host::Topological(2), 3).await, Err(Error::NoSlave)));
+        assert!(matches!(master.probe(Host::Fixed(0x42), 0).await, Err(Error::Timeout)));
+    });
+}
+
+#[test]
+fn simulated_write_checked() {
+    test(2, async |master, slaves| {
+        let mut mapping = Mapping::new();
+        let buffer = mapping.buffer::<u32>().unwrap()
+            .register(Host::Topological(0), OFFSET)
+            .register(Host::Topological(1), OFFSET)
+            .build();
+        mapping.configure_all(master).await.unwrap();
+        mapping.write_checked(master, buffer, 0x0001_0002, 2).await.unwrap();
+        assert_eq!(master.slave(Host::Topological(1)).read(OFFSET).await.unwrap().one().unwrap(), 2);
+        // a missing slave did not apply the write
+        assert!(matches!(mapping.write_checked(master, buffer, 0, 3).await, Err(Error::Master("partial write"))));
+        // slaves reporting an error are told
+        slaves[1].slave().lock().await.set(registers::ERROR, registers::CommandError::Checksum);
+        assert!(matches!(
+            mapping.write_checked(master, buffer, 0, 3).await, 
+            Err(Error::PartialWrite(dropped)) if dropped == [(Host::Topological(1), registers::CommandError::Checksum)],
+            ));
+        assert!(matches!(master.write_expected(buffer, 0, 3).await, Err(Error::Master("partial write"))));
+    });
+}
+
+#[test]
+fn simulated_shutdown() {
+    tokio::runtime::Runtime::new() 
+    .expect("failed to create runtime")
+    .block_on(async {
+        let (master, _slaves) = simulate::<MEM>(1, device()).expect("failed to simulate bus");
+        let slave = master.slave(Host::Topological(0));
+        let stream = slave.stream(COUNTER).await.unwrap();
+        // the master stops between frames and fails the commands still waiting
+        let (run, received) = (
+            master.run(),
+            async {
+                assert_eq!(slave.read(COUNTER).await.unwrap().one().unwrap(), 0);
+                (stream.receive(), async {master.shutdown()}).join().await.0
+            },
+        ).join().await;
+        run.expect("master communication failed");
+        assert!(matches!(received, Err(Error::Disconnected)));
+    });
+}
+
+#[test]
+fn simulated_receive_raw() {
+    test(1, async |master, slaves| {
+        slaves[0].slave().lock().await.set(COUNTER, 42);
+        let slave = master.slave(Host::Topological(0));
+        let stream = slave.stream(COUNTER).await.unwrap();
+        stream.send_read().await.unwrap();
+        let (header, data, executed) = stream.receive_raw().await.unwrap();
+        assert_eq!(executed, 1);
+        assert!(!header.access.error());
+        assert_eq!(data, 42u32.to_be_bytes());
+        // errors reported by the slave are left in the header
+        let outside = slave.stream(SlaveRegister::<u32>::new(MEM as u16)).await.unwrap();
+        outside.send_read().await.unwrap();
+        let (header, _, _) = outside.receive_raw().await.unwrap();
+        assert!(header.access.error());
+        assert_eq!(header.access.command_error(), registers::CommandError::InvalidRegister);
+    });
+}
+
+#[test]
+fn simulated_idle_since() {
+    test(1, async |master, _| {
+        assert_eq!(master.idle_since(), None);
+        master.slave(Host::Topological(0)).read(registers::VERSION).await.unwrap().one().unwrap();
+        let idle = master.idle_since().unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(master.idle_since().unwrap() >= idle + Duration::from_millis(20));
+    });
+}
+
+#[test]
+fn simulated_process_images() {
+    test(1, async |master, slaves| {
+        let host = Host::Topological(0);
+        slaves[0].slave().lock().await.set(COUNTER, 42);
+        slaves[0].slave().lock().await.set(OFFSET, 7);
+        let mut fast = Mapping::new();
+        let fast_buffer = fast.buffer::<u32>().unwrap().register(host, COUNTER).build();
+        let mut slow = Mapping::new_at(0x1000);
+        let slow_buffer = slow.buffer::<u16>().unwrap().register(host, OFFSET).build();
+        assert!(fast.clone().merge(&Mapping::new_at(2)).is_ok());
+        let mut overlapping = Mapping::new_at(2);
+        overlapping.buffer::<u16>().unwrap().padding(2).build();
+        assert!(fast.clone().merge(&overlapping).is_err());
+        fast.merge(&slow).unwrap();
+        fast.configure(&master.slave(host)).await.unwrap();
+        
+        // each image can be streamed on its own
+        let fast_stream = master.stream(fast_buffer).await.unwrap();
+        let slow_stream = master.stream(slow_buffer).await.unwrap();
+        fast_stream.send_read().await.unwrap();
+        slow_stream.send_read().await.unwrap();
+        assert_eq!(fast_stream.receive().await.unwrap().one().unwrap(), 42);
+        assert_eq!(slow_stream.receive().await.unwrap().one().unwrap(), 7);
+    });
+}
@@ -7,7 +7,7 @@ use packbytes::{FromBytes, ToBytes};
 use serial_test::serial;
 
 use uartcat::{
-    registers::{self, Register, SlaveRegister, VirtualSize},
+    registers::{self, Register, SlaveRegister, SlaveSize, VirtualSize},
     master::*,
     };
 
@@ -16,11 +16,22 @@ fn test<T, F>(test: T)
 where 
     T: FnOnce(Arc<Master>) -> F,
     F: Future,
+{
+    test_configured(|_| {}, test)
+}
+/// same as [test] but with settings applied to the master before running it
+fn test_configured<C, T, F>(configure: C, test: T)
+where 
+    C: FnOnce(&mut Master),
+    T: FnOnce(Arc<Master>) -> F,
+    F: Future,
 {
     tokio::runtime::Runtime::new() 
     .expect("failed to create runtime")
     .block_on(async move {
-        let master = Arc::new(Master::new("/dev/ttyUSB1", 1_500_000) .expect("failed to initialize master"));
+        let mut master = Master::new("/dev/ttyUSB1", 1_500_000) .expect("failed to initialize master");
+        configure(&mut master);
+        let master = Arc::new(master);
         (
             async {
                 tokio::time::timeout(Duration::from_secs(10), test(master.clone()))
@@ -35,14 +46,16 @@ where
 }
 
 // declare some application-specific registers expected on the slave
-const COUNTER: SlaveRegister<u32> = Register::new(0x500);
-const OFFSET: SlaveRegister<u16> = Register::new(0x504);
-const OFFSETED: SlaveRegister<u32> = Register::new(0x512);
+const COUNTER: SlaveRegister<u32> = Register::new(0x500).named("COUNTER");
+const OFFSET: SlaveRegister<u16> = Register::new(0x504).named("OFFSET");
+const OFFSETED: SlaveRegister<u32> = Register::new(0x512).named("OFFSETED");
 
 // buffer with a different layout
-#[derive(FromBytes, ToBytes, Default, Clone, Debug)]
+#[derive(FromBytes, ToBytes, Mapped, Default, Clone, Debug)]
 pub struct MyBuffer {
+    #[uartcat(register = "OFFSETED")]
     pub offseted: u32,
+    #[uartcat(register = "OFFSET")]
     pub offset: u16,
 }
 // buffer with a different layout
@@ -62,11 +75,19 @@ fn addresses_topological_fixed() {
             master.slave(Host::Topological(0)).write(registers::ADDRESS, fixed).await.unwrap().one().unwrap();
             
             let slave = master.slave(Host::Topological(0));
-            assert_eq!(slave.read(registers::VERSION).await.unwrap().one().unwrap(), 1);
+            assert_eq!(slave.read(registers::VERSION).await.unwrap().one().unwrap(), registers::PROTOCOL_VERSION);
             
             let slave = master.slave(Host::Fixed(fixed));
-            assert_eq!(slave.read(registers::VERSION).await.unwrap().one().unwrap(), 1);
+            assert_eq!(slave.read(registers::VERSION).await.unwrap().one().unwrap(), registers::PROTOCOL_VERSION);
+        }
+        // unassigned slaves are not reachable with fixed addresses
+        master.slave(Host::Topological(0)).write(registers::ADDRESS, registers::UNASSIGNED).await.unwrap().one().unwrap();
+        for fixed in [0, 3, registers::UNASSIGNED] {
+            let answer = master.slave(Host::Fixed(fixed)).read(registers::VERSION).await.unwrap();
+            assert_eq!(answer.executed, 0);
+            assert!(!master.slave(Host::Fixed(fixed)).present(3).await.unwrap());
         }
+        assert!(master.slave(Host::Topological(0)).present(3).await.unwrap());
     });
 }
 
@@ -76,8 +97,7 @@ fn standard_registers() {
     test(|master| async move {
         let slave = master.slave(Host::Topological(0));
         
-        slave.write(registers::LOSS, 0).await.unwrap().one().unwrap();
-        slave.write(registers::ERROR, registers::CommandError::None).await.unwrap().one().unwrap();
+        slave.reset().await.unwrap().one().unwrap();
         
         let device = slave.read(registers::DEVICE).await.unwrap().one().unwrap();
         assert_eq!(device.model.as_str().unwrap(), "esp32-test"); 
@@ -123,6 +143,283 @@ fn read_write_while_updating() {
     });
 }
 
+#[test]
+#[serial]
+fn read_many_registers() {
+    test(|master| async move {
+        let slave = master.slave(Host::Topological(0));
+        
+        slave.write(OFFSET, 12).await.unwrap().one().unwrap();
+        let (counter, offset, offseted) = slave.read_many((COUNTER, OFFSET, OFFSETED)).await.unwrap().one().unwrap();
+        assert_eq!(offset, 12);
+        assert!(offseted.wrapping_sub(counter) <= 13, "registers read from different cycles");
+    });
+}
+
+#[test]
+#[serial]
+fn pipeline_commands() {
+    test(|master| async move {
+        let slave = Host::Topological(0);
+        let results = master.pipeline([
+            PreparedCommand::write(slave.at(OFFSET.address()), 7u16.to_be_bytes().to_vec()),
+            PreparedCommand::read(slave.at(registers::VERSION.address()), registers::VERSION.size()),
+            PreparedCommand::read(slave.at(registers::DEVICE.address()), registers::DEVICE.size()),
+        ]).await;
+        let results = results.into_iter()
+            .map(|result| result.unwrap().one().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(results[1], [1]);
+        assert_eq!(results[2].len(), usize::from(registers::DEVICE.size()));
+    });
+}
+
+#[test]
+#[serial]
+fn topology_count() {
+    test(|master| async move {
+        assert_eq!(master.count_slaves().await.unwrap(), 1);
+        let watch = master.watch_topology(Duration::from_millis(10)).await.unwrap();
+        assert_eq!(watch.count(), 1);
+    });
+}
+
+#[test]
+#[serial]
+fn bus_diagnostics() {
+    test(|master| async move {
+        master.reset_diagnostics().await.unwrap();
+        let diagnostics = master.diagnostics().await.unwrap();
+        assert_eq!(diagnostics.slaves.len(), 1);
+        assert_eq!(diagnostics.total_loss, 0);
+        assert!(diagnostics.error.is_none());
+    });
+}
+
+#[test]
+#[serial]
+fn chain_check() {
+    test(|master| async move {
+        let report = master.check_chain(1).await.unwrap();
+        assert!(report.complete());
+        assert_eq!(report.addresses.len(), 1);
+        
+        let report = master.check_chain(2).await.unwrap();
+        assert_eq!(report.broken, Some(1));
+    });
+}
+
+#[test]
+#[serial]
+fn mapping_reserved_rejected() {
+    test(|master| async move {
+        let slave = master.slave(Host::Topological(0));
+        slave.write(registers::ERROR, registers::CommandError::None).await.unwrap().one().unwrap();
+        
+        // standard registers cannot be mapped
+        let mut mapping = Mapping::new();
+        mapping.buffer::<u8>().unwrap()
+            .register(slave.address(), registers::VERSION)
+            .build();
+        assert!(matches!(mapping.configure(&slave).await, Err(Error::Master(_))));
+        // the slave also rejects it if the master check is bypassed
+        let table = registers::MappingTable::from_iter(mapping.map()[&slave.address()].iter().copied()).unwrap();
+        slave.write(registers::MAPPING, table).await.unwrap().one().unwrap();
+        
+        let error = slave.read(registers::ERROR).await.unwrap().one().unwrap();
+        assert_eq!(error, registers::CommandError::InvalidMapping);
+        
+        // registers out of slave memory cannot be mapped
+        let mut mapping = Mapping::new();
+        mapping.buffer::<u8>().unwrap()
+            .register(slave.address(), SlaveRegister::<u8>::new(u16::MAX))
+            .build();
+        assert!(matches!(mapping.configure(&slave).await, Err(Error::Master(_))));
+        
+        Mapping::new().configure(&slave).await.unwrap();
+        slave.write(registers::ERROR, registers::CommandError::None).await.unwrap().one().unwrap();
+    });
+}
+
+#[test]
+#[serial]
+fn streaming_nonblocking() {
+    test(|master| async move {
+        let stream = master.slave(Host::Topological(0)).stream(COUNTER).await.unwrap();
+        assert!(stream.try_receive().is_none());
+        
+        stream.send_read().await.unwrap();
+        stream.receive_timeout(Duration::from_millis(500)).await.unwrap().one().unwrap();
+        assert!(stream.try_receive().is_none());
+        
+        stream.send_read().await.unwrap();
+        let received = loop {
+            if let Some(received) = stream.try_receive() 
+                {break received}
+            tokio::task::yield_now().await;
+        };
+        received.unwrap().one().unwrap();
+    });
+}
+
+#[test]
+#[serial]
+fn streaming_depth() {
+    test(|master| async move {
+        let stream = Stream::<_, SlaveSize>::new(&master, Host::Topological(0), COUNTER, 1).await.unwrap();
+        stream.send_read().await.unwrap();
+        // the answer is not received yet, so a second send cannot be outstanding
+        assert!(matches!(stream.send_read().await, Err(Error::Timeout)));
+        assert_eq!(stream.outstanding().await, 1);
+        stream.receive().await.unwrap().one().unwrap();
+        assert_eq!(stream.outstanding().await, 0);
+        stream.send_read().await.unwrap();
+        stream.receive().await.unwrap().one().unwrap();
+    });
+}
+
+#[derive(FromBytes, ToBytes, Mapped)]
+struct PaddedBuffer {
+    #[uartcat(register = "OFFSET")]
+    offset: u16,
+    #[uartcat(padding)]
+    _reserved: [u8; 3],
+    #[uartcat(register = "OFFSETED")]
+    offseted: u32,
+}
+
+#[test]
+fn offline_mapped() {
+    let host = Host::Topological(0);
+    let mut mapping = Mapping::new();
+    mapping.buffer::<u8>().unwrap().padding(1).build();
+    let buffer = mapping.mapped::<PaddedBuffer>(host).unwrap();
+    assert_eq!(buffer.address(), 1);
+    assert_eq!(mapping.map()[&host], [
+        registers::Mapping {slave_start: OFFSET.address(), virtual_start: 1, size: 2},
+        registers::Mapping {slave_start: OFFSETED.address(), virtual_start: 6, size: 4},
+        ]);
+}
+
+#[test]
+#[should_panic(expected = "mapping set has padding")]
+fn offline_mapping_dense() {
+    let host = Host::Topological(0);
+    let mut mapping = Mapping::new();
+    mapping.buffer::<MyBuffer>().unwrap()
+        .register(host, OFFSETED)
+        .register(host, OFFSET)
+        .build_dense();
+    mapping.buffer::<MyBuffer>().unwrap()
+        .register(host, OFFSETED)
+        .padding(2)
+        .build_dense();
+}
+
+#[test]
+fn offline_mapping_coverage() {
+    let mut mapping = Mapping::new();
+    let buffer = mapping.buffer::<MyBuffer>().unwrap()
+        .register(Host::Topological(0), OFFSET)
+        .padding(2)
+        .register(Host::Topological(1), OFFSET)
+        .build();
+    assert_eq!(mapping.coverage(buffer), [0 .. 2, 4 .. 6]);
+    let dense = mapping.buffer::<MyBuffer>().unwrap()
+        .register(Host::Topological(0), OFFSETED)
+        .register(Host::Topological(1), OFFSET)
+        .build_dense();
+    assert_eq!(mapping.coverage(dense), [0 .. 6]);
+}
+
+#[test]
+fn offline_mapping_hosts() {
+    let mut mapping = Mapping::new();
+    let first = mapping.buffer::<MyBuffer>().unwrap()
+        .register(Host::Topological(1), OFFSETED)
+        .register(Host::Topological(0), OFFSET)
+        .build();
+    let second = mapping.buffer::<u16>().unwrap()
+        .register(Host::Fixed(3), OFFSET)
+        .build();
+    assert_eq!(mapping.hosts(first), [Host::Topological(0), Host::Topological(1)]);
+    assert_eq!(mapping.hosts(second), [Host::Fixed(3)]);
+    // a register at the end of the virtual memory does not overflow
+    let last = registers::VirtualRegister::<u32>::new(u32::MAX - 1);
+    assert!(mapping.hosts(last).is_empty());
+}
+
+#[test]
+fn offline_mapping_plan() {
+    let mut mapping = Mapping::new();
+    mapping.buffer::<MyBuffer>().unwrap()
+        .register(Host::Topological(1), OFFSETED)
+        .register(Host::Topological(0), OFFSET)
+        .build();
+    mapping.buffer::<u8>().unwrap().padding(1).build();
+    let plan = mapping.plan();
+    assert!(plan.valid());
+    assert_eq!(plan.size, 7);
+    assert_eq!(plan.tables, [
+        (Host::Topological(0), vec![registers::Mapping {slave_start: OFFSET.address(), virtual_start: 4, size: 2}]),
+        (Host::Topological(1), vec![registers::Mapping {slave_start: OFFSETED.address(), virtual_start: 0, size: 4}]),
+        ]);
+    
+    mapping.buffer::<u8>().unwrap()
+        .register(Host::Fixed(2), registers::VERSION)
+        .build();
+    assert_eq!(mapping.plan().warnings, [
+        PlanWarning::StandardRegister {host: Host::Fixed(2), slave_start: registers::VERSION.address()},
+        ]);
+}
+
+#[test]
+fn offline_unsupported_stop_bits() {
+    let config = SerialConfig {stop_bits: StopBits::OnePointFive, .. SerialConfig::new(1_500_000)};
+    let error = Master::with_config("/dev/null", config).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn offline_unsupported_data_bits() {
+    for data_bits in [DataBits::Seven, DataBits::Nine] {
+        let config = SerialConfig {data_bits, .. SerialConfig::new(1_500_000)};
+        let error = Master::with_config("/dev/null", config).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
+
+#[test]
+fn offline_parity_needs_crc() {
+    let config = SerialConfig {parity: Parity::None, .. SerialConfig::new(1_500_000)};
+    let error = Master::with_config("/dev/null", config).unwrap_err();
+    if uartcat::command::TRAILER == 0 {
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
+
+#[test]
+fn offline_answer() {
+    let answer = || Answer {data: 42u32, executed: 2};
+    assert_eq!(answer().at_least(1).unwrap(), 42);
+    assert_eq!(answer().at_least(2).unwrap(), 42);
+    assert!(answer().at_least(3).is_err());
+    assert_eq!(answer().all(2).unwrap(), 42);
+    assert!(answer().all(3).is_err());
+    assert!(matches!(Answer {data: (), executed: 0}.at_least(1), Err(Error::NoSlave)));
+    assert!(matches!(Answer {data: (), executed: 0}.one(), Err(Error::NoSlave)));
+    assert!(!Answer {data: (), executed: 0}.present());
+    assert!(answer().present());
+    assert_eq!(answer().executed(), 2);
+    assert_eq!(format!("{:?}", answer()), "Answer { data: 42, executed: 2 }");
+    
+    let mapped = answer().map(|data| data + 1);
+    assert_eq!((mapped.data, mapped.executed), (43, 2));
+    let mapped = answer().and_then(|data| u8::try_from(data).map_err(|_| Error::Master("overflow"))).unwrap();
+    assert_eq!((mapped.data, mapped.executed), (42, 2));
+    assert!(answer().and_then(|_| Err::<(), _>(Error::Timeout)).is_err());
+}
+
 #[test]
 fn offline_mapping() {
     // create a mapping to gather many registers
@@ -179,25 +476,198 @@ fn streaming_virtual() {
         let slave = master.slave(Host::Topological(0));
     
         let mut mapping = Mapping::new();
-        let buffer = mapping.buffer::<MyBuffer>().unwrap()
-            .register(slave.address(), OFFSETED)
-            .register(slave.address(), OFFSET)
-            .build();
+        let buffer = mapping.mapped::<MyBuffer>(slave.address()).unwrap();
             
         mapping.configure(&slave).await.unwrap();
         
         // stream our custom packet of data
-        let mut previous = MyBuffer::default();
         let mut current = MyBuffer::default();
-        let stream = master.stream(buffer).await.unwrap();
-        stream.send_exchange(current.clone()).await.unwrap();
+        let mut stream = master.stream(buffer).await.unwrap();
         for i in 0 .. 10 {
             tokio::time::sleep(Duration::from_millis(10)).await;
-            stream.send_exchange(previous.clone()).await.unwrap();
-            (current, previous) = (stream.receive().await.unwrap().data, current);
             current.offset = (i%2)*100;
+            current = stream.cycle(current).await.unwrap().one().unwrap();
         }
         
         // TODO improve to actually check counter values and interaction with direct slave access
     });
 }
+
+#[test]
+#[serial]
+fn buffer_size() {
+    test(|master| async move {
+        let size = master.slave(Host::Topological(0)).buffer_size().await.unwrap().one().unwrap();
+        assert!(usize::from(size) >= registers::USER);
+    });
+}
+
+#[test]
+#[serial]
+fn write_verified() {
+    test(|master| async move {
+        let slave = master.slave(Host::Topological(0));
+        slave.write(OFFSET, 3).await.unwrap().one().unwrap();
+        let previous = slave.write_verified(OFFSET, 7).await.unwrap().one().unwrap();
+        assert_eq!(previous, 3);
+        assert_eq!(slave.read(OFFSET).await.unwrap().one().unwrap(), 7);
+    });
+}
+
+#[test]
+#[serial]
+fn read_into() {
+    test(|master| async move {
+        let slave = master.slave(Host::Topological(0));
+        let mut buffer = [0; 128];
+        slave.read_into(registers::DEVICE, &mut buffer).await.unwrap().one().unwrap();
+        let device = registers::Device::from_be_bytes(buffer);
+        assert_eq!(device.model.as_str().unwrap(), "esp32-test");
+    });
+}
+
+#[test]
+#[serial]
+fn blocking_master() {
+    let master = BlockingMaster::new("/dev/ttyUSB1", 1_500_000).expect("failed to initialize master");
+    let slave = master.slave(Host::Topological(0));
+    assert_eq!(slave.read(registers::VERSION).unwrap().one().unwrap(), registers::PROTOCOL_VERSION);
+    slave.write(OFFSET, 5).unwrap().one().unwrap();
+    assert_eq!(slave.exchange(OFFSET, 6).unwrap().one().unwrap(), 5);
+    assert!(!master.stopped());
+}
+
+#[test]
+#[serial]
+fn addressing_consistency() {
+    test(|master| async move {
+        assert_eq!(master.check_addressing().await.unwrap(), None);
+    });
+}
+
+#[test]
+#[serial]
+fn subscribe() {
+    test(|master| async move {
+        let mut subscription = master.subscribe(Host::Topological(0), COUNTER, Duration::from_millis(5)).await.unwrap()
+            .changes();
+        let mut previous = subscription.next().await.unwrap().one().unwrap();
+        for _ in 0 .. 5 {
+            let current = subscription.next().await.unwrap().one().unwrap();
+            assert_ne!(current, previous);
+            previous = current;
+        }
+    });
+}
+
+#[test]
+#[serial]
+fn command_raw() {
+    test(|master| async move {
+        let mut command = uartcat::command::Command::default();
+        command.access.set_topological(true);
+        command.access.set_write(true);
+        command.address = uartcat::command::Address::new(0, OFFSET.address());
+        master.command_raw(command, &mut 3u16.to_be_bytes()).await.unwrap().one().unwrap();
+        assert_eq!(master.slave(Host::Topological(0)).read(OFFSET).await.unwrap().one().unwrap(), 3);
+        
+        command.access.set_fixed(true);
+        assert!(matches!(master.command_raw(command, &mut [0; 2]).await, Err(Error::Master(_))));
+    });
+}
+
+#[test]
+#[serial]
+fn latency_metrics() {
+    test_configured(|master| master.set_metrics(true), |master| async move {
+        assert_eq!(master.latency_stats().unwrap().count, 0);
+        let slave = master.slave(Host::Topological(0));
+        for _ in 0 .. 10 {
+            slave.read(registers::VERSION).await.unwrap().one().unwrap();
+        }
+        let stats = master.latency_stats().unwrap();
+        assert_eq!(stats.count, 10);
+        assert!(stats.min <= stats.mean && stats.mean <= stats.max);
+        assert!(stats.min > Duration::ZERO);
+    });
+}
+
+#[test]
+#[serial]
+fn compare_exchange() {
+    test(|master| async move {
+        let slave = master.slave(Host::Topological(0));
+        slave.write(OFFSET, 1).await.unwrap().one().unwrap();
+        assert_eq!(slave.compare_exchange(OFFSET, 2, 3).await.unwrap().one().unwrap(), Err(1));
+        assert_eq!(slave.compare_exchange(OFFSET, 1, 3).await.unwrap().one().unwrap(), Ok(1));
+        assert_eq!(slave.read(OFFSET).await.unwrap().one().unwrap(), 3);
+    });
+}
+
+#[test]
+#[serial]
+fn dump() {
+    test(|master| async move {
+        let slave = master.slave(Host::Topological(0));
+        slave.write(OFFSET, 0x1234).await.unwrap().one().unwrap();
+        let size = slave.buffer_size().await.unwrap().one().unwrap();
+        let dump = slave.dump().await.unwrap();
+        assert!(dump.error.is_none());
+        assert_eq!(dump.data.len(), usize::from(size));
+        assert_eq!(dump.data[usize::from(OFFSET.address()) ..][.. 2], [0x12, 0x34]);
+        assert_eq!(dump.data[usize::from(registers::VERSION.address())], registers::PROTOCOL_VERSION);
+        
+        // a range going out of the slave memory stops at the failing read
+        let dump = slave.dump_range(0, usize::from(size) + 1).await;
+        assert!(dump.error.is_some());
+        assert!(dump.data.len() <= usize::from(size));
+    });
+}
+
+#[test]
+#[serial]
+fn read_large() {
+    test_configured(|master| master.set_max_command(4).unwrap(), |master| async move {
+        let slave = master.slave(Host::Topological(0));
+        slave.write(OFFSET, 0x1234).await.unwrap().one().unwrap();
+        slave.write(OFFSETED, 0x5678).await.unwrap().one().unwrap();
+        let mut mapping = Mapping::new();
+        let buffer = mapping.buffer::<MyBuffer>().unwrap()
+            .register(slave.address(), OFFSETED)
+            .register(slave.address(), OFFSET)
+            .build();
+        mapping.configure(&slave).await.unwrap();
+        
+        // the buffer is read in chunks of 3 bytes
+        let mut data = [0; 6];
+        let read = master.read_large(buffer.address(), &mut data).await;
+        assert!(read.error.is_none());
+        assert_eq!((read.done, read.executed), (6, 1));
+        let value = MyBuffer::from_be_bytes(data);
+        assert_eq!(value.offset, 0x1234);
+    });
+}
+
+#[test]
+#[serial]
+fn gather_slaves() {
+    test(|master| async move {
+        let count = SlaveSize::from(master.count_slaves().await.unwrap()).min(4);
+        let mut mapping = Mapping::new();
+        // registers of the slaves are packed in one integer
+        let mut buffer = mapping.buffer::<u64>().unwrap();
+        for index in 0 .. count {
+            master.slave(Host::Topological(index)).write(OFFSET, 100 + index).await.unwrap().one().unwrap();
+            buffer = buffer.register(Host::Topological(index), OFFSET);
+        }
+        let buffer = buffer.padding(2 * (4 - count)).build();
+        mapping.configure_all(&master).await.unwrap();
+        
+        // one virtual read merges the registers of all slaves
+        let answer = master.read(buffer).await.unwrap();
+        assert_eq!(answer.executed, count as u8);
+        for index in 0 .. count {
+            assert_eq!((answer.data >> (48 - 16 * index)) as u16, 100 + index);
+        }
+    });
+}
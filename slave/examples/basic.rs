@@ -49,19 +49,19 @@ async fn main(_spawner: Spawner) {
         .with_baudrate(1_500_000)
         .with_data_bits(DataBits::_8)
         .with_stop_bits(StopBits::_1)
-        .with_parity(Parity::Even)
+        .with_parity(if uartcat::command::PARITY {Parity::Even} else {Parity::None})
         .with_rx(RxConfig::default() .with_fifo_full_threshold(1))
         ;
     let bus = esp_hal::uart::Uart::new(peripherals.UART1, config).unwrap()
         .with_rx(peripherals.GPIO16)
         .with_tx(peripherals.GPIO17)
         .into_async();
-    let slave = Slave::<_, MEMORY>::new(bus, Device {
-        serial: "".try_into().unwrap(),
-        model: "esp32-example".try_into().unwrap(),
-        hardware_version: "0.1".try_into().unwrap(),
-        software_version: "0.1".try_into().unwrap(),
-        });
+    let slave = Slave::<_, MEMORY>::new(bus, Device::builder()
+        .model("esp32-example")
+        .and_then(|device| device.hardware_version("0.1"))
+        .and_then(|device| device.software_version("0.1"))
+        .expect("device strings too long")
+        .build());
     info!("init done");
     // refresh registers periodically
     let task = async {
@@ -1,13 +1,18 @@
+use core::ops::Range;
 use bilge::prelude::*;
-use packbytes::{FromBytes, ToBytes};
+use packbytes::{FromBytes, ToBytes, ByteArray};
 
-use crate::pack_bilge;
+use crate::{
+    pack_bilge,
+    registers::CommandError,
+    };
 
 
+/// maximum data size of a command allowed by the protocol, master and slaves can use smaller limits
 pub const MAX_COMMAND: usize = 4096;
 
 /// memory bus command header
-#[derive(Copy, Clone, FromBytes, ToBytes, Debug, Default)]
+#[derive(Copy, Clone, FromBytes, ToBytes, Debug, Default, PartialEq)]
 pub struct Command {
     /// identifier of command
     pub token: u16,
@@ -23,6 +28,28 @@ pub struct Command {
     pub checksum: u8,
 }
 
+impl Command {
+    /** 
+        check that a header that passed its checksum is a possible command
+        
+        since the header checksum is weak, random data can pass it, so this is used as an additional check when catching up a command on the bus
+    */
+    pub fn plausible(&self) -> bool {
+        usize::from(self.size) <= MAX_COMMAND
+        && (self.access.error() || match self.access.operation() {
+            Some(Operation::Access) => true,
+            Some(Operation::CompareExchange) => self.compare_exchange_valid(),
+            None => false,
+        })
+    }
+    /// check the constraints of [Operation::CompareExchange] on this command
+    pub fn compare_exchange_valid(&self) -> bool {
+        self.access.read() && self.access.write()
+        && self.access.fixed() != self.access.topological()
+        && self.size.is_multiple_of(2)
+    }
+}
+
 /// type of memory access
 #[bitsize(8)]
 #[derive(Copy, Clone, FromBits, DebugBits, PartialEq, Default)]
@@ -36,13 +63,77 @@ pub struct Access {
         - if True, an individual slave's registers are addresses, the 32 bit addres concatenates 16bit address of slave and 16bit address of register in this slave
     */
     pub fixed: bool,
-    /// if set, the slave address is topological
+    /// if set, the slave address is topological. Setting it along `fixed` is only valid with extended addressing, see [Access::extended]
     pub topological: bool,
-    _reserved: u3,
+    /// error code of the failed command when `error` is set, operation to perform otherwise. See [Access::command_error] and [Access::operation]
+    pub code: u3,
     /// set to True for a command that could not be executed, the error code is instantly set in register `error`
     pub error: bool,
 }
 pack_bilge!(Access);
+impl Access {
+    /// error reported in `code`, codes that do not fit in `code` are reported as [CommandError::Unknown]
+    pub fn command_error(&self) -> CommandError {
+        match CommandError::from(self.code().value()) {
+            CommandError::None => CommandError::Unknown,
+            error => error,
+        }
+    }
+    /// set the error flag and report the given error in `code`
+    pub fn set_command_error(&mut self, error: CommandError) {
+        let code = u8::from(error);
+        self.set_error(true);
+        self.set_code(u3::try_new(code).unwrap_or(u3::new(0)));
+    }
+    /// operation requested in `code`, `None` if the error flag is set or the operation is unknown
+    pub fn operation(&self) -> Option<Operation> {
+        if self.error()
+            {return None}
+        match self.code().value() {
+            0 => Some(Operation::Access),
+            1 => Some(Operation::CompareExchange),
+            _ => None,
+        }
+    }
+    /// request the given operation in `code`
+    pub fn set_operation(&mut self, operation: Operation) {
+        self.set_code(u3::new(operation as u8));
+    }
+    /**
+        whether the command uses extended addressing, marked by both `fixed` and `topological` flags
+        
+        the whole 32 bit address is then a register address in the slaves selected by [SELECT](crate::registers::SELECT), so slaves can expose more than 64KB of memory (in segments). Only plain accesses are possible this way
+    */
+    #[cfg(feature = "extended-address")]
+    pub fn extended(&self) -> bool {
+        self.fixed() && self.topological()
+    }
+    /// check these flags are a legal combination for a command sent by a master, giving the reason otherwise
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.fixed() && self.topological() && !cfg!(feature = "extended-address")
+            {return Err("command cannot be both fixed and topological")}
+        if self.error()
+            {return Err("command cannot be sent with an error")}
+        if self.operation().is_none()
+            {return Err("unknown command operation")}
+        Ok(())
+    }
+}
+
+/// operation performed by slaves on the addressed memory, stored in [Access::code] of commands without error
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Operation {
+    /// plain memory access, reading and/or writing as requested by [Access::read] and [Access::write]
+    Access = 0,
+    /**
+        compare the register with an expected value and write a new value only if they are equal
+        
+        The command data is the expected value followed by the new value, the register being half the command size. The slave answers the register value before the operation followed by the new value, so the master knows the swap happened if the answered value is the expected one.
+        It is only valid for an exchange (read and write) addressing one slave
+    */
+    CompareExchange = 1,
+}
 
 #[bitsize(32)]
 #[derive(Copy, Clone, FromBits, DebugBits, PartialEq, Default)]
@@ -54,8 +145,173 @@ pub struct Address {
 }
 pack_bilge!(Address);
 
-/// checksum method used for command header and data
+/// initial value of [checksum]
+pub const CHECKSUM_SEED: u8 = 0b1011_0111;
+
+/**
+    checksum method used for command header and data
+    
+    starting from [CHECKSUM_SEED], each byte is added to the accumulator modulo 256, then the accumulator is shifted left by one bit, dropping its highest bit. Master and slave must produce the exact same result
+*/
 pub fn checksum(slice: &[u8]) -> u8 {
-    let initial = 0b010110111; // standard neutral value of checksum
-    slice.iter().cloned().fold(initial, |a, b|  a.wrapping_add(b)<<1)
+    slice.iter().cloned().fold(CHECKSUM_SEED, |a, b|  a.wrapping_add(b) << 1)
+}
+
+
+/// size of a serialized command header, followed by its one byte checksum
+pub const HEADER: usize = <Command as FromBytes>::Bytes::SIZE;
+
+/**
+    size of the CRC appended after the data of every frame, covering the header, its checksum and the data
+    
+    It is only used with the `frame-crc` feature, so master and slaves must be built with the same features to understand each other
+*/
+pub const TRAILER: usize = if cfg!(feature = "frame-crc") {2} else {0};
+/**
+    whether the serial link uses an even parity bit on each byte
+    
+    the `no-parity` feature disables it for adapters that cannot do parity at high baud rates, and then enables the frame [TRAILER] since checksums are the only way left to detect corrupted bytes. Slaves must configure their UART accordingly
+*/
+pub const PARITY: bool = !cfg!(feature = "no-parity");
+/// initial state of [frame_crc]
+pub const CRC_SEED: u16 = 0xffff;
+/**
+    CRC-16/CCITT-FALSE used for the frame trailer, see [TRAILER]
+    
+    starting from [CRC_SEED], the bytes of the frame can be given in several slices by chaining the returned state
+*/
+pub fn frame_crc(state: u16, bytes: &[u8]) -> u16 {
+    bytes.iter().fold(state, |crc, &byte| {
+        (0 .. 8).fold(crc ^ (u16::from(byte) << 8), |crc, _| 
+            if crc & 0x8000 != 0  {(crc << 1) ^ 0x1021}
+            else {crc << 1})
+    })
+}
+
+/// result of [decode_frame]
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeOutcome {
+    /// the buffer starts with a complete frame, its data is at the given range of the buffer
+    Frame(Command, Range<usize>),
+    /// the buffer starts with a valid header but not all its data, the data will be at the given range once received
+    Partial(Command, Range<usize>),
+    /// the buffer is too short for a header, it needs at least the given number of bytes
+    Incomplete(usize),
+    /// the buffer does not start with a valid header, the given number of bytes must be dropped before decoding again
+    Skip(usize),
+}
+
+/**
+    decode the frame at the start of the given bytes, without any IO
+    
+    a header is only accepted if its checksum is good and it is [plausible](Command::plausible). With the `frame-crc` feature, a complete frame is also only accepted if its [TRAILER] is good, and it ends after it.
+    This never panics, whatever the input bytes, so the bus receivers catching up commands in a noisy byte stream simply drive this function
+*/
+pub fn decode_frame(bytes: &[u8]) -> DecodeOutcome {
+    let Some(header) = bytes.get(.. HEADER+1)
+        else {return DecodeOutcome::Incomplete(HEADER+1)};
+    if checksum(&header[.. HEADER]) != header[HEADER]
+        {return DecodeOutcome::Skip(1)}
+    let command = Command::from_be_bytes(header[.. HEADER].try_into().unwrap());
+    if !command.plausible()
+        {return DecodeOutcome::Skip(1)}
+    let data = HEADER+1 .. HEADER+1 + usize::from(command.size);
+    let Some(trailer) = bytes.get(data.end .. data.end + TRAILER)
+        else {return DecodeOutcome::Partial(command, data)};
+    if TRAILER != 0 && frame_crc(CRC_SEED, &bytes[.. data.end]).to_be_bytes()[.. TRAILER] != *trailer
+        {return DecodeOutcome::Skip(1)}
+    DecodeOutcome::Frame(command, data)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    #[test]
+    fn access_validate() {
+        let mut access = Access::default();
+        assert!(access.validate().is_ok());
+        access.set_fixed(true);
+        assert!(access.validate().is_ok());
+        access.set_topological(true);
+        assert_eq!(access.validate().is_ok(), cfg!(feature = "extended-address"));
+        
+        let mut access = Access::default();
+        access.set_command_error(CommandError::InvalidAccess);
+        assert!(access.validate().is_err());
+        let mut access = Access::default();
+        access.set_code(u3::new(5));
+        assert!(access.validate().is_err());
+    }
+    
+    #[test]
+    fn frame_crc_vectors() {
+        assert_eq!(frame_crc(CRC_SEED, b"123456789"), 0x29b1);
+        assert_eq!(frame_crc(frame_crc(CRC_SEED, b"1234"), b"56789"), 0x29b1);
+    }
+    
+    #[test]
+    fn checksum_vectors() {
+        assert_eq!(checksum(&[]), 0xb7);
+        assert_eq!(checksum(&[0]), 110);
+        assert_eq!(checksum(&[1, 2, 3]), 206);
+        assert_eq!(checksum(b"uartcat"), 68);
+        assert_eq!(checksum(&(0 ..= 255).collect::<std::vec::Vec<u8>>()), 254);
+    }
+    
+    #[test]
+    fn decode_frame_resync() {
+        let command = Command {token: 3, size: 2, checksum: checksum(&[4, 5]), .. Command::default()};
+        let header = command.to_be_bytes();
+        let mut frame = std::vec![0xff, 0x12];
+        frame.extend_from_slice(&header);
+        frame.push(checksum(&header));
+        frame.extend_from_slice(&[4, 5]);
+        let crc = frame_crc(CRC_SEED, &frame[2 ..]).to_be_bytes();
+        frame.extend_from_slice(&crc[.. TRAILER]);
+        
+        // garbage before the header is skipped byte per byte
+        let mut start = 0;
+        let outcome = loop {
+            match decode_frame(&frame[start ..]) {
+                DecodeOutcome::Skip(skip) => start += skip,
+                outcome => break outcome,
+            }
+        };
+        assert_eq!(start, 2);
+        let DecodeOutcome::Frame(decoded, data) = outcome 
+            else {panic!("frame not decoded")};
+        assert_eq!(decoded.token, 3);
+        assert_eq!(frame[start ..][data], [4, 5]);
+        assert_eq!(decode_frame(&frame[start ..][.. HEADER+2]), DecodeOutcome::Partial(decoded, HEADER+1 .. HEADER+3));
+        assert_eq!(decode_frame(&frame[start ..][.. HEADER]), DecodeOutcome::Incomplete(HEADER+1));
+    }
+    
+    #[test]
+    fn decode_frame_noise() {
+        // pseudo random bytes, the decoder must never panic and always progress
+        let mut state = 0x2545_f491_u32;
+        let noise = (0 .. 1 << 16).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect::<std::vec::Vec<u8>>();
+        let mut start = 0;
+        while start < noise.len() {
+            start += match decode_frame(&noise[start ..]) {
+                DecodeOutcome::Skip(skip) => skip,
+                DecodeOutcome::Frame(_, data) => data.end + TRAILER,
+                DecodeOutcome::Partial(_, data) => {
+                    assert!(data.end + TRAILER > noise.len() - start);
+                    break
+                },
+                DecodeOutcome::Incomplete(size) => {
+                    assert!(size > noise.len() - start);
+                    break
+                },
+            };
+        }
+    }
 }
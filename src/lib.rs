@@ -55,16 +55,21 @@ for a complete example see [`master/examples/basic.rs`](https://github.com/jimy-
 */
 
 #![no_std]
-#[cfg(feature = "std")]
+#[cfg(any(feature = "std", test))]
 extern crate std;
 
-mod command;
+/// format of the frames exchanged on the bus
+pub mod command;
 mod mutex;
 mod utils;
 
 
+pub use command::MAX_COMMAND;
+
 pub mod registers;
-#[cfg(feature = "master")]
+#[cfg(feature = "master-futures")]
 pub mod master;
 #[cfg(feature = "slave")]
 pub mod slave;
+#[cfg(feature = "test-util")]
+pub mod test_util;
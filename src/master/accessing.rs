@@ -1,16 +1,41 @@
-use std::vec::Vec;
+use std::{
+    vec::Vec,
+    boxed::Box,
+    time::Duration,
+    };
+use core::{
+    ops::Range,
+    pin::Pin,
+    future::{Future, poll_fn},
+    task::{Context, Poll},
+    };
+use log::*;
 use packbytes::{FromBytes, ToBytes, ByteArray};
-use crate::registers::{Register, SlaveRegister, VirtualRegister, SlaveSize, VirtualSize};
+use crate::{
+    registers::{self, Register, SlaveRegister, VirtualRegister, SlaveSize, VirtualSize},
+    command::{self, Command},
+    };
 use super::{
     Error,
-    networking::{Master, Topic, Address, PinnedBuffer},
+    networking::{Master, Topic, Address},
+    timer::Interval,
     };
 
 
-type UartcatResult<T> = Result<Answer<T>, Error>;
+pub(super) type UartcatResult<T> = Result<Answer<T>, Error>;
+/// future kept by the types implementing `futures::Stream`, to resume it on the next poll
+pub(super) type Polling<'m, O> = Pin<Box<dyn Future<Output=O> + Send + 'm>>;
+
+/// report a slave rejecting an access with the register name, since [Error::Slave] does not tell which register
+fn report<T, A: core::fmt::LowerHex>(error: &Error, action: &str, register: Register<T, A>) {
+    if let Error::Slave(code) = error {
+        warn!("{} {} rejected by slave: {:?}", action, register, code);
+    }
+}
 
 
 /// received data and number of slaves who executed the command
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Answer<T> {
     /// data received
     pub data: T,
@@ -21,14 +46,14 @@ impl<T> Answer<T> {
     /// ok if at least one slave executed the command
     pub fn any(self) -> Result<T, Error> {
         if self.executed == 0 
-            {return Err(Error::Master("no slave answered"))}
+            {return Err(Error::NoSlave)}
         Ok(self.data)
     }
     /// ok if the exact given number of slave executed the command
     pub fn exact(self, executed: u8) -> Result<T, Error> {
         if self.executed != executed {
             if self.executed == 0
-                {return Err(Error::Master("no slave answered"))}
+                {return Err(Error::NoSlave)}
             else
                 {return Err(Error::Master("incorrect number of answers"))}
         }
@@ -38,48 +63,161 @@ impl<T> Answer<T> {
     pub fn one(self) -> Result<T, Error>  {
         self.exact(1)
     }
+    /// ok if the command was executed by at least the given number of slaves
+    pub fn at_least(self, executed: u8) -> Result<T, Error> {
+        if self.executed < executed {
+            if self.executed == 0
+                {return Err(Error::NoSlave)}
+            else
+                {return Err(Error::Master("not enough answers"))}
+        }
+        Ok(self.data)
+    }
+    /// ok if the command was executed by all the expected slaves, this is the same as [Self::exact]
+    pub fn all(self, expected: u8) -> Result<T, Error> {
+        self.exact(expected)
+    }
+    /**
+        true if at least one slave executed the command
+        
+        since the answer went through the whole bus, a command not executed means no slave matched its address, whereas a lost answer is reported as [Error::Timeout]
+    */
+    pub fn present(&self) -> bool {
+        self.executed != 0
+    }
+    /// number of slaves that executed the command, without consuming the answer
+    pub fn executed(&self) -> u8 {
+        self.executed
+    }
+    /// transform the received data, keeping the number of executions
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Answer<U> {
+        Answer {data: f(self.data), executed: self.executed}
+    }
+    /// transform the received data with a fallible function, keeping the number of executions
+    pub fn and_then<U>(self, f: impl FnOnce(T) -> Result<U, Error>) -> Result<Answer<U>, Error> {
+        Ok(Answer {data: f(self.data)?, executed: self.executed})
+    }
 }
 
 
 
 impl Master {
     pub fn slave(&self, host: Host) -> Slave<'_>   {Slave{master: self, host}}
+    pub fn virtual_region(&self, base: VirtualSize) -> VirtualRegion<'_>   {VirtualRegion{master: self, base}}
     
     pub async fn stream<T: FromBytes + ToBytes>(&self, buffer: VirtualRegister<T>) -> Result<Stream<'_, T>, Error> {
-        Stream::<T, VirtualSize>::new(self, buffer).await
+        Stream::<T, VirtualSize>::new(self, buffer, STREAM_DEPTH).await
     }
     pub async fn read<T: FromBytes>(&self, register: VirtualRegister<T>) -> UartcatResult<T> {
         let mut buffer = T::Bytes::zeroed();
-        let executed = self.read_bytes(register.address(), buffer.as_mut()).await?.executed;
+        let executed = self.read_bytes(register.address(), buffer.as_mut()).await
+            .inspect_err(|error| report(error, "read", register))?
+            .executed;
         Ok(Answer{
             data: T::from_be_bytes(buffer),
             executed,
             })
     }
+    /// read the given register into a byte buffer owned by the caller, leaving deserialization to the caller. This avoids a new value buffer for each read of big registers, and the master reuses the answer buffers of previous commands so repeated reads do not allocate
+    pub async fn read_into<T: FromBytes>(&self, register: VirtualRegister<T>, buffer: &mut T::Bytes) -> UartcatResult<()> {
+        let executed = self.read_bytes(register.address(), buffer.as_mut()).await
+            .inspect_err(|error| report(error, "read", register))?
+            .executed;
+        Ok(Answer{
+            data: (),
+            executed,
+            })
+    }
     pub async fn write<T: ToBytes>(&self, register: VirtualRegister<T>, value: T) -> UartcatResult<()> {
-        let executed = self.write_bytes(register.address(), value.to_be_bytes().as_mut()).await?.executed;
+        let executed = self.write_bytes(register.address(), value.to_be_bytes().as_mut()).await
+            .inspect_err(|error| report(error, "write", register))?
+            .executed;
         Ok(Answer{
             data: (),
             executed,
             })
     }
+    /**
+        write like [Self::write], but fail with [Error::Master] if fewer than the `expected` slaves applied the write
+        
+        slaves receiving corrupted data do not apply it and do not count it as executed, so a partial write means some slaves kept their previous value. A virtual command is executed by every slave it goes through, so `expected` is the number of slaves in the chain (see [Self::count_slaves]). [Mapping::write_checked](super::Mapping::write_checked) also tells which ones dropped it
+    */
+    pub async fn write_expected<T: ToBytes>(&self, register: VirtualRegister<T>, value: T, expected: u8) -> Result<(), Error> {
+        let answer = self.write(register, value).await?;
+        if answer.executed < expected {
+            warn!("write {} applied by {} slaves out of {}", register, answer.executed, expected);
+            return Err(Error::Master("partial write"))
+        }
+        answer.exact(expected)
+    }
     pub async fn exchange<C,T>(&self, register: VirtualRegister<T>, value: T) -> UartcatResult<T> 
     where 
         C: ByteArray, 
         T: ToBytes<Bytes=C> + FromBytes<Bytes=C> 
     {
         let mut buffer = value.to_be_bytes();
-        let executed = self.exchange_bytes(register.address(), buffer.as_mut()).await?.executed;
+        let executed = self.exchange_bytes(register.address(), buffer.as_mut()).await
+            .inspect_err(|error| report(error, "exchange", register))?
+            .executed;
         Ok(Answer{
             data: T::from_be_bytes(buffer),
             executed,
             })
     }
+    /**
+        exchange the given register with a byte buffer owned by the caller, leaving serialization to the caller like [Self::read_into]
+        
+        the buffer bytes are sent and replaced with the ones received, so big registers are not moved in and out of a value. It is left unchanged if no answer was received
+    */
+    pub async fn exchange_in_place<C,T>(&self, register: VirtualRegister<T>, buffer: &mut C) -> UartcatResult<()> 
+    where 
+        C: ByteArray, 
+        T: ToBytes<Bytes=C> + FromBytes<Bytes=C> 
+    {
+        let executed = self.exchange_bytes(register.address(), buffer.as_mut()).await
+            .inspect_err(|error| report(error, "exchange", register))?
+            .executed;
+        Ok(Answer{
+            data: (),
+            executed,
+            })
+    }
     
     pub async fn stream_bytes(&self, _address: VirtualSize, _size: SlaveSize) -> StreamBytes<'_>   {todo!()}
     pub async fn read_bytes<'d>(&self, address: VirtualSize, data: &'d mut [u8]) -> UartcatResult<&'d mut [u8]> {
         self.command(address, true, false, data).await
     }
+    /**
+        read a range of virtual memory bigger than a command, in as many commands at consecutive addresses as needed by [max_command](Master::max_command)
+        
+        the commands are sent one after the other, and the first failing command (timeout, or slave rejecting a chunk crossing a mapping boundary) stops the read, so the bytes read before are still reported
+    */
+    pub async fn read_large(&self, address: VirtualSize, data: &mut [u8]) -> LargeRead {
+        let mut read = LargeRead {done: 0, executed: u8::MAX, error: None};
+        match self.chunks(data.len()) {
+            Err(error) => read.error = Some(error),
+            Ok(chunks) => for part in chunks {
+                let Some(start) = VirtualSize::try_from(part.start).ok()
+                    .and_then(|done| address.checked_add(done))
+                    else {
+                        read.error = Some(Error::Master("read range exceeds virtual memory"));
+                        break
+                    };
+                match self.read_bytes(start, &mut data[part.clone()]).await {
+                    Ok(answer) => read.executed = read.executed.min(answer.executed),
+                    Err(error) => {
+                        read.error = Some(error);
+                        break
+                    },
+                }
+                read.done = part.end;
+            },
+        }
+        if read.done == 0 {
+            read.executed = 0;
+        }
+        read
+    }
     pub async fn write_bytes(&self, address: VirtualSize, data: &mut [u8]) -> UartcatResult<()> {
         self.command(address, false, true, data).await 
             .map(|a| Answer {data: (), executed: a.executed})
@@ -88,20 +226,137 @@ impl Master {
         self.command(address, true, true, data).await
     }
     
+    /**
+        send all the given commands before waiting for any answer, so the bus is kept busy
+        
+        - there is no ordering guarantee between the commands execution, nor between their answers. The results are anyway returned in the same order as the commands
+        - each command reserves a token until the whole pipeline is done, so there cannot be more than 16384 commands pending on the master (64 when sharing the bus, see [Master::with_id]), additional commands fail with [Error::Master]
+    */
+    pub async fn pipeline(&self, commands: impl IntoIterator<Item=PreparedCommand>) -> Vec<UartcatResult<Vec<u8>>> {
+        let mut topics = Vec::new();
+        for command in commands {
+            let size = command.data.len();
+            let topic = match Topic::new(self, command.address, command.data).await {
+                Ok(topic) => match topic.send(command.read, command.write, None).await {
+                    Ok(()) => Ok((topic, size)),
+                    Err(err) => Err(err),
+                },
+                Err(err) => Err(err),
+            };
+            topics.push(topic);
+        }
+        let mut results = Vec::with_capacity(topics.len());
+        for topic in topics {
+            results.push(match topic {
+                Ok((topic, size)) => {
+                    let mut data = std::vec![0; size];
+                    topic.receive(Some(&mut data)).await
+                        .map(|executed| Answer {data, executed})
+                },
+                Err(err) => Err(err),
+            });
+        }
+        results
+    }
+    /**
+        read the same register in a contiguous range of slaves, given by topological index, each answer is returned with its slave index
+        
+        the reads are sent as a [pipeline](Self::pipeline). The result stops at the first slave not answering (like the end of the bus), errors reported by slaves are returned as errors
+    */
+    pub async fn read_range_of_slaves<T: FromBytes>(&self, slaves: Range<SlaveSize>, register: SlaveRegister<T>) -> Result<Vec<(SlaveSize, Answer<T>)>, Error> {
+        let commands = slaves.clone()
+            .map(|index| PreparedCommand::read(Address::Topological(index, register.address()), register.size()));
+        let mut answers = Vec::new();
+        for (index, result) in slaves.zip(self.pipeline(commands).await) {
+            let answer = match result {
+                Ok(answer) if answer.executed != 0 => answer,
+                Ok(_) | Err(Error::Timeout) => break,
+                Err(error) => {
+                    report(&error, "read", register);
+                    return Err(error)
+                },
+            };
+            let mut bytes = T::Bytes::zeroed();
+            bytes.as_mut().copy_from_slice(&answer.data);
+            answers.push((index, Answer {data: T::from_be_bytes(bytes), executed: answer.executed}));
+        }
+        Ok(answers)
+    }
+    
+    /**
+        send a command with a custom header, for protocol experimentation
+        
+        the whole header is sent as given, except the token, size and checksum which are set by the master. Its `read` and `write` access flags decide whether the data is read, written or exchanged. Headers that cannot be valid (like both `fixed` and `topological`, or an error flag) are rejected with [Error::Master] without being sent
+    */
+    pub async fn command_raw<'d>(&self, command: Command, data: &'d mut [u8]) -> UartcatResult<&'d mut [u8]> {
+        let executed = {
+            let topic = Topic::with_command(self, command, self.buffer(data.len())).await?;
+            topic.send(command.access.read(), command.access.write(), Some(data)).await?;
+            topic.receive(Some(data)).await?
+            };
+        Ok(Answer {data, executed})
+    }
+    
+    /**
+        read bytes at a 32 bit address in the slaves selected for extended addressing, see [Slave::select]
+        
+        all selected slaves execute it, so only one should be selected at a time for reading
+    */
+    #[cfg(feature = "extended-address")]
+    pub async fn read_extended<'d>(&self, address: u32, data: &'d mut [u8]) -> UartcatResult<&'d mut [u8]> {
+        self.command_at(Address::Extended(address), true, false, data).await
+    }
+    /// write bytes at a 32 bit address in the slaves selected for extended addressing, see [Slave::select]
+    #[cfg(feature = "extended-address")]
+    pub async fn write_extended(&self, address: u32, data: &mut [u8]) -> UartcatResult<()> {
+        self.command_at(Address::Extended(address), false, true, data).await
+            .map(|a| Answer {data: (), executed: a.executed})
+    }
+    
     async fn command<'d>(&self, address: VirtualSize, read: bool, write: bool, data: &'d mut [u8]) -> UartcatResult<&'d mut [u8]> {
+        self.command_at(Address::Virtual(address), read, write, data).await
+    }
+    /**
+        send a one-shot command and wait for its answer
+        
+        the data is sent from the caller's buffer, but the master owns the buffer receiving the answer. It is taken from the buffers of previous commands, so a command only allocates when none was released yet
+    */
+    async fn command_at<'d>(&self, address: Address, read: bool, write: bool, data: &'d mut [u8]) -> UartcatResult<&'d mut [u8]> {
         let executed = {
             let topic = Topic::new(
                 self, 
-                Address::Virtual(address),
-                PinnedBuffer::Borrowed(data),
+                address,
+                self.buffer(data.len()),
                 ).await?;
-            topic.send(read, write, None).await?;
-            topic.receive(None).await?
+            topic.send(read, write, Some(data)).await?;
+            topic.receive(Some(data)).await?
             };
         Ok(Answer {data, executed})
     }
 }
 
+/// command ready to be sent in a [Master::pipeline]
+pub struct PreparedCommand {
+    address: Address,
+    read: bool,
+    write: bool,
+    data: Vec<u8>,
+}
+impl PreparedCommand {
+    /// read the given amount of bytes at the given address
+    pub fn read(address: Address, size: SlaveSize) -> Self {
+        Self {address, read: true, write: false, data: std::vec![0; usize::from(size)]}
+    }
+    /// write the given bytes at the given address
+    pub fn write(address: Address, data: Vec<u8>) -> Self {
+        Self {address, read: false, write: true, data}
+    }
+    /// read-then-write the given bytes at the given address
+    pub fn exchange(address: Address, data: Vec<u8>) -> Self {
+        Self {address, read: true, write: true, data}
+    }
+}
+
 /** 
     represent a specific slave on the bus
 
@@ -129,23 +384,71 @@ impl<'m> Slave<'m> {
     pub fn new(master: &'m Master, host: Host) -> Self {
         Self {master, host}
     }
+    /// master this slave is accessed with
+    pub fn master(&self) -> &'m Master {self.master}
     pub fn address(&self) -> Host {
         self.host
     }
+    /**
+        give this slave the given fixed address, and return a handle reaching it at this address
+        
+        the write must be executed by exactly one slave, so reaching no slave or many slaves (when addressing a fixed address already used by several) is an error. The slave keeps its fixed address until it is reset, see [registers::CONTROL_RESET]
+    */
+    pub async fn assign(&self, address: SlaveSize) -> Result<Slave<'m>, Error> {
+        if address == registers::UNASSIGNED
+            {return Err(Error::Master("cannot assign the unassigned address"))}
+        self.write(registers::ADDRESS, address).await?.one()?;
+        Ok(Self::new(self.master, Host::Fixed(address)))
+    }
     
     pub async fn stream<T: FromBytes + ToBytes>(&self, buffer: SlaveRegister<T>) -> Result<Stream<'m, T, SlaveSize>, Error> {
-        Stream::<T, SlaveSize>::new(self.master, self.host, buffer).await
+        Stream::<T, SlaveSize>::new(self.master, self.host, buffer, STREAM_DEPTH).await
     }
     pub async fn read<T: FromBytes>(&self, register: SlaveRegister<T>) -> UartcatResult<T> {
         let mut buffer = T::Bytes::zeroed();
-        let executed = self.read_bytes(register.address(), buffer.as_mut()).await?.executed;
+        let executed = self.read_bytes(register.address(), buffer.as_mut()).await
+            .inspect_err(|error| report(error, "read", register))?
+            .executed;
         Ok(Answer{
             data: T::from_be_bytes(buffer),
             executed,
             })
     }
+    /**
+        read an enum register (like the ones packed with [pack_enum](crate::pack_enum)), failing with [Error::InvalidEnum] if the byte read is not one of its variants
+        
+        [Self::read] would silently convert such byte to the enum fallback variant, masking a bus corruption or a slave bug as a legitimate value. A byte encoding the fallback variant itself is still accepted
+    */
+    pub async fn read_enum<T>(&self, register: SlaveRegister<T>) -> UartcatResult<T>
+    where T: Copy + FromBytes<Bytes=[u8; 1]> + ToBytes<Bytes=[u8; 1]>
+    {
+        let mut buffer = [0];
+        let executed = self.read_bytes(register.address(), &mut buffer).await
+            .inspect_err(|error| report(error, "read", register))?
+            .executed;
+        let data = T::from_be_bytes(buffer);
+        if data.to_be_bytes() != buffer {
+            return Err(Error::InvalidEnum(buffer[0]))
+        }
+        Ok(Answer{
+            data,
+            executed,
+            })
+    }
+    /// read the given register into a byte buffer owned by the caller, see [Master::read_into]
+    pub async fn read_into<T: FromBytes>(&self, register: SlaveRegister<T>, buffer: &mut T::Bytes) -> UartcatResult<()> {
+        let executed = self.read_bytes(register.address(), buffer.as_mut()).await
+            .inspect_err(|error| report(error, "read", register))?
+            .executed;
+        Ok(Answer{
+            data: (),
+            executed,
+            })
+    }
     pub async fn write<T: ToBytes>(&self, register: SlaveRegister<T>, value: T) -> UartcatResult<()> {
-        let executed = self.write_bytes(register.address(), value.to_be_bytes().as_mut()).await?.executed;
+        let executed = self.write_bytes(register.address(), value.to_be_bytes().as_mut()).await
+            .inspect_err(|error| report(error, "write", register))?
+            .executed;
         Ok(Answer{
             data: (),
             executed,
@@ -154,12 +457,137 @@ impl<'m> Slave<'m> {
     /// read-then-write the given register on current slave
     pub async fn exchange<C: ByteArray, T: ToBytes<Bytes=C> + FromBytes<Bytes=C>>(&self, register: SlaveRegister<T>, value: T) -> UartcatResult<T> {
         let mut buffer = value.to_be_bytes();
-        let executed = self.exchange_bytes(register.address(), buffer.as_mut()).await?.executed;
+        let executed = self.exchange_bytes(register.address(), buffer.as_mut()).await
+            .inspect_err(|error| report(error, "exchange", register))?
+            .executed;
         Ok(Answer{
             data: T::from_be_bytes(buffer),
             executed,
             })
     }
+    /// read-then-write the given register on current slave with a byte buffer owned by the caller, see [Master::exchange_in_place]
+    pub async fn exchange_in_place<C: ByteArray, T: ToBytes<Bytes=C> + FromBytes<Bytes=C>>(&self, register: SlaveRegister<T>, buffer: &mut C) -> UartcatResult<()> {
+        let executed = self.exchange_bytes(register.address(), buffer.as_mut()).await
+            .inspect_err(|error| report(error, "exchange", register))?
+            .executed;
+        Ok(Answer{
+            data: (),
+            executed,
+            })
+    }
+    /**
+        write the given register then read it back to make sure the slave stored the given value, the previous value is returned
+        
+        the write is an exchange so the previous value comes in the same round trip, the read back is a second command. A value differing from the one written is reported as [Error::Master]
+    */
+    pub async fn write_verified<C, T>(&self, register: SlaveRegister<T>, value: T) -> UartcatResult<T> 
+    where 
+        C: ByteArray,
+        T: ToBytes<Bytes=C> + FromBytes<Bytes=C> + PartialEq + Clone,
+    {
+        let previous = self.exchange(register, value.clone()).await?.one()?;
+        let answer = self.read(register).await?;
+        if answer.data != value 
+            {return Err(Error::Master("readback mismatch"))}
+        Ok(Answer{
+            data: previous,
+            executed: answer.executed,
+            })
+    }
+    
+    /**
+        write the given value only if the register currently has the expected value, atomically on the slave side
+        
+        Like [core::sync::atomic::AtomicU32::compare_exchange], it returns `Ok` with the previous value if the register was written, or `Err` with the current value otherwise. The values are compared by their serialized bytes, see [command::Operation::CompareExchange]
+    */
+    pub async fn compare_exchange<C, T>(&self, register: SlaveRegister<T>, expected: T, new: T) -> UartcatResult<Result<T, T>> 
+    where 
+        C: ByteArray,
+        T: ToBytes<Bytes=C> + FromBytes<Bytes=C>,
+    {
+        let expected = expected.to_be_bytes();
+        let mut data = Vec::with_capacity(2 * C::SIZE);
+        data.extend_from_slice(expected.as_ref());
+        data.extend_from_slice(new.to_be_bytes().as_ref());
+        let mut command = self.host.at(register.address()).command();
+        command.access.set_operation(command::Operation::CompareExchange);
+        let executed = {
+            let topic = Topic::with_command(self.master, command, self.master.buffer(data.len())).await?;
+            topic.send(true, true, Some(&data)).await?;
+            topic.receive(Some(&mut data)).await.inspect_err(|error| report(error, "compare-exchange", register))?
+            };
+        let mut current = C::zeroed();
+        current.as_mut().copy_from_slice(&data[.. C::SIZE]);
+        let swapped = current.as_ref() == expected.as_ref();
+        let value = T::from_be_bytes(current);
+        Ok(Answer {
+            data: if swapped {Ok(value)} else {Err(value)},
+            executed,
+        })
+    }
+    /**
+        modify the given register with the given function, without losing changes made meanwhile by the slave or other masters, the previous value is returned
+        
+        the register is read, then the modified value is written with [Self::compare_exchange]. If the register changed in between, the function is applied again to the new value, up to a few attempts before giving up with [Error::Master]. This makes safe the common read-modify-write of a few bits in a register packing flags
+    */
+    pub async fn update<C, T>(&self, register: SlaveRegister<T>, mut modify: impl FnMut(&mut T)) -> UartcatResult<T> 
+    where 
+        C: ByteArray,
+        T: ToBytes<Bytes=C> + FromBytes<Bytes=C> + Clone,
+    {
+        const ATTEMPTS: usize = 8;
+        let mut current = self.read(register).await?.one()?;
+        for _ in 0 .. ATTEMPTS {
+            let mut new = current.clone();
+            modify(&mut new);
+            let answer = self.compare_exchange(register, current, new).await?;
+            let executed = answer.executed;
+            match answer.one()? {
+                Ok(previous) => return Ok(Answer {data: previous, executed}),
+                Err(changed) => current = changed,
+            }
+        }
+        Err(Error::Master("register kept changing during update"))
+    }
+    /**
+        read several registers in one command
+        
+        the whole memory range spanning from the lowest to the highest register is read, so gaps between registers are read and discarded
+    */
+    pub async fn read_many<S: RegisterSet>(&self, registers: S) -> UartcatResult<S::Value> {
+        let span = registers.span()
+            .ok_or(Error::Master("register set is exceeding slave memory"))?;
+        let mut buffer = std::vec![0; usize::from(span.end - span.start)];
+        let executed = self.read_bytes(span.start, &mut buffer).await
+            .inspect_err(|error| report(error, "read", SlaveRegister::<()>::new(span.start).named("register set")))?
+            .executed;
+        Ok(Answer{
+            data: registers.unpack(span.start, &buffer),
+            executed,
+            })
+    }
+    
+    /// read the size of the slave buffer, see [registers::BUFFER_SIZE]
+    pub async fn buffer_size(&self) -> UartcatResult<u16> {
+        self.read(registers::BUFFER_SIZE).await
+    }
+    /**
+        reset the slave communication state: its loss counter, error, mapping table and fixed address, see [registers::CONTROL_RESET]
+        
+        the slave is no longer reachable by its fixed address afterwards
+    */
+    pub async fn reset(&self) -> UartcatResult<()> {
+        self.write(registers::CONTROL, registers::CONTROL_RESET).await
+    }
+    /**
+        select or deselect this slave for extended addressing, see [registers::SELECT] and [Master::read_extended]
+        
+        slaves stay selected until deselected or [reset](Self::reset), so the master should deselect a slave before selecting an other one
+    */
+    #[cfg(feature = "extended-address")]
+    pub async fn select(&self, selected: bool) -> UartcatResult<()> {
+        self.write(registers::SELECT, u8::from(selected)).await
+    }
     
     pub async fn read_bytes<'d>(&self, address: SlaveSize, data: &'d mut [u8]) -> UartcatResult<&'d mut [u8]> {
         self.command(address, true, false, data).await
@@ -174,15 +602,16 @@ impl<'m> Slave<'m> {
     pub async fn stream_bytes(&self, _address: SlaveSize, _size: SlaveSize) -> StreamBytes<'m>   {todo!()}
     
     
+    /// send a one-shot command and wait for its answer, taking its answer buffer from the master like `Master::command_at`
     async fn command<'d>(&self, address: SlaveSize, read: bool, write: bool, data: &'d mut [u8]) -> UartcatResult<&'d mut [u8]> {
         let executed = {
             let topic = Topic::new(
                 self.master, 
                 self.host.at(address.into()), 
-                PinnedBuffer::Borrowed(data),
+                self.master.buffer(data.len()),
                 ).await?;
-            topic.send(read, write, None).await?;
-            topic.receive(None).await?
+            topic.send(read, write, Some(data)).await?;
+            topic.receive(Some(data)).await?
             };
         Ok(Answer {data, executed})
     }
@@ -190,39 +619,167 @@ impl<'m> Slave<'m> {
 
 
 
+/**
+    represent a region of the virtual memory, starting at a base address
+    
+    registers accessed through this struct are relative to the region's base address. It offers the same methods as [Slave] so that virtual and per-slave accesses look the same.
+    
+    this struct is a simple reference and address and can be created and destroyed whenever with no effect on the bus
+*/
+pub struct VirtualRegion<'m> {
+    master: &'m Master,
+    base: VirtualSize,
+}
+impl<'m> VirtualRegion<'m> {
+    pub fn new(master: &'m Master, base: VirtualSize) -> Self {
+        Self {master, base}
+    }
+    /// base address of this region in the virtual memory
+    pub fn address(&self) -> VirtualSize {
+        self.base
+    }
+    /// absolute register in the virtual memory, for the given register relative to this region
+    pub fn absolute<T>(&self, register: VirtualRegister<T>) -> Result<VirtualRegister<T>, Error> {
+        self.base.checked_add(register.address())
+            .map(VirtualRegister::new)
+            .ok_or(Error::Master("register is out of virtual memory"))
+    }
+    
+    pub async fn stream<T: FromBytes + ToBytes>(&self, register: VirtualRegister<T>) -> Result<Stream<'m, T>, Error> {
+        Stream::<T, VirtualSize>::new(self.master, self.absolute(register)?, STREAM_DEPTH).await
+    }
+    pub async fn read<T: FromBytes>(&self, register: VirtualRegister<T>) -> UartcatResult<T> {
+        self.master.read(self.absolute(register)?).await
+    }
+    pub async fn write<T: ToBytes>(&self, register: VirtualRegister<T>, value: T) -> UartcatResult<()> {
+        self.master.write(self.absolute(register)?, value).await
+    }
+    /// read-then-write the given register in the virtual memory
+    pub async fn exchange<C: ByteArray, T: ToBytes<Bytes=C> + FromBytes<Bytes=C>>(&self, register: VirtualRegister<T>, value: T) -> UartcatResult<T> {
+        self.master.exchange(self.absolute(register)?, value).await
+    }
+}
+
+/**
+    set of slave registers that can be read at once, this is implemented for tuples of [SlaveRegister]
+    
+    see [Slave::read_many]
+*/
+pub trait RegisterSet {
+    /// tuple of values of the registers
+    type Value;
+    /// memory range covering all registers, `None` if it exceeds slave memory
+    fn span(&self) -> Option<Range<SlaveSize>>;
+    /// extract all register values from a buffer starting at the given address
+    fn unpack(&self, start: SlaveSize, data: &[u8]) -> Self::Value;
+}
+macro_rules! impl_register_set {
+    ($($t:ident $i:tt),+) => {
+        impl<$($t: FromBytes),+> RegisterSet for ($(SlaveRegister<$t>,)+) {
+            type Value = ($($t,)+);
+            
+            fn span(&self) -> Option<Range<SlaveSize>> {
+                let start = [$(self.$i.address()),+].into_iter().min()?;
+                let end = [$(self.$i.address().checked_add(self.$i.size())?),+].into_iter().max()?;
+                Some(start .. end)
+            }
+            fn unpack(&self, start: SlaveSize, data: &[u8]) -> Self::Value {
+                ($({
+                    let mut buffer = $t::Bytes::zeroed();
+                    buffer.as_mut().copy_from_slice(&data[usize::from(self.$i.address() - start) ..][.. $t::Bytes::SIZE]);
+                    $t::from_be_bytes(buffer)
+                },)+)
+            }
+        }
+    };
+}
+impl_register_set!(A 0);
+impl_register_set!(A 0, B 1);
+impl_register_set!(A 0, B 1, C 2);
+impl_register_set!(A 0, B 1, C 2, D 3);
+impl_register_set!(A 0, B 1, C 2, D 3, E 4);
+impl_register_set!(A 0, B 1, C 2, D 3, E 4, F 5);
+impl_register_set!(A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_register_set!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+
+
+
+/// outcome of [Master::read_large]
+#[derive(Debug)]
+pub struct LargeRead {
+    /// number of bytes read from the start of the range, shorter than the range if a read failed
+    pub done: usize,
+    /// minimum number of slaves that executed the commands, 0 if nothing was read
+    pub executed: u8,
+    /// error of the read that stopped before the end of the range
+    pub error: Option<Error>,
+}
+
+impl Master {
+    /// split `size` bytes of data in the consecutive ranges sent by each command when they do not fit in one, see [Self::max_command]
+    pub(crate) fn chunks(&self, size: usize) -> Result<impl Iterator<Item=Range<usize>>, Error> {
+        let chunk = self.max_command();
+        if chunk == 0 && size != 0 {
+            return Err(Error::Master("maximum command size is too small"));
+        }
+        Ok((0 .. size).step_by(chunk.max(1))
+            .map(move |start| start .. size.min(start + chunk)))
+    }
+}
+
+/// depth of streams created by `stream` methods, allowing one exchange in flight while the previous answer is processed
+const STREAM_DEPTH: usize = 2;
+
+fn check_depth(depth: usize) -> Result<(), Error> {
+    if depth == 0  {Err(Error::Master("stream depth must allow at least one frame"))}
+    else {Ok(())}
+}
+
 /** 
     Custom sequence access to bus memory
   
     It basically reserve a topic token on the bus, and allows repeated sending/receval using the same topic and memory area.
     The consequence is that any answer concerning that topic and region are received indistinctly. It allows custom exchange sequences, like artcat commands without waiting for answers, and receving answers in a separate coroutine.
+    
+    Only the last answer is kept until received, so sending waits while `depth` frames are outstanding (in flight or answered but not received yet). A depth of 1 guarantees no answer is overwritten before being received. Answers arriving after a newer frame was sent are outdated, so they are discarded and only the answer of the last frame sent is received.
 */
 pub struct Stream<'m, T, A=VirtualSize> {
     register: Register<T,A>,
     topic: Topic<'m>,
+    /// maximum number of outstanding frames before sending waits
+    depth: usize,
+    /// an exchange has been sent by [Self::cycle] and its answer not yet received
+    primed: bool,
 }
 impl<'m, T> Stream<'m, T, SlaveSize>
 where T: FromBytes {
-    pub async fn new(master: &'m Master, host: Host, register: SlaveRegister<T>) -> Result<Self, Error> {
+    pub async fn new(master: &'m Master, host: Host, register: SlaveRegister<T>, depth: usize) -> Result<Self, Error> {
+        check_depth(depth)?;
         Ok(Self {
             topic: Topic::new(
                 master, 
                 host.at(register.address()), 
-                PinnedBuffer::Owned(Vec::from(T::Bytes::zeroed().as_ref())),
+                Vec::from(T::Bytes::zeroed().as_ref()),
                 ).await?,
             register,
+            depth,
+            primed: false,
             })
     }
 }
 impl<'m, T> Stream<'m, T, VirtualSize> 
 where T: FromBytes {
-    pub async fn new(master: &'m Master, register: VirtualRegister<T>) -> Result<Self, Error> {
+    pub async fn new(master: &'m Master, register: VirtualRegister<T>, depth: usize) -> Result<Self, Error> {
+        check_depth(depth)?;
         Ok(Self {
             topic: Topic::new(
                 master, 
                 Address::Virtual(register.address()), 
-                PinnedBuffer::Owned(Vec::from(T::Bytes::zeroed().as_ref())),
+                Vec::from(T::Bytes::zeroed().as_ref()),
                 ).await?,
             register,
+            depth,
+            primed: false,
             })
     }
 }
@@ -233,6 +790,14 @@ where
 {
     /// return the register we are streaming
     pub fn register(&self) -> Register<T,A>  {self.register.clone()}
+    /// maximum number of outstanding frames before sending waits
+    pub fn depth(&self) -> usize  {self.depth}
+    /// number of frames sent and not answered yet, plus the answer not received yet
+    pub async fn outstanding(&self) -> usize  {self.topic.outstanding().await}
+    /// token of this stream's frames, to [cancel](Master::cancel) it from an other task
+    pub fn token(&self) -> u16  {self.topic.token()}
+    /// release the stream's token right away, answers still in flight are ignored
+    pub async fn cancel(self)  {self.topic.cancel().await}
     
     /// wait for a answer to be received, and unpack the received value
     pub async fn receive(&self) -> UartcatResult<T>  {
@@ -243,6 +808,33 @@ where
             executed,
             })
     }
+    /// same as [Self::receive] but with a different timeout than the master's
+    pub async fn receive_timeout(&self, timeout: Duration) -> UartcatResult<T>  {
+        let mut buffer = T::Bytes::zeroed();
+        let executed = self.topic.receive_timeout(Some(buffer.as_mut()), timeout).await?;
+        Ok(Answer{
+            data: T::from_be_bytes(buffer),
+            executed,
+            })
+    }
+    /**
+        wait for a answer to be received, and return its header, data and number of executions as seen on the bus
+        
+        nothing is decoded, so errors reported by slaves are left in the header's access flags rather than returned. This is meant for debugging exchanges
+    */
+    pub async fn receive_raw(&self) -> Result<(Command, Vec<u8>, u8), Error>  {
+        let (header, data) = self.topic.receive_raw().await?;
+        Ok((header, data, header.executed))
+    }
+    /// unpack the answer if one has been received, `None` is returned immediately if nothing was received yet
+    pub fn try_receive(&self) -> Option<UartcatResult<T>>  {
+        let mut buffer = T::Bytes::zeroed();
+        Some(self.topic.try_receive(Some(buffer.as_mut()))?
+            .map(|executed| Answer{
+                data: T::from_be_bytes(buffer),
+                executed,
+                }))
+    }
     /// check whether a answer has been received, and unpack the current value in the buffer whenever nothing has been received
     pub async fn get(&self) -> T  {
         let mut buffer = T::Bytes::zeroed();
@@ -255,18 +847,146 @@ where T: ToBytes
 {
     /// send a write command with the given value, this has not effect on the current value in the buffer
     pub async fn send_write(&self, value: T) -> Result<(), Error>  {
+        self.topic.wait_outstanding(self.depth).await?;
         self.topic.send(false, true, Some(value.to_be_bytes().as_ref())).await
     }
     /// send a read command , this has not effect on the current value in the buffer
     pub async fn send_read(&self) -> Result<(), Error> {
+        self.topic.wait_outstanding(self.depth).await?;
         self.topic.send(true, false, Some(T::Bytes::zeroed().as_ref())).await
     }
-    /// send a read-then-write command writing the given value, this has not effect on the current value in the buffer
+    /**
+        send a read-then-write command writing the given value, this has not effect on the current value in the buffer
+        
+        like all sends, it waits while [Self::depth] frames are outstanding, and fails with [Error::Timeout] if they are not answered and received within the master's timeout
+    */
     pub async fn send_exchange(&self, value: T) -> Result<(), Error> {
+        self.topic.wait_outstanding(self.depth).await?;
         self.topic.send(true, true, Some(value.to_be_bytes().as_ref())).await
     }
 }
 
+impl<'m, T,A> Stream<'m, T,A>
+where 
+    T: FromBytes + ToBytes + Clone,
+    A: Copy,
+{
+    /**
+        send an exchange with the given value, and return the answer to the exchange sent by the previous cycle
+        
+        This implements the usual cyclic loop with one frame of delay, so the bus is always busy with the next exchange while the previous answer is processed. If several answers arrived since the last cycle, only the most recent is returned. 
+        The first cycle has no previous exchange, so it sends the value twice and returns the answer to the first one.
+    */
+    pub async fn cycle(&mut self, value: T) -> UartcatResult<T> {
+        if ! self.primed {
+            self.send_exchange(value.clone()).await?;
+            self.primed = true;
+        }
+        let answer = self.receive().await;
+        self.send_exchange(value).await?;
+        answer
+    }
+}
+
+
+/**
+    periodic read of a slave register, created by [Master::subscribe]
+    
+    it uses a single [Stream] for all reads, so it does not consume tokens on each read. The values can be awaited one by one with [Self::next], or consumed as a `futures::Stream`, which never ends
+*/
+pub struct Subscription<'m, T> {
+    /// state of the reads, moved in the read in progress if any
+    reads: Option<Reads<'m, T>>,
+    /// read in progress, giving back the state of the reads with the value
+    polling: Option<Polling<'m, (Reads<'m, T>, UartcatResult<T>)>>,
+}
+/// internal state of the periodic reads of a [Subscription]
+struct Reads<'m, T> {
+    stream: Stream<'m, T, SlaveSize>,
+    interval: Interval,
+    /// last value received, only kept to skip unchanged values
+    last: Option<Vec<u8>>,
+    changes: bool,
+}
+impl<'m, T: FromBytes + Send + 'm> Subscription<'m, T>
+where T::Bytes: Send {
+    /// only yield values different from the previous one received
+    pub fn changes(mut self) -> Self {
+        if let Some(reads) = &mut self.reads {
+            reads.changes = true;
+        }
+        self
+    }
+    /// wait for the next periodic read to be answered, and return its value
+    pub async fn next(&mut self) -> UartcatResult<T> {
+        poll_fn(|context| self.poll_value(context)).await
+    }
+    fn poll_value(&mut self, context: &mut Context<'_>) -> Poll<UartcatResult<T>> {
+        if let Some(reads) = self.reads.take() {
+            self.polling = Some(Box::pin(reads.next()));
+        }
+        let polling = self.polling.as_mut().expect("subscription state lost");
+        let Poll::Ready((reads, result)) = polling.as_mut().poll(context)
+            else {return Poll::Pending};
+        self.polling = None;
+        self.reads = Some(reads);
+        Poll::Ready(result)
+    }
+}
+// the read in progress is boxed, so nothing is pinned in place
+impl<T> Unpin for Subscription<'_, T> {}
+impl<'m, T: FromBytes + Send + 'm> futures_core::Stream for Subscription<'m, T>
+where T::Bytes: Send {
+    type Item = UartcatResult<T>;
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_value(context).map(Some)
+    }
+}
+impl<'m, T: FromBytes> Reads<'m, T> {
+    /// wait for the next periodic read to be answered, and return its value with the state of the reads
+    async fn next(mut self) -> (Self, UartcatResult<T>) {
+        let result = self.read().await;
+        (self, result)
+    }
+    async fn read(&mut self) -> UartcatResult<T> {
+        loop {
+            self.interval.tick().await;
+            self.stream.topic.send(true, false, Some(T::Bytes::zeroed().as_ref())).await?;
+            let mut buffer = T::Bytes::zeroed();
+            let executed = self.stream.topic.receive(Some(buffer.as_mut())).await?;
+            if self.changes {
+                if self.last.as_deref() == Some(buffer.as_ref())
+                    {continue}
+                self.last = Some(Vec::from(buffer.as_ref()));
+            }
+            return Ok(Answer {
+                data: T::from_be_bytes(buffer),
+                executed,
+                })
+        }
+    }
+}
+impl Master {
+    /**
+        periodically read the given register of the given slave, the values are returned by [Subscription::next]
+        
+        the first read is immediate. If values are not consumed as fast as the period, the missed reads are skipped. A zero period is rejected with [Error::Master]
+    */
+    pub async fn subscribe<T: FromBytes>(&self, host: Host, register: SlaveRegister<T>, period: Duration) -> Result<Subscription<'_, T>, Error> {
+        if period.is_zero()
+            {return Err(Error::Master("subscription period must be non-zero"))}
+        Ok(Subscription {
+            reads: Some(Reads {
+                stream: Stream::<T, SlaveSize>::new(self, host, register, 1).await?,
+                interval: Interval::new(period),
+                last: None,
+                changes: false,
+            }),
+            polling: None,
+        })
+    }
+}
+
 
 /// TODO
 #[allow(unused)]
@@ -0,0 +1,105 @@
+use std::{
+    path::Path,
+    sync::Arc,
+    future::Future,
+    };
+use packbytes::{FromBytes, ToBytes, ByteArray};
+use tokio::{
+    runtime::{Runtime, Builder},
+    task::JoinHandle,
+    };
+use crate::registers::{SlaveRegister, VirtualRegister};
+use super::{
+    networking::Master,
+    accessing::{Host, Slave, UartcatResult},
+    };
+
+
+/**
+    synchronous wrapper around [Master], for programs not using async
+    
+    It owns a tokio runtime with one worker thread, which runs [Master::run] in background: it is still required to receive answers, but the user does not need to run it. Every method blocks the calling thread until the command is answered.
+    
+    Any async method of [Master] can also be called in a blocking way using [Self::block_on]. The blocking methods must not be called from an async context.
+*/
+pub struct BlockingMaster {
+    master: Arc<Master>,
+    runtime: Runtime,
+    run: JoinHandle<Result<(), std::io::Error>>,
+}
+impl BlockingMaster {
+    /// initialize a master on the given serial port file and with the given baud rate, and start its run loop
+    pub fn new(path: impl AsRef<Path>, rate: u32) -> Result<Self, std::io::Error> {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+        // the serial port must be registered in the runtime that will poll it
+        let master = Arc::new(runtime.block_on(async { Master::new(path, rate) })?);
+        let run = runtime.spawn({
+            let master = master.clone();
+            async move { master.run().await }
+            });
+        Ok(Self {master, runtime, run})
+    }
+    /// the async master, for use with [Self::block_on]
+    pub fn master(&self) -> &Master {&self.master}
+    /// true if the run loop stopped, in which case no answer can be received anymore
+    pub fn stopped(&self) -> bool {self.run.is_finished()}
+    
+    /// block the current thread until the given future completes, running it in this master's runtime
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+    /// blocking equivalent of [Master::slave]
+    pub fn slave(&self, host: Host) -> BlockingSlave<'_> {
+        BlockingSlave {master: self, slave: self.master.slave(host)}
+    }
+    /// blocking equivalent of [Master::read]
+    pub fn read<T: FromBytes>(&self, register: VirtualRegister<T>) -> UartcatResult<T> {
+        self.block_on(self.master.read(register))
+    }
+    /// blocking equivalent of [Master::write]
+    pub fn write<T: ToBytes>(&self, register: VirtualRegister<T>, value: T) -> UartcatResult<()> {
+        self.block_on(self.master.write(register, value))
+    }
+    /// blocking equivalent of [Master::exchange]
+    pub fn exchange<C, T>(&self, register: VirtualRegister<T>, value: T) -> UartcatResult<T> 
+    where 
+        C: ByteArray, 
+        T: ToBytes<Bytes=C> + FromBytes<Bytes=C> 
+    {
+        self.block_on(self.master.exchange(register, value))
+    }
+}
+impl Drop for BlockingMaster {
+    fn drop(&mut self) {
+        self.run.abort();
+    }
+}
+
+/// synchronous wrapper around [Slave], created by [BlockingMaster::slave]
+pub struct BlockingSlave<'m> {
+    master: &'m BlockingMaster,
+    slave: Slave<'m>,
+}
+impl<'m> BlockingSlave<'m> {
+    /// the async slave, for use with [BlockingMaster::block_on]
+    pub fn slave(&self) -> &Slave<'m> {&self.slave}
+    /// blocking equivalent of [Slave::read]
+    pub fn read<T: FromBytes>(&self, register: SlaveRegister<T>) -> UartcatResult<T> {
+        self.master.block_on(self.slave.read(register))
+    }
+    /// blocking equivalent of [Slave::write]
+    pub fn write<T: ToBytes>(&self, register: SlaveRegister<T>, value: T) -> UartcatResult<()> {
+        self.master.block_on(self.slave.write(register, value))
+    }
+    /// blocking equivalent of [Slave::exchange]
+    pub fn exchange<C, T>(&self, register: SlaveRegister<T>, value: T) -> UartcatResult<T> 
+    where 
+        C: ByteArray, 
+        T: ToBytes<Bytes=C> + FromBytes<Bytes=C> 
+    {
+        self.master.block_on(self.slave.exchange(register, value))
+    }
+}
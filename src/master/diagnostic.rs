@@ -0,0 +1,423 @@
+use core::{
+    ops::Range,
+    pin::Pin,
+    future::poll_fn,
+    task::{Context, Poll},
+    };
+use std::{
+    time::{Duration, Instant},
+    vec::Vec,
+    boxed::Box,
+    };
+use crate::registers::{self, CommandError, SlaveSize};
+use super::{
+    Error,
+    networking::Master,
+    accessing::{Host, Slave, Polling},
+    timer,
+    };
+
+
+impl Master {
+    /**
+        count the slaves currently reachable on the bus
+
+        this sends a read of one byte in the virtual memory, which is executed by every slave without altering anything. A bus with no answer is counted as empty
+    */
+    pub async fn count_slaves(&self) -> Result<u8, Error> {
+        match self.read_bytes(0, &mut [0]).await {
+            Ok(answer) => Ok(answer.executed),
+            Err(Error::Timeout) => Ok(0),
+            Err(err) => Err(err),
+        }
+    }
+    /**
+        send throwaway reads to the given slave until one is answered, to synchronize the bus before the first real command
+        
+        after power-up, slaves may not have caught the frames headers yet, so the first commands are often lost. Each attempt waits for the timeout configured in the master (see [Self::timeout_for]), [Error::Timeout] is returned if all the given attempts are lost, and [Error::NoSlave] if the answer came back with no slave at this address
+    */
+    pub async fn probe(&self, host: Host, attempts: usize) -> Result<(), Error> {
+        if self.slave(host).present(attempts).await?
+            {Ok(())}
+        else
+            {Err(Error::NoSlave)}
+    }
+    /**
+        check whether slaves answer at the given address, returning how many executed the command
+        
+        this sends a read of zero bytes, which slaves execute without locking their buffer, so it is the cheapest presence probe. Unlike [Self::probe], it is sent once, so a lost answer is returned as [Error::Timeout]
+    */
+    pub async fn ping(&self, host: Host) -> Result<u8, Error> {
+        Ok(self.slave(host).read_bytes(registers::VERSION.address(), &mut []).await?.executed)
+    }
+    /// read the communication health registers of every slave on the bus
+    pub async fn diagnostics(&self) -> Result<BusDiagnostics, Error> {
+        let mut diagnostics = BusDiagnostics::default();
+        for index in 0 .. SlaveSize::from(self.count_slaves().await?) {
+            let (error, loss) = self.slave(Host::Topological(index))
+                .read_many((registers::ERROR, registers::LOSS)).await?.one()?;
+            diagnostics.total_loss += u32::from(loss);
+            if diagnostics.error.is_none() && error != CommandError::None {
+                diagnostics.error = Some((index, error));
+            }
+            diagnostics.slaves.push(SlaveDiagnostics {index, loss, error});
+        }
+        Ok(diagnostics)
+    }
+    /**
+        reset the communication health registers of every slave on the bus
+        
+        there is no broadcast for slave registers, so this is sending one command per slave
+    */
+    pub async fn reset_diagnostics(&self) -> Result<(), Error> {
+        for index in 0 .. SlaveSize::from(self.count_slaves().await?) {
+            let slave = self.slave(Host::Topological(index));
+            slave.write(registers::ERROR, CommandError::None).await?.one()?;
+            slave.write(registers::LOSS, 0).await?.one()?;
+        }
+        Ok(())
+    }
+    /**
+        check that every slave on the bus implements the protocol version of this crate
+        
+        returns the number of slaves checked, or [Error::IncompatibleVersion] with the version of the first slave differing. This is meant to be done when connecting to a bus, before any other communication
+    */
+    pub async fn check_versions(&self) -> Result<u8, Error> {
+        let count = self.count_slaves().await?;
+        for index in 0 .. SlaveSize::from(count) {
+            self.slave(Host::Topological(index)).check_version().await?;
+        }
+        Ok(count)
+    }
+    /**
+        check the bus wiring by comparing the reachable slaves with the number of slaves expected
+        
+        each reachable slave is also asked its fixed address. If the chain is broken, the returned report gives the topological index where answers stop
+    */
+    pub async fn check_chain(&self, expected: u8) -> Result<ChainReport, Error> {
+        let reached = self.count_slaves().await?;
+        let mut report = ChainReport {expected, reached, addresses: Vec::new(), broken: None};
+        for index in 0 .. SlaveSize::from(expected) {
+            let answer = match self.slave(Host::Topological(index)).read(registers::ADDRESS).await {
+                Ok(answer) => answer.one(),
+                Err(Error::Timeout) => Err(Error::Timeout),
+                Err(err) => return Err(err),
+            };
+            match answer {
+                Ok(address) => report.addresses.push(address),
+                Err(_) => {
+                    report.broken = Some(index);
+                    break
+                },
+            }
+        }
+        if report.broken.is_none() && reached < expected {
+            report.broken = Some(SlaveSize::from(reached));
+        }
+        Ok(report)
+    }
+    /**
+        check that topological and fixed addressing reach the same slaves, to find a slave not handling topological addresses correctly (like an old firmware not decrementing them)
+        
+        every reachable slave is assigned its topological index as fixed address, then each index is read with both addressings. The first topological index where they do not match is returned, it is the position of the misbehaving slave or the one following it
+        
+        this overwrites the fixed addresses of all slaves
+    */
+    pub async fn check_addressing(&self) -> Result<Option<SlaveSize>, Error> {
+        let count = SlaveSize::from(self.count_slaves().await?);
+        for index in 0 .. count {
+            let answer = self.slave(Host::Topological(index)).write(registers::ADDRESS, index).await?;
+            if answer.executed != 1 
+                {return Ok(Some(index))}
+        }
+        for index in 0 .. count {
+            let topological = self.slave(Host::Topological(index)).read(registers::ADDRESS).await?;
+            let fixed = self.slave(Host::Fixed(index)).read(registers::ADDRESS).await?;
+            if topological.executed != 1 || topological.data != index
+            || fixed.executed != 1 || fixed.data != index
+                {return Ok(Some(index))}
+        }
+        Ok(None)
+    }
+    /**
+        measure the effective bus bandwidth, to help picking a baud rate and a maximum command size for a chain
+        
+        reads of the virtual memory, executed by every slave without altering anything, are timed for sizes doubling from the start of the given range, up to its end or the master's [max_command](Master::max_command). Each size is read a few times, and the measure stops at the first size all reads of which time out
+    */
+    pub async fn benchmark(&self, sizes: Range<usize>) -> Result<BandwidthReport, Error> {
+        const ATTEMPTS: u32 = 4;
+        let end = sizes.end.min(self.max_command() + 1);
+        let mut report = BandwidthReport::default();
+        let mut size = sizes.start.max(1);
+        let mut data = std::vec![0; end];
+        while size < end {
+            let mut sample = BandwidthSample {size, duration: Duration::ZERO, timeouts: 0};
+            for _ in 0 .. ATTEMPTS {
+                let start = Instant::now();
+                match self.read_bytes(0, &mut data[.. size]).await {
+                    Ok(_) => sample.duration += start.elapsed(),
+                    Err(Error::Timeout) => sample.timeouts += 1,
+                    Err(error) => return Err(error),
+                }
+            }
+            if sample.timeouts == ATTEMPTS 
+                {break}
+            sample.duration /= ATTEMPTS - sample.timeouts;
+            report.samples.push(sample);
+            size *= 2;
+        }
+        report.estimate();
+        Ok(report)
+    }
+    /**
+        start watching for slaves added or removed from the bus, by periodically counting the slaves
+        
+        the slaves are counted with [Self::count_slaves] rather than a topological read of [registers::VERSION], because a topological read is only executed by the addressed slave and cannot tell how many follow it
+    */
+    pub async fn watch_topology(&self, period: Duration) -> Result<TopologyWatch<'_>, Error> {
+        Ok(TopologyWatch {
+            master: self,
+            period,
+            count: self.count_slaves().await?,
+            candidate: None,
+            polling: None,
+        })
+    }
+}
+
+/// communication health of all slaves on the bus, see [Master::diagnostics]
+#[derive(Clone, Debug, Default)]
+pub struct BusDiagnostics {
+    /// health of each slave, in topological order
+    pub slaves: Vec<SlaveDiagnostics>,
+    /// sum of loss counters of all slaves
+    pub total_loss: u32,
+    /// first error reported in topological order, with the topological index of its slave
+    pub error: Option<(SlaveSize, CommandError)>,
+}
+/// communication health of one slave
+#[derive(Copy, Clone, Debug)]
+pub struct SlaveDiagnostics {
+    /// topological index of the slave
+    pub index: SlaveSize,
+    /// content of register [registers::LOSS]
+    pub loss: u16,
+    /// content of register [registers::ERROR]
+    pub error: CommandError,
+}
+
+/// result of a bus wiring check, see [Master::check_chain]
+#[derive(Clone, Debug)]
+pub struct ChainReport {
+    /// number of slaves expected on the bus
+    pub expected: u8,
+    /// number of slaves that executed a command sent to all of them
+    pub reached: u8,
+    /// fixed addresses of reachable slaves, in topological order
+    pub addresses: Vec<SlaveSize>,
+    /// topological index of the first slave not answering, if any
+    pub broken: Option<SlaveSize>,
+}
+impl ChainReport {
+    /// true if all expected slaves are answering
+    pub fn complete(&self) -> bool {
+        self.broken.is_none()
+    }
+}
+
+/// effective bus bandwidth, see [Master::benchmark]
+#[derive(Clone, Debug, Default)]
+pub struct BandwidthReport {
+    /// measures for each size read, by increasing size
+    pub samples: Vec<BandwidthSample>,
+    /// bytes per second transmitted once a frame is started, if at least two sizes were measured
+    pub throughput: Option<f64>,
+    /// time spent by each frame regardless of its size (header, latency of slaves and the master), if at least two sizes were measured
+    pub overhead: Option<Duration>,
+}
+/// measure of one read size
+#[derive(Copy, Clone, Debug)]
+pub struct BandwidthSample {
+    /// number of data bytes read
+    pub size: usize,
+    /// mean round-trip time of the reads that did not time out
+    pub duration: Duration,
+    /// number of reads that timed out
+    pub timeouts: u32,
+}
+impl BandwidthReport {
+    /// fit round-trip times as an overhead plus a time proportional to the size
+    fn estimate(&mut self) {
+        if self.samples.len() < 2
+            {return}
+        let count = self.samples.len() as f64;
+        let mean_size = self.samples.iter().map(|sample| sample.size as f64).sum::<f64>() / count;
+        let mean_time = self.samples.iter().map(|sample| sample.duration.as_secs_f64()).sum::<f64>() / count;
+        let (mut covariance, mut variance) = (0., 0.);
+        for sample in &self.samples {
+            let size = sample.size as f64 - mean_size;
+            covariance += size * (sample.duration.as_secs_f64() - mean_time);
+            variance += size * size;
+        }
+        let slope = covariance / variance;
+        if slope > 0. {
+            self.throughput = Some(1. / slope);
+        }
+        self.overhead = Some(Duration::from_secs_f64((mean_time - slope * mean_size).max(0.)));
+    }
+}
+
+/// change in the number of slaves on the bus
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TopologyEvent {
+    /// slaves appeared on the bus
+    SlaveAdded {before: u8, after: u8},
+    /// slaves disappeared from the bus
+    SlaveRemoved {before: u8, after: u8},
+}
+
+/**
+    periodic poll of the number of slaves on the bus, created by [Master::watch_topology]
+
+    a change is only reported once it has been observed by two consecutive polls, so that a single lost answer does not produce spurious events
+    
+    the events can be awaited one by one with [Self::next], or consumed as a `futures::Stream`, which never ends
+*/
+pub struct TopologyWatch<'m> {
+    master: &'m Master,
+    period: Duration,
+    /// last confirmed number of slaves
+    count: u8,
+    /// number of slaves seen by the last poll if it differs from the confirmed one
+    candidate: Option<u8>,
+    /// polls in progress, returning the new candidate and the event
+    polling: Option<Polling<'m, Watched>>,
+}
+impl TopologyWatch<'_> {
+    /// last confirmed number of slaves
+    pub fn count(&self) -> u8 {self.count}
+
+    /// wait for the next confirmed change of topology
+    pub async fn next(&mut self) -> Result<TopologyEvent, Error> {
+        poll_fn(|context| self.poll_event(context)).await
+    }
+    fn poll_event(&mut self, context: &mut Context<'_>) -> Poll<Result<TopologyEvent, Error>> {
+        let polling = self.polling.get_or_insert_with(|| Box::pin(
+            watch(self.master, self.period, self.count, self.candidate)
+            ));
+        let Poll::Ready((candidate, result)) = polling.as_mut().poll(context)
+            else {return Poll::Pending};
+        self.polling = None;
+        self.candidate = candidate;
+        if let Ok(TopologyEvent::SlaveAdded {after, ..} | TopologyEvent::SlaveRemoved {after, ..}) = result {
+            self.count = after;
+        }
+        Poll::Ready(result)
+    }
+}
+impl futures_core::Stream for TopologyWatch<'_> {
+    type Item = Result<TopologyEvent, Error>;
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_event(context).map(Some)
+    }
+}
+/// new candidate and event returned by [watch]
+type Watched = (Option<u8>, Result<TopologyEvent, Error>);
+/// poll the number of slaves until it differs from `count` twice in a row
+async fn watch(master: &Master, period: Duration, count: u8, mut candidate: Option<u8>) -> Watched {
+    loop {
+        timer::sleep(period).await;
+        let polled = match master.count_slaves().await {
+            Ok(polled) => polled,
+            Err(error) => return (candidate, Err(error)),
+        };
+        if polled == count {
+            candidate = None;
+        }
+        else if candidate != Some(polled) {
+            candidate = Some(polled);
+        }
+        else {
+            let before = count;
+            return (None, Ok(if polled > before
+                {TopologyEvent::SlaveAdded {before, after: polled}}
+            else
+                {TopologyEvent::SlaveRemoved {before, after: polled}}
+                ))
+        }
+    }
+}
+
+
+impl Slave<'_> {
+    /**
+        check whether a slave answers to this address, retrying when answers are lost
+        
+        an answer executed by no slave went through the whole bus, so it proves the address is unoccupied. Lost or corrupted answers prove nothing and are retried, [Error::Timeout] is returned if all the given attempts are lost
+    */
+    pub async fn present(&self, attempts: usize) -> Result<bool, Error> {
+        let mut last = Error::Timeout;
+        for _ in 0 .. attempts {
+            match self.read(registers::VERSION).await {
+                Ok(answer) => return Ok(answer.present()),
+                // only the addressed slave can report an error
+                Err(Error::Slave(_)) => return Ok(true),
+                Err(Error::Disconnected) => return Err(Error::Disconnected),
+                Err(error) => last = error,
+            }
+        }
+        Err(last)
+    }
+    /// check that the slave implements [registers::PROTOCOL_VERSION], see [Master::check_versions]
+    pub async fn check_version(&self) -> Result<(), Error> {
+        match self.read(registers::VERSION).await?.one()? {
+            registers::PROTOCOL_VERSION => Ok(()),
+            version => Err(Error::IncompatibleVersion(version)),
+        }
+    }
+    /**
+        read the whole slave buffer, for post-mortem analysis of a slave
+        
+        its size is given by [registers::BUFFER_SIZE], segments after the buffer are not read. See [Self::dump_range]
+    */
+    pub async fn dump(&self) -> Result<Dump, Error> {
+        let size = self.buffer_size().await?.one()?;
+        Ok(self.dump_range(0, usize::from(size)).await)
+    }
+    /**
+        read the given range of slave memory, in as many commands as needed by the master's [max_command](Master::max_command)
+        
+        the commands are sent one after the other, and the first failing command stops the dump, so the bytes read before are still returned
+    */
+    pub async fn dump_range(&self, start: SlaveSize, size: usize) -> Dump {
+        let mut dump = Dump {data: std::vec![0; size], error: None};
+        let mut done = 0;
+        match self.master().chunks(size) {
+            Err(error) => dump.error = Some(error),
+            Ok(chunks) => for part in chunks {
+                let Ok(address) = SlaveSize::try_from(usize::from(start) + part.start) else {
+                    dump.error = Some(Error::Master("dump range exceeds slave memory"));
+                    break
+                };
+                let result = self.read_bytes(address, &mut dump.data[part.clone()]).await
+                    .and_then(|answer| answer.one());
+                if let Err(error) = result {
+                    dump.error = Some(error);
+                    break
+                }
+                done = part.end;
+            },
+        }
+        dump.data.truncate(done);
+        dump
+    }
+}
+
+/// content of a range of slave memory, see [Slave::dump]
+#[derive(Debug)]
+pub struct Dump {
+    /// bytes read from the start of the range, shorter than the range if a read failed
+    pub data: Vec<u8>,
+    /// error of the read that stopped the dump before the end of the range
+    pub error: Option<Error>,
+}
@@ -1,68 +1,361 @@
 use log::*;
-use packbytes::{FromBytes, ByteArray};
+use packbytes::{FromBytes, ToBytes, ByteArray};
 use std::{
     marker::PhantomData,
     collections::HashMap,
+    ops::Range,
     vec::Vec,
     };
-use crate::registers::{self, SlaveRegister, VirtualRegister};
+use crate::{
+    registers::{self, SlaveRegister, VirtualRegister, SlaveSize, VirtualSize},
+    command::MAX_COMMAND,
+    };
 use super::accessing::{Host, Slave};
+use super::networking::Master;
 use super::{Error, usize_to_message};
 
 
-/// helper to build a global config of slaves mappings to the common virtual memory. it follows the builder pattern
+/**
+    helper to build a global config of slaves mappings to the common virtual memory. it follows the builder pattern
+    
+    Independent process images (like cyclic loops at different rates) can be built in separate mappings starting at different virtual addresses with [Self::new_at], then merged with [Self::merge] since each slave has only one mapping table.
+*/
 #[derive(Clone, Debug)]
 pub struct Mapping {
     map: HashMap<Host, Vec<registers::Mapping>>,
+    /// start of the first buffer
+    base: u32,
     end: u32,
 }
 impl Mapping {
     pub fn new() -> Self {
+        Self::new_at(0)
+    }
+    /// same as [Self::new] but placing buffers from the given virtual address
+    pub fn new_at(base: VirtualSize) -> Self {
         Self {
             map: HashMap::new(),
-            end: 0,
+            base,
+            end: base,
+        }
+    }
+    /**
+        add the registers of an other mapping to this one, so both can be configured at once
+        
+        their virtual regions must not overlap, otherwise [Error::Master] is returned and this mapping is left unchanged
+    */
+    pub fn merge(&mut self, other: &Mapping) -> Result<(), Error> {
+        let empty = |mapping: &Mapping| mapping.base == mapping.end;
+        if !empty(self) && !empty(other) && self.base < other.end && other.base < self.end {
+            return Err(Error::Master("merged mappings have overlapping virtual regions"));
+        }
+        for (&host, table) in &other.map {
+            self.map.entry(host).or_default().extend_from_slice(table);
+        }
+        if empty(self) {
+            (self.base, self.end) = (other.base, other.end);
         }
+        else if !empty(other) {
+            self.base = self.base.min(other.base);
+            self.end = self.end.max(other.end);
+        }
+        Ok(())
     }
     pub fn buffer<T: FromBytes>(&mut self) -> Result<BufferMapping<'_, T>, Error> {
         let start = self.end;
-        self.end = self.end.checked_add(usize_to_message(T::Bytes::SIZE)?.into())
+        self.end = self.end.checked_add(usize_to_message(T::Bytes::SIZE, MAX_COMMAND)?.into())
             .ok_or(Error::Master("no more virtual memory available"))?;
         Ok(BufferMapping {
             start,
             end: start,
+            padded: 0,
             mapping: self,
             ty: PhantomData,
             })
     }
+    /// map the registers of a [Mapped] struct from the given slave, see [Mapped]
+    pub fn mapped<T: Mapped>(&mut self, slave: Host) -> Result<VirtualRegister<T>, Error> {
+        T::map(self, slave)
+    }
     pub fn map(&self) -> &HashMap<Host, Vec<registers::Mapping>> {
         &self.map
     }
+    /**
+        check the mapping and preview the resulting layout, without accessing the bus
+        
+        the checks not needing the slaves are performed here, whereas checking that registers exist in the slave memory is done by [Self::validate]
+    */
+    pub fn plan(&self) -> MappingPlan {
+        let mut tables = self.map.iter()
+            .map(|(&host, table)| {
+                let mut table = table.clone();
+                table.sort_by_key(|item| item.virtual_start);
+                (host, table)
+            })
+            .collect::<Vec<_>>();
+        tables.sort_by_key(|(host, _)| host_order(host));
+        
+        let mut warnings = Vec::new();
+        let capacity = registers::MAPPING_ENTRIES;
+        for (host, table) in &tables {
+            if table.len() > capacity {
+                warnings.push(PlanWarning::TooManyEntries {host: *host, count: table.len()});
+            }
+            for item in table {
+                if usize::from(item.slave_start) < registers::USER {
+                    warnings.push(PlanWarning::StandardRegister {host: *host, slave_start: item.slave_start});
+                }
+                if usize::from(item.slave_start) + usize::from(item.size) > usize::from(SlaveSize::MAX) + 1 {
+                    warnings.push(PlanWarning::OutOfRange {host: *host, slave_start: item.slave_start});
+                }
+            }
+        }
+        // every pair of entries mapping the same virtual bytes, they are checked once
+        let entries = tables.iter()
+            .flat_map(|(host, table)| table.iter().map(move |item| (*host, item)))
+            .collect::<Vec<_>>();
+        for (i, &(first, a)) in entries.iter().enumerate() {
+            for &(second, b) in &entries[i+1 ..] {
+                if registers::intersect_mapping(*a, b.virtual_range()).is_some() {
+                    warnings.push(PlanWarning::VirtualOverlap {first, second, virtual_start: a.virtual_start.max(b.virtual_start)});
+                }
+            }
+        }
+        MappingPlan {tables, size: self.end - self.base, warnings}
+    }
+    /// slaves having at least one register mapped in the given virtual register, sorted by address
+    pub fn hosts<T: FromBytes>(&self, register: VirtualRegister<T>) -> Vec<Host> {
+        let (start, stop) = (register.address(), register.address().saturating_add(u32::from(register.size())));
+        let mut hosts = self.map.iter()
+            .filter(|(_, table)| table.iter().any(|&item| 
+                registers::intersect_mapping(item, start .. stop).is_some()))
+            .map(|(&host, _)| host)
+            .collect::<Vec<_>>();
+        hosts.sort_by_key(host_order);
+        hosts
+    }
+    /**
+        byte ranges of the given virtual register that are backed by slave registers, as offsets in the register sorted and merged
+        
+        slaves pass the other bytes through unchanged, so in a read they keep whatever the master sent (like stale data in padding between mapped fields)
+    */
+    pub fn coverage<T: FromBytes>(&self, register: VirtualRegister<T>) -> Vec<Range<usize>> {
+        let frame = register.address() .. register.address().saturating_add(u32::from(register.size()));
+        let mut covered = self.map.values()
+            .flatten()
+            .filter_map(|&item| registers::intersect_mapping(item, frame.clone()))
+            .map(|(in_frame, _)| in_frame)
+            .collect::<Vec<_>>();
+        covered.sort_by_key(|range| range.start);
+        let mut merged = Vec::<Range<usize>>::with_capacity(covered.len());
+        for range in covered {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        merged
+    }
+    /**
+        find which slaves failed an exchange of the given virtual register
+        
+        the answer of a virtual exchange merges all slaves contributions, so its error flag does not tell which slave failed. This reads the [registers::ERROR] register of every slave mapped in the register (see [Self::hosts]) and reports the ones having an error
+        
+        slaves only keep their first error until it is reset, so the errors should be reset before the exchange to not report older failures
+    */
+    pub async fn errors<T: FromBytes>(&self, master: &Master, register: VirtualRegister<T>) -> Result<Vec<(Host, registers::CommandError)>, Error> {
+        let mut errors = Vec::new();
+        for host in self.hosts(register) {
+            let error = master.slave(host).read(registers::ERROR).await?.one()?;
+            if error != registers::CommandError::None {
+                errors.push((host, error));
+            }
+        }
+        Ok(errors)
+    }
+    /**
+        write the given virtual register, making sure all the `slaves` of the chain applied the write, see [Master::write_expected]
+        
+        on a partial write or an error reported by the slaves, the [registers::ERROR] register of the slaves mapped in it is read like in [Self::errors], and the ones having an error are returned in [Error::PartialWrite]. If none has an error, or if reading them fails, the error of the write itself is returned. The errors should be reset before the write to not report older failures
+    */
+    pub async fn write_checked<T: FromBytes + ToBytes>(&self, master: &Master, register: VirtualRegister<T>, value: T, slaves: u8) -> Result<(), Error> {
+        let failure = match master.write(register, value).await {
+            Ok(answer) if answer.executed >= slaves => return answer.exact(slaves),
+            Ok(answer) => {
+                warn!("write {} applied by {} slaves out of {}", register, answer.executed, slaves);
+                Error::Master("partial write")
+            },
+            Err(error @ Error::Slave(_)) => error,
+            Err(error) => return Err(error),
+        };
+        match self.errors(master, register).await {
+            Ok(dropped) if !dropped.is_empty() => Err(Error::PartialWrite(dropped)),
+            Ok(_) => Err(failure),
+            Err(error) => {
+                warn!("cannot read which slaves dropped write {}: {}", register, error);
+                Err(failure)
+            },
+        }
+    }
+    /**
+        write the mapping table of the given slave
+        
+        the mapped registers are first checked to exist in the slave memory, and their number against the slave's [registers::MAPPING_CAPACITY], so a wrong mapping is reported here rather than by [registers::CommandError::InvalidMapping] on the slave later
+        
+        only the used entries of the table are sent. If they do not fit in one command of the master's [max_command](super::Master::max_command), they are written in several commands and the table size is written last, which is when the slave applies the new table
+    */
     pub async fn configure(&self, slave: &Slave<'_>) -> Result<(), Error> {
-        let mut mapping = registers::MappingTable::default();
-        if let Some(table) = self.map.get(&slave.address()) {
-            if table.len() > mapping.map.len() {
-                return Err(Error::Master("too many items in mapping table"));
+        const ENTRY: usize = <registers::Mapping as FromBytes>::Bytes::SIZE;
+        self.validate(slave).await?;
+        let table = self.map.get(&slave.address()).map(Vec::as_slice).unwrap_or(&[]);
+        if table.len() > registers::MAPPING_ENTRIES {
+            return Err(Error::Master("too many items in mapping table"));
+        }
+        if table.len() > usize::from(slave.read(registers::MAPPING_CAPACITY).await?.one()?) {
+            return Err(Error::Master("too many items for the slave mapping capacity"));
+        }
+        let size = u8::try_from(table.len()).unwrap();
+        let mut entries = table.iter()
+            .flat_map(|item| item.to_be_bytes())
+            .collect::<Vec<u8>>();
+        let max = slave.master().max_command();
+        
+        // the table size takes one byte before the entries
+        if entries.len() < max {
+            let mut data = Vec::with_capacity(1 + entries.len());
+            data.push(size);
+            data.extend_from_slice(&entries);
+            slave.write_bytes(registers::MAPPING.address(), &mut data).await?.one()
+        }
+        else {
+            let chunk = max / ENTRY * ENTRY;
+            if chunk == 0 {
+                return Err(Error::Master("maximum command size is too small for a mapping entry"));
             }
-            mapping.size = u8::try_from(table.len()).unwrap();
-            for (i, item) in table.iter().enumerate() {
-                mapping.map[i] = *item;
+            let start = registers::MAPPING.address() + 1;
+            for (i, part) in entries.chunks_mut(chunk).enumerate() {
+                slave.write_bytes(start + (i * chunk) as u16, part).await?.one()?;
             }
+            slave.write_bytes(registers::MAPPING.address(), &mut [size]).await?.one()
+        }
+    }
+    /**
+        write the mapping tables of all slaves having registers in this mapping, see [Self::configure]
+        
+        once done, one virtual exchange of a buffer gathers the registers of all its slaves
+    */
+    pub async fn configure_all(&self, master: &Master) -> Result<(), Error> {
+        let mut hosts = self.map.keys().copied().collect::<Vec<_>>();
+        hosts.sort_by_key(host_order);
+        for host in hosts {
+            self.configure(&master.slave(host)).await?;
         }
-        slave.write(registers::MAPPING, mapping).await?.one()
+        Ok(())
+    }
+    /**
+        check that all registers mapped for the given slave are in its user memory
+        
+        the slave memory size is not known to the master, and can have gaps when the slave uses segments, so each mapped register is probed by reading it
+    */
+    pub async fn validate(&self, slave: &Slave<'_>) -> Result<(), Error> {
+        let Some(table) = self.map.get(&slave.address())
+            else {return Ok(())};
+        for item in table {
+            if usize::from(item.slave_start) < registers::USER {
+                error!("mapped register {:#x} of {:?} is in the standard section", item.slave_start, slave.address());
+                return Err(Error::Master("mapped register is in the slave standard section"));
+            }
+            let mut probe = std::vec![0; usize::from(item.size)];
+            match slave.read_bytes(item.slave_start, &mut probe).await.and_then(|answer| answer.one()) {
+                Ok(_) => {},
+                Err(Error::Slave(registers::CommandError::InvalidRegister)) => {
+                    error!("mapped register {:#x} ({} bytes) of {:?} is out of slave memory", item.slave_start, item.size, slave.address());
+                    return Err(Error::Master("mapped register is out of slave memory"));
+                },
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// sort key of hosts, giving the topological ones first
+fn host_order(host: &Host) -> (u8, SlaveSize) {
+    match *host {
+        Host::Topological(address) => (0, address),
+        Host::Fixed(address) => (1, address),
     }
 }
 
+/// preview of the configuration of a [Mapping], see [Mapping::plan]
+#[derive(Clone, Debug, PartialEq)]
+pub struct MappingPlan {
+    /// mapping table of each slave, topological slaves first, entries sorted by virtual address
+    pub tables: Vec<(Host, Vec<registers::Mapping>)>,
+    /// size of the virtual region from the first buffer to the end of the last one, including their padding and the gaps between merged mappings
+    pub size: u32,
+    /// problems found in the mapping, the configuration can only succeed if there is none
+    pub warnings: Vec<PlanWarning>,
+}
+impl MappingPlan {
+    /// true if no problem was found
+    pub fn valid(&self) -> bool {
+        self.warnings.is_empty()
+    }
+}
+/// problem found by [Mapping::plan]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PlanWarning {
+    /// a slave has more entries than its mapping table can store
+    TooManyEntries {host: Host, count: usize},
+    /// a mapped register is in the slave standard section, slaves reject it
+    StandardRegister {host: Host, slave_start: SlaveSize},
+    /// a mapped register exceeds the slave addressable memory
+    OutOfRange {host: Host, slave_start: SlaveSize},
+    /// two entries map the same virtual bytes, from the same slave or from different ones
+    VirtualOverlap {first: Host, second: Host, virtual_start: u32},
+}
+
+/**
+    packed struct whose fields are mapped to slave registers, so it can be mapped to virtual memory in one call to [Mapping::mapped]
+    
+    It is meant to be derived, the derive macro ensures the registers are mapped in the order of the fields and each register has the type of its field:
+    ```ignore
+    #[derive(FromBytes, ToBytes, Mapped)]
+    struct MyBuffer {
+        #[uartcat(register = "OFFSETED")]
+        offseted: u32,
+        #[uartcat(padding)]
+        reserved: u16,
+        #[uartcat(register = "OFFSET")]
+        offset: u16,
+    }
+    ```
+*/
+pub trait Mapped: FromBytes {
+    /// add the struct's registers from the given slave in a new buffer of the mapping
+    fn map(mapping: &mut Mapping, slave: Host) -> Result<VirtualRegister<Self>, Error>;
+}
+
 /// helper to map multiple slave registers into a packed struct in the virtual memory. it follows the builder pattern
 #[derive(Debug)]
 pub struct BufferMapping<'m, T> {
     start: u32,
     end: u32,
+    /// number of bytes not mapped to any register
+    padded: u32,
     mapping: &'m mut Mapping,
     ty: PhantomData<T>,
 }
 impl<T: FromBytes> BufferMapping<'_, T> {
+    /**
+        skip bytes of the packed type, not mapping them to any register
+        
+        no slave is writing the padded bytes, so they are answered with the content sent by the master: zero for a read, the given value for a write or exchange
+    */
     pub fn padding(mut self, size: u16) -> Self {
         self.end += u32::from(size);
+        self.padded += u32::from(size);
         self
     }
     pub fn register<R: FromBytes>(mut self, slave: Host, register: SlaveRegister<R>) -> Self {
@@ -82,5 +375,10 @@ impl<T: FromBytes> BufferMapping<'_, T> {
         assert_eq!(self.end, self.start + T::Bytes::SIZE as u32, "mapping set has different size than packed type");
         VirtualRegister::new(self.start)
     }
+    /// same as [Self::build] but also asserts that every byte of the packed type is mapped to a register, so no padding is left in the buffer
+    pub fn build_dense(self) -> VirtualRegister<T> {
+        assert_eq!(self.padded, 0, "mapping set has padding");
+        self.build()
+    }
 }
 
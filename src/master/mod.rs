@@ -8,11 +8,18 @@
     
     - methods at [Master] level are addressing the virtual memory
     - For communicating with specific slaves and address their memory, use the [Slave] struct which is a lighweight reference to [Master]
+    - [VirtualRegion] is the same kind of reference for a region of the virtual memory
     
     memory access style
     
     - for one-shot access to the different memories, use the methods directly accessible in [Master] and [Slave]
     - for more customized or repeated access sequences, use the [Stream] and [StreamBytes] provided by [Master] and [Slave]
+    - for programs not using async, [BlockingMaster] wraps a [Master] and its runtime
+    
+    executors
+    
+    - with feature `tokio` (enabled by `master`), [Master::new] opens serial ports in a tokio runtime
+    - with feature `master-futures` alone, the master runs on any executor, on serial links given to [Master::with_streams] as `futures-io` streams
 */
 
 /// implementation of the bus exchanges, this is the tricky part of the code
@@ -21,11 +28,27 @@ mod networking;
 mod accessing;
 /// helpers to map slave registers to virtual memory
 mod mapping;
+/// helpers to inspect the bus topology and health
+mod diagnostic;
+/// serial ports opened with tokio
+#[cfg(feature = "tokio")]
+mod serial;
+/// synchronous wrapper for programs not using async
+#[cfg(feature = "tokio")]
+mod blocking;
+/// timers of the master, optionally independent of tokio
+mod timer;
 
 
-pub use networking::Master;
+pub use networking::{Master, Address, ReconnectPolicy, Direction, SerialConfig, DataBits, Parity, StopBits, LatencyStats, Receiver, Transmitter};
 pub use accessing::*;
 pub use mapping::*;
+pub use diagnostic::*;
+#[cfg(feature = "tokio")]
+pub use serial::TokioStream;
+#[cfg(feature = "tokio")]
+pub use blocking::*;
+pub use uartcat_derive::Mapped;
 
 
 use crate::{
@@ -45,6 +68,21 @@ pub enum Error {
     Master(&'static str),
     #[error("no data arrived in expected time")]
     Timeout,
+    #[error("the answer arrived but no slave executed the command")]
+    NoSlave,
+    #[error("bus disconnected before the answer arrived")]
+    Disconnected,
+    #[error("the command was cancelled before its answer arrived")]
+    Cancelled,
+    #[error("no frame received on the bus for longer than the stall timeout")]
+    BusStall,
+    #[error("incompatible protocol version {0} reported by a slave")]
+    IncompatibleVersion(u8),
+    #[error("value {0} read is not a variant of the enum register")]
+    InvalidEnum(u8),
+    /// slaves that did not apply a write, with the error they reported, see [Mapping::write_checked]
+    #[error("write dropped by slaves {0:?}")]
+    PartialWrite(std::vec::Vec<(Host, CommandError)>),
 }
 impl From<std::io::Error> for Error {
     fn from(error: std::io::Error) -> Self {
@@ -53,7 +91,7 @@ impl From<std::io::Error> for Error {
 }
 
 
-fn usize_to_message(size: usize) -> Result<u16, Error> {
-    if size < MAX_COMMAND  {Ok(size as u16)}
+fn usize_to_message(size: usize, max: usize) -> Result<u16, Error> {
+    if size <= max.min(MAX_COMMAND)  {Ok(size as u16)}
     else {Err(Error::Master("data is longer than maximum allowed message"))}
 }
@@ -1,24 +1,28 @@
-use packbytes::{FromBytes, ToBytes, ByteArray};
-use tokio::io::AsyncReadExt;
-// use tokio_serial::{SerialStream, SerialPort, DataBits, Parity, StopBits};
-use serial2_tokio::{SerialPort, CharSize, StopBits, Parity};
+use packbytes::ToBytes;
+use log::*;
+use futures_io::{AsyncRead, AsyncWrite};
 use std::{
-    path::Path,
+    io::ErrorKind,
     task::{Poll, Waker},
     future::poll_fn,
+    pin::Pin,
     collections::HashMap,
-    mem::transmute,
     vec::Vec,
-    ops::{Deref, DerefMut},
-    time::Duration,
+    boxed::Box,
+    time::{Duration, Instant},
+    fmt,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering::Relaxed},
+        },
     };
 
 use crate::{
     mutex::*,
-    command::{Command, MAX_COMMAND, checksum, self},
-    registers::{CommandError, SlaveSize, VirtualSize},
+    command::{Command, MAX_COMMAND, HEADER, TRAILER, CRC_SEED, DecodeOutcome, checksum, decode_frame, frame_crc, self},
+    registers::{SlaveSize, VirtualSize},
     };
-use super::{Error, usize_to_message};
+use super::{Error, usize_to_message, timer};
 
 
 
@@ -29,12 +33,55 @@ use super::{Error, usize_to_message};
     all methods here are addressing the virtual memory which is shared by all slaves
 */
 pub struct Master {
+    /// settings the serial port was opened with
+    config: SerialConfig,
     /// uart RX/TX stream
-    receive: BusyMutex<SerialPort>,
-    transmit: BusyMutex<SerialPort>,
+    receive: BusyMutex<Receiver>,
+    transmit: BusyMutex<Transmitter>,
     /// command answers currently waited for
     pending: BusyMutex<HashMap<Token, Pending>>,
+    /// tokens of topics dropped while `pending` was locked, their entries are removed by the next task locking it
+    dropped: Mutex<Vec<Token>>,
+    /// buffers of the topics released, reused by the next ones so one-shot commands do not allocate each time
+    spare: Mutex<Vec<Vec<u8>>>,
+    /// time waited for answers, for a command reaching no further than the first slave
     timeout: Duration,
+    /// time added to `timeout` for each slave a command goes through, see [Self::set_timeout_model]
+    per_hop: Duration,
+    /// number of slaves that executed the last virtual command answered, taken as the chain length
+    chain: AtomicU8,
+    /// bus silence after which timeouts are reported as [Error::BusStall], see [Self::set_stall_timeout]
+    stall: Option<Duration>,
+    /// maximum data size of commands sent
+    max_command: usize,
+    /// how to reopen the serial port if it disappears, if enabled
+    reconnect: Option<Reconnect>,
+    /// identifier of this master when sharing the bus with others, see [Self::with_id]
+    id: Option<u8>,
+    /// minimum bus idle time before transmitting when sharing the bus
+    guard: Duration,
+    /// reference for the bus activity timestamps
+    epoch: Instant,
+    /// time of the last frame received, in microseconds since `epoch`, 0 if nothing was received yet
+    activity: AtomicU64,
+    /// time until which transmission is delayed after a collision, in microseconds since `epoch`
+    backoff: AtomicU64,
+    /// minimum delay between consecutive frames sent, see [Self::set_interframe_gap]
+    gap: Duration,
+    /// time of the last transmission completion, in microseconds since `epoch`
+    transmitted: AtomicU64,
+    /// callback inspecting all frames, see [Self::set_tracer]
+    tracer: Option<Box<Tracer>>,
+    /// callback enabling the RS-485 driver while transmitting, see [Self::set_direction_control]
+    direction: Option<Box<DirectionControl>>,
+    /// delay between switching the driver and transmitting
+    turnaround: Duration,
+    /// round-trip times of commands, if enabled
+    metrics: Option<Metrics>,
+    /// requests [Self::run] to stop, see [Self::shutdown]
+    stop: AtomicBool,
+    /// for waking up [Self::run] when a shutdown is requested
+    stopping: Mutex<Option<Waker>>,
     
     // TODO reimplement pending with an atomic queue
 }
@@ -42,63 +89,638 @@ pub struct Master {
 struct Pending {
     /// initial command header, executed is set to MAX until actual answer received
     command: Command,
-    /// buffer for data reception
-    buffer: &'static mut [u8],
+    /// data sent by default, and overwritten by the received data
+    buffer: Vec<u8>,
     /// for waking up the async task waiting for the answer
     waker: Option<Waker>,
     /// result set after last reception
     result: Option<Result<u8, Error>>,
+    /// header of the last answer received intact, whatever it reports
+    answer: Option<Command>,
+    /// data of the last answer received intact
+    raw: Vec<u8>,
+    /// time of the last transmission, in microseconds since `epoch`, only set when metrics are enabled
+    sent: u64,
+    /// number of transmissions of this command
+    transmissions: usize,
+    /// number of transmissions answered or given up
+    answers: usize,
+    /// sequence number of the last frame sent, see [SEQUENCE_BITS]
+    sequence: Token,
+    /// for waking up the async task waiting for frames in flight to decrease
+    flow: Option<Waker>,
+}
+impl Pending {
+    /// number of frames sent and not answered yet, plus the answer not consumed yet
+    fn outstanding(&self) -> usize {
+        self.transmissions.saturating_sub(self.answers) + usize::from(self.result.is_some())
+    }
+    fn wake_flow(&mut self) {
+        if let Some(waker) = self.flow.take() {
+            waker.wake();
+        }
+    }
+    /// give up the frames in flight and fail the answer awaited
+    fn cancel(&mut self) {
+        self.result = Some(Err(Error::Cancelled));
+        self.answer = None;
+        self.answers = self.transmissions;
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+        self.wake_flow();
+    }
 }
 /// internal token type for pending commands
 type Token = u16;
+/// maximum number of buffers kept for reuse by a master, see [Master::buffer]
+const SPARE_BUFFERS: usize = 16;
+/**
+    number of low bits of the tokens sent holding a sequence number, incremented on each frame sent for the same topic
+    
+    slaves answer with the token unchanged, so a late answer of a frame sent before the last one is recognized and discarded. The header has no room for a dedicated field, so topics reserve tokens by groups of `1 << SEQUENCE_BITS`
+*/
+const SEQUENCE_BITS: u32 = 2;
+const SEQUENCE_MASK: Token = (1 << SEQUENCE_BITS) - 1;
+/// receiving half of the serial link, see [Master::with_streams]
+pub type Receiver = Box<dyn AsyncRead + Send + Unpin>;
+/// transmitting half of the serial link, see [Master::with_streams]
+pub type Transmitter = Box<dyn AsyncWrite + Send + Unpin>;
+/// callback type for [Master::set_reconnect]
+type Reopen = dyn Fn() -> Result<(Receiver, Transmitter), std::io::Error> + Send + Sync;
+/// callback type for [Master::set_tracer]
+type Tracer = dyn Fn(Direction, &Command, &[u8]) + Send + Sync;
+type DirectionControl = dyn Fn(bool) + Send + Sync;
+
+/// enables the RS-485 driver while alive, so it is released whatever way the transmission ends, even cancelled
+struct Driver<'m>(&'m DirectionControl);
+impl<'m> Driver<'m> {
+    fn enable(control: &'m DirectionControl) -> Self {
+        control(true);
+        Self(control)
+    }
+}
+impl Drop for Driver<'_> {
+    fn drop(&mut self) {
+        (self.0)(false);
+    }
+}
+
+/// direction of a frame on the bus, relative to the master
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// frame sent by the master
+    Send,
+    /// frame received by the master
+    Receive,
+}
+
+/**
+    settings of the serial port, see [Master::with_config]
+    
+    uartcat slaves use even parity and one stop bit, or no parity with the `no-parity` feature, see [command::PARITY]. Other settings are meant for interoperating with third-party devices.
+    
+    Without parity, corrupted bytes are only detected by checksums, so [Parity::None] is rejected unless the frames have a [CRC trailer](command::TRAILER)
+*/
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SerialConfig {
+    /// baud rate
+    pub rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+impl SerialConfig {
+    /// standard uartcat settings at the given baud rate
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate,
+            data_bits: DataBits::Eight,
+            parity: if command::PARITY {Parity::Even} else {Parity::None},
+            stop_bits: StopBits::One,
+        }
+    }
+    /// transmission time of one character with these settings, including its start, parity and stop bits. It is zero for a rate of 0, used by ports with no physical line
+    pub fn character_time(&self) -> Duration {
+        if self.rate == 0
+            {return Duration::ZERO}
+        let data = match self.data_bits {
+            DataBits::Seven => 7,
+            DataBits::Eight => 8,
+            DataBits::Nine => 9,
+        };
+        let parity = if self.parity == Parity::None {0} else {1};
+        // counted in half bits for the 1.5 stop bits
+        let stop = match self.stop_bits {
+            StopBits::One => 2,
+            StopBits::OnePointFive => 3,
+            StopBits::Two => 4,
+        };
+        let half_bits = 2 * (1 + data + parity) + stop;
+        Duration::from_nanos(half_bits * 500_000_000 / u64::from(self.rate))
+    }
+}
+/**
+    number of data bits of each character on the serial port
+    
+    uartcat frames are made of 8 bit bytes, so only [DataBits::Eight] is supported for now and opening a port with an other size fails rather than corrupting frames. Other sizes are listed for legacy devices, until frames can be packed on them
+*/
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DataBits {
+    Seven,
+    Eight,
+    Nine,
+}
+/// parity bit of each byte on the serial port
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+/// number of stop bits of each byte on the serial port
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    /// not supported by most serial ports, opening the port fails if unsupported
+    OnePointFive,
+    Two,
+}
+
+/// statistics of commands round-trip time, see [Master::set_metrics]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    /// number of answers received
+    pub count: u64,
+    /// round-trip time of the last answer received
+    pub last: Duration,
+    pub min: Duration,
+    pub max: Duration,
+    pub mean: Duration,
+}
+/// internal accumulator of round-trip times, in microseconds
+#[derive(Default)]
+struct Metrics {
+    count: AtomicU64,
+    total: AtomicU64,
+    last: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+}
+impl Metrics {
+    fn new() -> Self {
+        Self {min: AtomicU64::new(u64::MAX), .. Default::default()}
+    }
+    fn record(&self, latency: u64) {
+        self.count.fetch_add(1, Relaxed);
+        self.total.fetch_add(latency, Relaxed);
+        self.last.store(latency, Relaxed);
+        self.min.fetch_min(latency, Relaxed);
+        self.max.fetch_max(latency, Relaxed);
+    }
+    fn stats(&self) -> LatencyStats {
+        let count = self.count.load(Relaxed);
+        if count == 0
+            {return LatencyStats::default()}
+        LatencyStats {
+            count,
+            last: Duration::from_micros(self.last.load(Relaxed)),
+            min: Duration::from_micros(self.min.load(Relaxed)),
+            max: Duration::from_micros(self.max.load(Relaxed)),
+            mean: Duration::from_micros(self.total.load(Relaxed) / count),
+        }
+    }
+}
+
+/// policy for reopening a disappeared serial port, see [Master::set_reconnect]
+#[derive(Copy, Clone, Debug)]
+pub struct ReconnectPolicy {
+    /// maximum number of consecutive reopen attempts, `None` for unlimited
+    pub attempts: Option<u32>,
+    /// delay before the first attempt, doubled after each failed attempt
+    pub initial: Duration,
+    /// maximum delay between attempts
+    pub max: Duration,
+}
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: None,
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(5),
+        }
+    }
+}
+/// internal struct holding what is needed to reopen the serial port
+struct Reconnect {
+    reopen: Box<Reopen>,
+    policy: ReconnectPolicy,
+}
 
 
 // TODO implement per-command timeout
 impl Master {
-    /// initialize a master on the given serial port file and with the given baud rate
-    pub fn new(path: impl AsRef<Path>, rate: u32) -> Result<Self, std::io::Error> {
-        let bus1 = SerialPort::open(path, |mut settings: serial2_tokio::Settings| {
-                settings.set_raw();
-                settings.set_baud_rate(rate)?;
-                settings.set_char_size(CharSize::Bits8);
-                settings.set_stop_bits(StopBits::One);
-                settings.set_parity(Parity::Even);
-                Ok(settings)
-                })?;
-        let bus2 = bus1.try_clone()?;
-        Ok(Self {
-            receive: BusyMutex::from(bus1),
-            transmit: BusyMutex::from(bus2),
+    /**
+        initialize a master on an already opened serial link, given as its receiving and transmitting halves
+        
+        they can be any `futures-io` streams, so the master runs on any executor. `config` is only used to estimate transmission times, the link must already be set up with it
+    */
+    pub fn with_streams(receive: Receiver, transmit: Transmitter, config: SerialConfig) -> Self {
+        Self {
+            config,
+            receive: BusyMutex::from(receive),
+            transmit: BusyMutex::from(transmit),
             pending: BusyMutex::from(HashMap::new()),
+            dropped: Mutex::new(Vec::new()),
+            spare: Mutex::new(Vec::new()),
             timeout: Duration::from_millis(100),
-        })
+            per_hop: Duration::ZERO,
+            chain: AtomicU8::new(0),
+            stall: None,
+            max_command: MAX_COMMAND,
+            reconnect: None,
+            id: None,
+            guard: Duration::from_millis(1),
+            epoch: Instant::now(),
+            activity: AtomicU64::new(0),
+            backoff: AtomicU64::new(0),
+            gap: Duration::ZERO,
+            transmitted: AtomicU64::new(0),
+            tracer: None,
+            direction: None,
+            turnaround: Duration::ZERO,
+            metrics: None,
+            stop: AtomicBool::new(false),
+            stopping: Mutex::new(None),
+        }
+    }
+    /**
+        reopen the serial link with the given callback if it disappears
+        
+        when the link disappears (`NotFound` or `BrokenPipe` IO errors, or reaching its end), [Self::run] fails all commands waiting for an answer with [Error::Disconnected], then tries to reopen the link following the given policy. It only returns the IO error if all attempts failed.
+    */
+    pub fn set_reconnect(&mut self, policy: ReconnectPolicy, reopen: impl Fn() -> Result<(Receiver, Transmitter), std::io::Error> + Send + Sync + 'static) {
+        self.reconnect = Some(Reconnect {
+            reopen: Box::new(reopen),
+            policy,
+        });
+    }
+    /**
+        set the maximum data size of commands sent, bigger commands are rejected with [Error::Master]
+        
+        Commands of exactly this size are sent. It must not exceed the frame size of any slave on the bus, and cannot exceed [MAX_COMMAND]
+    */
+    pub fn set_max_command(&mut self, size: usize) -> Result<(), Error> {
+        if size > MAX_COMMAND
+            {return Err(Error::Master("maximum command size exceeds protocol limit"))}
+        self.max_command = size;
+        Ok(())
+    }
+    /// maximum data size of commands sent
+    pub fn max_command(&self) -> usize {self.max_command}
+    /**
+        wait answers for `base + per_hop * slaves`, where `slaves` is the number of slaves before the addressed one, so that far slaves of a long chain get more time than near ones
+        
+        topological commands use their topological index. Other commands can reach any slave, so they use the length of the chain, as counted by the last virtual command answered (like [Self::count_slaves]). The default is 100ms with nothing per slave
+    */
+    pub fn set_timeout_model(&mut self, base: Duration, per_hop: Duration) {
+        self.timeout = base;
+        self.per_hop = per_hop;
+    }
+    /// time waited for the answer to the given command, see [Self::set_timeout_model]
+    pub fn timeout_for(&self, command: &Command) -> Duration {
+        let hops = if command.access.topological() && !command.access.fixed()
+            {u32::from(command.address.slave())}
+        else
+            {u32::from(self.chain.load(Relaxed))};
+        self.timeout.saturating_add(self.per_hop.saturating_mul(hops))
+    }
+    /// settings the serial port was opened with
+    pub fn config(&self) -> SerialConfig {self.config}
+    
+    /**
+        enable cooperative sharing of the bus with other masters, each having a different `id`
+        
+        The masters are only avoiding collisions, there is no hardware arbitration:
+        - each master uses its own range of command tokens, so a master can recognize frames it did not send. This leaves 64 commands in flight per master
+        - a master only transmits when the bus has been idle for the guard interval, see [Self::set_guard]
+        - when a frame from an other master is received, transmission is delayed by a random multiple of the guard interval
+    */
+    pub fn with_id(mut self, id: u8) -> Self {
+        self.id = Some(id);
+        self
+    }
+    /**
+        set a minimum delay between the end of a frame transmission and the start of the next one, zero (default) disables it
+        
+        This helps slaves delimiting frames when the serial adapter merges back-to-back frames. The delay is measured from the moment the frame was given to the serial port, so it should account for the transmission time in the adapter
+    */
+    pub fn set_interframe_gap(&mut self, gap: Duration) {
+        self.gap = gap;
+    }
+    /**
+        set a callback inspecting every frame sent and received by this master, for protocol debugging
+        
+        It is called with the frame header and data right before sending, and right after receiving a frame (even not matching any command of this master). It is run in the bus tasks so it must be quick to not alter the bus timing
+    */
+    pub fn set_tracer(&mut self, tracer: impl Fn(Direction, &Command, &[u8]) + Send + Sync + 'static) {
+        self.tracer = Some(Box::new(tracer));
+    }
+    /**
+        set a callback switching the driver of a half-duplex RS-485 transceiver, typically toggling its DE/RE GPIO
+        
+        It is called with `true` before sending each frame and with `false` once it has been transmitted, or as soon as the transmission fails or is cancelled, with `turnaround` waited after enabling and after the transmission, for the transceiver to switch. Serial ports cannot be drained asynchronously, so the end of transmission is estimated from the frame size and the serial settings (see [SerialConfig::character_time]), and the driver is disabled a bit late if the port buffers were not empty
+    */
+    pub fn set_direction_control(&mut self, control: impl Fn(bool) + Send + Sync + 'static, turnaround: Duration) {
+        self.direction = Some(Box::new(control));
+        self.turnaround = turnaround;
+    }
+    /**
+        enable or disable recording the round-trip time of commands, see [Self::latency_stats]. Enabling it resets the statistics
+        
+        The round-trip time is measured from the end of the command transmission to the reception of its answer header and data, so it includes the answer transmission time. When disabled (default), no time is recorded
+    */
+    pub fn set_metrics(&mut self, enabled: bool) {
+        self.metrics = enabled.then(Metrics::new);
+    }
+    /// statistics of commands round-trip time since metrics were enabled, `None` if disabled
+    pub fn latency_stats(&self) -> Option<LatencyStats> {
+        self.metrics.as_ref().map(Metrics::stats)
+    }
+    /**
+        number of commands currently holding a token, waiting for their answer or kept by a [Stream](super::Stream)
+        
+        it can be polled concurrently with commands, and never exceeds the number of tokens available. A count that keeps growing while commands time out suggests [Self::run] has stalled
+    */
+    pub async fn outstanding(&self) -> usize {
+        self.lock_pending().await.len()
+    }
+    /// true if no command is holding a token, see [Self::outstanding]
+    pub async fn is_idle(&self) -> bool {
+        self.outstanding().await == 0
+    }
+    /**
+        time elapsed since the last frame was received by [Self::run], `None` if no frame was received yet
+        
+        frames of other masters on a shared bus are counted too, so this tells how long the bus has been quiet
+    */
+    pub fn idle_since(&self) -> Option<Duration> {
+        match self.activity.load(Relaxed) {
+            0 => None,
+            activity => Some(Duration::from_micros(self.now().saturating_sub(activity))),
+        }
+    }
+    /**
+        report answers timing out as [Error::BusStall] rather than [Error::Timeout] when no frame at all was received for the given duration, `None` (default) disables it
+        
+        a total silence of the bus is usually a jammed chain (like a slave stuck transmitting) rather than a lost answer, so the application can react by resetting the slaves
+    */
+    pub fn set_stall_timeout(&mut self, stall: Option<Duration>) {
+        self.stall = stall;
+    }
+    /// error for an answer not received in time, depending on the bus silence
+    fn timed_out(&self) -> Error {
+        let silence = Duration::from_micros(self.now().saturating_sub(self.activity.load(Relaxed)));
+        match self.stall {
+            Some(stall) if silence >= stall => Error::BusStall,
+            _ => Error::Timeout,
+        }
+    }
+    /**
+        cancel the command holding the given token, see [Stream::token](super::Stream::token)
+        
+        its frames in flight are given up and its answer fails with [Error::Cancelled], the token itself stays reserved until the command is dropped. Nothing happens if no command holds this token
+    */
+    pub async fn cancel(&self, token: u16) {
+        if let Some(buffer) = self.lock_pending().await.get_mut(&token) {
+            buffer.cancel();
+        }
+    }
+    /// lock the pending commands, removing the ones dropped meanwhile
+    async fn lock_pending(&self) -> BusyMutexGuard<'_, HashMap<Token, Pending>> {
+        let mut pending = self.pending.lock().await;
+        for token in self.dropped.lock().unwrap().drain(..) {
+            self.recycle(pending.remove(&token));
+        }
+        pending
+    }
+    /// keep the buffer of a removed command for a next one, unless enough are already kept
+    fn recycle(&self, removed: Option<Pending>) {
+        let Some(removed) = removed
+            else {return};
+        let mut spare = self.spare.lock().unwrap();
+        for buffer in [removed.buffer, removed.raw] {
+            if spare.len() < SPARE_BUFFERS {
+                spare.push(buffer);
+            }
+        }
+    }
+    /// zeroed buffer of the given size for a new command, reusing a spare one if any
+    pub(crate) fn buffer(&self, size: usize) -> Vec<u8> {
+        let mut buffer = self.spare.lock().unwrap().pop().unwrap_or_default();
+        buffer.clear();
+        buffer.resize(size, 0);
+        buffer
+    }
+    /// number of buffers currently kept for reuse by the next commands
+    #[cfg(feature = "test-util")]
+    pub fn spare_buffers(&self) -> usize {
+        self.spare.lock().unwrap().len()
+    }
+    /// identifier of this master on a shared bus, if enabled
+    pub fn id(&self) -> Option<u8> {self.id}
+    /// set the minimum bus idle time before transmitting on a shared bus, see [Self::with_id]
+    pub fn set_guard(&mut self, guard: Duration) {
+        self.guard = guard;
+    }
+    
+    /// number of topic tokens this master can use
+    fn tokens(&self) -> usize {
+        match self.id {
+            Some(_) => (usize::from(u8::MAX) + 1) >> SEQUENCE_BITS,
+            None => (usize::from(Token::MAX) + 1) >> SEQUENCE_BITS,
+        }
+    }
+    /// topic token in the range of this master, its sequence bits are left to zero
+    fn token(&self, index: u16) -> Token {
+        let index = index << SEQUENCE_BITS;
+        match self.id {
+            Some(id) => u16::from_be_bytes([id, index as u8]),
+            None => index,
+        }
+    }
+    /// true if the given token was not sent by this master
+    fn foreign(&self, token: Token) -> bool {
+        self.id.is_some_and(|id| token.to_be_bytes()[0] != id)
+    }
+    fn now(&self) -> u64 {
+        u64::try_from(self.epoch.elapsed().as_micros()).unwrap_or(u64::MAX)
+    }
+    /// wait until this master is allowed to transmit on a shared bus
+    async fn arbitrate(&self) {
+        if self.id.is_none()
+            {return}
+        let guard = u64::try_from(self.guard.as_micros()).unwrap_or(u64::MAX);
+        loop {
+            let allowed = self.activity.load(Relaxed).saturating_add(guard)
+                .max(self.backoff.load(Relaxed));
+            let now = self.now();
+            if now >= allowed
+                {break}
+            timer::sleep(Duration::from_micros(allowed - now)).await;
+        }
     }
     
     /**
         coroutine responsible of receving all responses from the bus
         
         it **must** be running in order to receive answers
+        
+        When the serial port reaches its end (EOF) or [Self::shutdown] is called, it returns `Ok`. When it stops, all commands waiting for an answer fail with [Error::Disconnected] instead of waiting for their timeout.
     */
     pub async fn run(&self) -> Result<(), std::io::Error> {
         let mut bus = self.receive.try_lock().expect("run function called twice");
-        let mut receive = [0u8; MAX_COMMAND];
         loop {
-            const HEADER: usize = <Command as FromBytes>::Bytes::SIZE;
+            let result = self.receive_all(&mut bus).await;
+            self.disconnected().await;
+            let Err(error) = result
+                else {return Ok(())};
+            match &self.reconnect {
+                Some(reconnect) if matches!(error.kind(), ErrorKind::NotFound | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof) => {
+                    self.reopen(&mut bus, reconnect, error).await?;
+                },
+                _ if error.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                _ => return Err(error),
+            }
+        }
+    }
+    /// fail all commands waiting for an answer
+    async fn disconnected(&self) {
+        let mut pending = self.lock_pending().await;
+        for buffer in pending.values_mut() {
+            if buffer.result.is_none() {
+                buffer.result = Some(Err(Error::Disconnected));
+                buffer.answer = None;
+                if let Some(waker) = buffer.waker.take() {
+                    waker.wake();
+                }
+            }
+            buffer.answers = buffer.transmissions;
+            buffer.wake_flow();
+        }
+    }
+    /// try reopening the serial port until it succeeds or the policy gives up, in which case the given error is returned
+    async fn reopen(&self, bus: &mut Receiver, reconnect: &Reconnect, error: std::io::Error) -> Result<(), std::io::Error> {
+        let policy = reconnect.policy;
+        let mut delay = policy.initial;
+        let mut attempt = 0;
+        loop {
+            if policy.attempts.is_some_and(|attempts| attempt >= attempts)
+                {return Err(error)}
+            attempt += 1;
+            timer::sleep(delay).await;
+            delay = (delay * 2).min(policy.max);
+            if let Ok((receive, transmit)) = (reconnect.reopen)() {
+                *bus = receive;
+                *self.transmit.lock().await = transmit;
+                return Ok(())
+            }
+        }
+    }
+    /**
+        make [Self::run] return once the frame being received is done, failing the commands waiting for an answer with [Error::Disconnected]
+        
+        the master can then be dropped to close the serial port, or run again. If it is not running, the next run returns immediately
+    */
+    pub fn shutdown(&self) {
+        self.stop.store(true, Relaxed);
+        if let Some(waker) = self.stopping.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+    /// receive answers until an IO error occurs or a shutdown is requested
+    async fn receive_all(&self, bus: &mut Receiver) -> Result<(), std::io::Error> {
+        let mut receive = [0u8; HEADER+1 + MAX_COMMAND + TRAILER];
+        // number of bytes already received at the start of the buffer, and number of them used by the last frame
+        let (mut filled, mut consumed) = (0, 0);
+        loop {
+            receive[.. filled].rotate_left(consumed);
+            filled -= consumed;
+            // only stop between frames
+            if filled == 0 {
+                let received = poll_fn(|context| {
+                    // the waker is registered before checking, so a shutdown requested meanwhile is not missed
+                    self.stopping.lock().unwrap().replace(context.waker().clone());
+                    if self.stop.swap(false, Relaxed)
+                        {return Poll::Ready(None)}
+                    Pin::new(&mut *bus).poll_read(context, &mut receive[.. HEADER+1]).map(Some)
+                }).await;
+                match received.transpose()? {
+                    None => return Ok(()),
+                    Some(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                    Some(size) => filled = size,
+                }
+            }
             // receive an amount that can be a header and its checksum
-            bus.read_exact(&mut receive[.. HEADER+1]).await?;
-            // loop until checksum is good to catch up new command
-            while checksum(&receive[.. HEADER]) != receive[HEADER] {
-                receive[.. HEADER+1].rotate_left(1);
-                bus.read_exact(&mut receive[HEADER .. HEADER+1]).await?;
+            fill(bus, &mut receive, &mut filled, HEADER+1).await?;
+            // loop until a header is decoded to catch up new command
+            let header = loop {
+                match decode_frame(&receive[.. HEADER+1]) {
+                    DecodeOutcome::Frame(header, _) | DecodeOutcome::Partial(header, _) => break header,
+                    DecodeOutcome::Skip(skip) => {
+                        receive[.. filled].rotate_left(skip);
+                        filled -= skip;
+                        fill(bus, &mut receive, &mut filled, HEADER+1).await?;
+                    },
+                    DecodeOutcome::Incomplete(_) => unreachable!("a header is always received"),
+                }
+            };
+            
+            let size = usize::from(header.size);
+            consumed = HEADER+1 + size + TRAILER;
+            fill(bus, &mut receive, &mut filled, consumed).await?;
+            let crc = frame_crc(CRC_SEED, &receive[.. HEADER+1 + size]).to_be_bytes();
+            let intact = crc[.. TRAILER] == receive[HEADER+1 + size ..][.. TRAILER];
+            if !intact && !self.lock_pending().await.contains_key(&(header.token & !SEQUENCE_MASK)) {
+                // most likely a header found in noise, so the bytes it claimed may hold real frames
+                consumed = 1;
+                continue;
+            }
+            let data = &receive[HEADER+1 ..][.. size];
+            if let Some(tracer) = &self.tracer {
+                tracer(Direction::Receive, &header, data);
             }
-            let header = Command::from_be_bytes(receive[.. HEADER].try_into().unwrap());
             
-            let data = &mut receive[.. usize::from(header.size)];
-            bus.read_exact(data).await?;
+            let now = self.now();
+            self.activity.store(now.max(1), Relaxed);
+            if !header.access.fixed() && !header.access.topological() && intact {
+                self.chain.store(header.executed, Relaxed);
+            }
+            if self.foreign(header.token) {
+                // an other master is transmitting, leave it the bus for some time
+                let guard = u64::try_from(self.guard.as_micros()).unwrap_or(u64::MAX);
+                let delay = guard.saturating_mul(rand::random_range(1 ..= 4));
+                self.backoff.store(now.saturating_add(delay), Relaxed);
+                continue;
+            }
             
-            let mut pending = self.pending.lock().await;
-            if let Some(buffer) = pending.get_mut(&header.token) {
-                if !(  buffer.command.token == header.token
+            let mut pending = self.lock_pending().await;
+            if let Some(buffer) = pending.get_mut(&(header.token & !SEQUENCE_MASK)) {
+                if intact && header.token != buffer.command.token {
+                    // answer of a frame sent before the last one, its data is outdated
+                    debug!("discarded stale answer for token {}", header.token);
+                    buffer.answers = (buffer.answers + 1).min(buffer.transmissions);
+                    buffer.wake_flow();
+                    continue;
+                }
+                // kept as received for raw receptions
+                buffer.answer = intact.then_some(header);
+                buffer.raw.clear();
+                buffer.raw.extend_from_slice(data);
+                if !intact {
+                    buffer.result = Some(Err(Error::Master("frame crc mismatch")));
+                }
+                else if !(  buffer.command.token == header.token
                     && buffer.command.access.fixed() == header.access.fixed()
                     && buffer.command.access.topological() == header.access.topological()
                     && buffer.command.access.read() == header.access.read()
@@ -110,7 +732,7 @@ impl Master {
                     buffer.result = Some(Err(Error::Master("reponse header mismatch")));
                 }
                 else if header.access.error() {
-                    buffer.result = Some(Err(Error::Slave(CommandError::Unknown)));
+                    buffer.result = Some(Err(Error::Slave(header.access.command_error())));
                 }
                 else if header.checksum != checksum(data) {
                     buffer.result = Some(Err(Error::Master("data checksum mismatch")));
@@ -119,22 +741,93 @@ impl Master {
                     buffer.buffer.copy_from_slice(data);
                     buffer.result = Some(Ok(header.executed));
                 }
+                if let Some(metrics) = &self.metrics {
+                    metrics.record(now.saturating_sub(buffer.sent));
+                }
+                buffer.answers = (buffer.answers + 1).min(buffer.transmissions);
                 
                 if let Some(waker) = buffer.waker.take() {
                     waker.wake();
                 }
+                buffer.wake_flow();
             }
         }
     }
 }
 
+impl fmt::Debug for Master {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Master")
+            .field("config", &self.config)
+            .field("timeout", &self.timeout)
+            .field("per_hop", &self.per_hop)
+            .field("stall", &self.stall)
+            .field("max_command", &self.max_command)
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// receive bytes after the `filled` ones already in the buffer, until it holds `size` bytes
+async fn fill(bus: &mut Receiver, buffer: &mut [u8], filled: &mut usize, size: usize) -> Result<(), std::io::Error> {
+    while *filled < size {
+        let received = poll_fn(|context| Pin::new(&mut *bus).poll_read(context, &mut buffer[*filled .. size])).await?;
+        if received == 0
+            {return Err(ErrorKind::UnexpectedEof.into())}
+        *filled += received;
+    }
+    Ok(())
+}
+/// send all the given bytes
+async fn write_all(bus: &mut Transmitter, mut data: &[u8]) -> Result<(), std::io::Error> {
+    while !data.is_empty() {
+        let sent = poll_fn(|context| Pin::new(&mut *bus).poll_write(context, data)).await?;
+        if sent == 0
+            {return Err(ErrorKind::WriteZero.into())}
+        data = &data[sent ..];
+    }
+    Ok(())
+}
+
+impl Address {
+    /// command header addressing this address
+    pub fn command(self) -> Command {
+        let mut command = Command::default();
+        match self {
+            Address::Topological(slave, local) => {
+                command.access.set_topological(true);
+                command.address = command::Address::new(slave, local);
+            },
+            Address::Fixed(slave, local) => {
+                command.access.set_fixed(true);
+                command.address = command::Address::new(slave, local);
+            },
+            Address::Virtual(global) => {
+                command.address = command::Address::from(global);
+            },
+            #[cfg(feature = "extended-address")]
+            Address::Extended(local) => {
+                command.access.set_fixed(true);
+                command.access.set_topological(true);
+                command.address = command::Address::from(local);
+            },
+        }
+        command
+    }
+}
 
-/// object allowing to send commands and wait and receive responses using master pending buffers
+/**
+    object allowing to send commands and wait and receive responses using master pending buffers
+    
+    dropping it never blocks: if the master is busy with the pending commands, the token is released by the next task using them
+*/
 pub struct Topic<'m> {
     master: &'m Master,
     token: Token,
-    #[allow(unused)]  // this field needs to be owned here, despite its ref is being used by Master
-    buffer: PinnedBuffer<'m>,
+    /// time waited for answers, given by the master's timeout model
+    timeout: Duration,
+    /// the token was already released by [Self::cancel]
+    released: bool,
 }
 /// data address on this bus
 #[derive(Copy, Clone)]
@@ -145,129 +838,231 @@ pub enum Address {
     Fixed(u16, SlaveSize),
     /// mapped address in the virtual memory
     Virtual(VirtualSize),
+    /// register address in the slaves selected for extended addressing, see [command::Access::extended]
+    #[cfg(feature = "extended-address")]
+    Extended(u32),
 }
 impl<'m> Topic<'m> {
-    pub async fn new(master: &'m Master, address: Address, mut buffer: PinnedBuffer<'m>) -> Result<Self, Error> {
+    /// reserve a token for commands at the given address, the buffer holds the data sent by default and its size is the command size
+    pub async fn new(master: &'m Master, address: Address, buffer: Vec<u8>) -> Result<Self, Error> {
+        Self::with_command(master, address.command(), buffer).await
+    }
+    /// same as [Self::new] but with the given command header, only its token and size are replaced
+    pub async fn with_command(master: &'m Master, mut command: Command, buffer: Vec<u8>) -> Result<Self, Error> {
+        // fail fast rather than waiting for the slaves to reject it
+        command.access.validate().map_err(Error::Master)?;
         // reserve space in the master for the answer
-        let mut pending = master.pending.lock().await;
+        let mut pending = master.lock_pending().await;
+        if pending.len() >= master.tokens() {
+            return Err(Error::Master("no more token available for a new command"));
+        }
         // reserve a free token, preferably random to increase the chance of getting one that was not used by previus communication (useful at start) and to decrease the chance of good checksum for bad packet
         let first = rand::random::<u16>();
         let token = loop {
             if let Some(token) = (0 ..= u16::try_from(pending.len()).unwrap())
-                .map(|i|  i.wrapping_add(first))
+                .map(|i|  master.token(i.wrapping_add(first)))
                 .filter(|k| ! pending.contains_key(&k))
                 .next()
                 {break token}
             };
         
         // set that part of the command that is not gonna change
-        let mut command = Command::default();
         command.token = token;
-        command.size = usize_to_message(buffer.len())?;
-
-        match address {
-            Address::Topological(slave, local) => {
-                command.access.set_topological(true);
-                command.address = command::Address::new(slave, local).into();
-            },
-            Address::Fixed(slave, local) => {
-                command.access.set_fixed(true);
-                command.address = command::Address::new(slave, local).into();
-            },
-            Address::Virtual(global) => {
-                command.address = command::Address::from(global);
-            },
-        }
+        command.size = usize_to_message(buffer.len(), master.max_command)?;
         
         pending.insert(token, Pending {
             command: command,
-            // SAFETY: we will remove this reference when self is dropped, self guarantees that this buffer lives until then
-            buffer: unsafe {transmute::<&mut [u8], &mut [u8]>(buffer.deref_mut())},
+            buffer,
             waker: None,
             result: None,
+            answer: None,
+            raw: master.buffer(0),
+            sent: 0,
+            transmissions: 0,
+            answers: 0,
+            sequence: 0,
+            flow: None,
             });
-        Ok(Self{master, token, buffer})
+        let timeout = master.timeout_for(&command);
+        Ok(Self{master, token, timeout, released: false})
     }
     /// send the current content of the buffer
     pub async fn send(&self, read: bool, write: bool, data: Option<&[u8]>) -> Result<(), Error> {
+        self.master.arbitrate().await;
+        // delays are waited before locking the pending commands, so answers are still dispatched meanwhile
+        let mut bus = self.master.transmit.lock().await;
+        if ! self.master.gap.is_zero() {
+            let gap = u64::try_from(self.master.gap.as_micros()).unwrap_or(u64::MAX);
+            let allowed = self.master.transmitted.load(Relaxed).saturating_add(gap);
+            let now = self.master.now();
+            if now < allowed {
+                timer::sleep(Duration::from_micros(allowed - now)).await;
+            }
+        }
+        let driver = self.master.direction.as_deref().map(Driver::enable);
+        if driver.is_some() {
+            timer::sleep(self.master.turnaround).await;
+        }
         let mut pending = self.master.pending.lock().await;
         let buffer = pending.get_mut(&self.token).unwrap();
-        let data = data.unwrap_or(buffer.buffer);
+        let data = data.unwrap_or(&buffer.buffer);
         // update command for new buffer
+        buffer.sequence = (buffer.sequence + 1) & SEQUENCE_MASK;
+        buffer.command.token = self.token | buffer.sequence;
         buffer.command.checksum = checksum(data);
         buffer.command.access.set_read(read);
         buffer.command.access.set_write(write);
-        {
-            let bus = self.master.transmit.lock().await;
-            let header = buffer.command.to_be_bytes();
-            bus.write_all(&header).await?;
-            bus.write_all(&checksum(&header).to_be_bytes()).await?;
-            bus.write_all(data).await?;
+        if let Some(tracer) = &self.master.tracer {
+            tracer(Direction::Send, &buffer.command, data);
+        }
+        let header = buffer.command.to_be_bytes();
+        let header_checksum = checksum(&header).to_be_bytes();
+        write_all(&mut bus, &header).await?;
+        write_all(&mut bus, &header_checksum).await?;
+        write_all(&mut bus, data).await?;
+        if TRAILER != 0 {
+            let crc = [header.as_ref(), &header_checksum, data].into_iter().fold(CRC_SEED, frame_crc);
+            write_all(&mut bus, &crc.to_be_bytes()[.. TRAILER]).await?;
+        }
+        poll_fn(|context| Pin::new(&mut **bus).poll_flush(context)).await?;
+        let size = HEADER+1 + data.len() + TRAILER;
+        let now = self.master.now();
+        self.master.transmitted.store(now, Relaxed);
+        if self.master.metrics.is_some() {
+            buffer.sent = now;
+        }
+        buffer.transmissions += 1;
+        if driver.is_some() {
+            // the answer may come before the driver is released
+            drop(pending);
+            let size = u32::try_from(size).unwrap();
+            timer::sleep(self.master.config.character_time() * size + self.master.turnaround).await;
+        }
+        Ok(())
+    }
+    /// number of frames sent and not answered yet, plus the answer not received yet
+    pub async fn outstanding(&self) -> usize {
+        self.master.pending.lock().await
+            .get(&self.token).unwrap()
+            .outstanding()
+    }
+    /**
+        wait until less than `depth` frames are outstanding, see [Self::outstanding]
+        
+        If it does not happen before the master's timeout, frames still in flight are considered lost and [Error::Timeout] is returned
+    */
+    pub async fn wait_outstanding(&self, depth: usize) -> Result<(), Error> {
+        let polling = poll_fn(|context| {
+            if let Some(mut pending) = self.master.pending.try_lock() {
+                let buffer = pending.get_mut(&self.token).unwrap();
+                if buffer.outstanding() < depth {
+                    return Poll::Ready(())
+                }
+                buffer.flow.replace(context.waker().clone());
+            }
+            // the master is busy, poll again rather than missing its wakeup
+            else {
+                context.waker().wake_by_ref();
+            }
+            Poll::Pending
+        });
+        if timer::timeout(self.timeout, polling).await.is_err() {
+            let mut pending = self.master.pending.lock().await;
+            let buffer = pending.get_mut(&self.token).unwrap();
+            buffer.answers = buffer.transmissions;
+            return Err(self.master.timed_out())
         }
         Ok(())
     }
     /// wait for answer to be ready in the current buffer
-    pub async fn receive(&self, mut copy: Option<&mut [u8]>) -> Result<u8, Error> {
+    pub async fn receive(&self, copy: Option<&mut [u8]>) -> Result<u8, Error> {
+        self.receive_timeout(copy, self.timeout).await
+    }
+    /// wait for answer to be ready in the current buffer, with a different timeout than the master's
+    pub async fn receive_timeout(&self, mut copy: Option<&mut [u8]>, timeout: Duration) -> Result<u8, Error> {
         let polling = poll_fn(|context| {
             if let Some(mut pending) = self.master.pending.try_lock() {
                 let buffer = pending.get_mut(&self.token).unwrap();
                 if let Some(result) = buffer.result.take() {
-                    if let Some(dst) = copy.take() {
-                        dst.copy_from_slice(buffer.buffer);
+                    // the buffer only holds an answer if it was received correctly
+                    if let Some(dst) = copy.take().filter(|_| result.is_ok()) {
+                        dst.copy_from_slice(&buffer.buffer);
                     }
+                    buffer.wake_flow();
                     return Poll::Ready(result)
                 }
                 buffer.waker.replace(context.waker().clone());
             }
-            // TODO check wether it is ok to return pending without changing waker in the pending task
-            // nothing else to do, leave resources to the runtime
+            // the master is busy, poll again rather than missing its wakeup
+            else {
+                context.waker().wake_by_ref();
+            }
             Poll::Pending
         });
-        tokio::time::timeout(self.master.timeout, polling).await
-            .map_err(|_| Error::Timeout)?
+        timer::timeout(timeout, polling).await
+            .map_err(|_| self.master.timed_out())?
+    }
+    /**
+        wait for an answer like [Self::receive], but return its header and data as received
+        
+        errors reported by the slaves or by the answer header are left in the returned header, only transmission errors are returned as errors
+    */
+    pub async fn receive_raw(&self) -> Result<(Command, Vec<u8>), Error> {
+        let polling = poll_fn(|context| {
+            if let Some(mut pending) = self.master.pending.try_lock() {
+                let buffer = pending.get_mut(&self.token).unwrap();
+                if let Some(result) = buffer.result.take() {
+                    buffer.wake_flow();
+                    return Poll::Ready(match buffer.answer.take() {
+                        Some(header) => Ok((header, buffer.raw.clone())),
+                        None => Err(result.err().unwrap_or(Error::Master("answer header lost"))),
+                    })
+                }
+                buffer.waker.replace(context.waker().clone());
+            }
+            // the master is busy, poll again rather than missing its wakeup
+            else {
+                context.waker().wake_by_ref();
+            }
+            Poll::Pending
+        });
+        timer::timeout(self.timeout, polling).await
+            .map_err(|_| self.master.timed_out())?
+    }
+    /// take the answer if already received, without waiting. `None` is returned if nothing was received or the master is busy
+    pub fn try_receive(&self, copy: Option<&mut [u8]>) -> Option<Result<u8, Error>> {
+        let mut pending = self.master.pending.try_lock()?;
+        let buffer = pending.get_mut(&self.token).unwrap();
+        let result = buffer.result.take()?;
+        if let Some(dst) = copy.filter(|_| result.is_ok()) {
+            dst.copy_from_slice(&buffer.buffer);
+        }
+        buffer.wake_flow();
+        Some(result)
+    }
+    /// token identifying this topic, its frames on the bus carry it with a sequence number in the low bits (see [SEQUENCE_BITS])
+    pub fn token(&self) -> u16 {self.token}
+    /// release the token right away, waiting for the master rather than deferring it like dropping does
+    pub async fn cancel(mut self) {
+        let removed = self.master.lock_pending().await.remove(&self.token);
+        self.master.recycle(removed);
+        self.released = true;
     }
     /// copy the current data in the buffer, received or not, already read or not
     pub async fn get(&self, dst: &mut [u8]) {
         let pending = self.master.pending.lock().await;
         let buffer = pending.get(&self.token).unwrap();
-        dst.copy_from_slice(buffer.buffer);
+        dst.copy_from_slice(&buffer.buffer);
     }
 }
 impl Drop for Topic<'_> {
     fn drop(&mut self) {
-        loop {
-            if let Some(mut pending) = self.master.pending.try_lock() {
-                pending.remove(&self.token);
-                break
-            }
-            // nothing else to do, leave resources to the kernel
-            std::thread::yield_now();
+        if self.released
+            {return}
+        match self.master.pending.try_lock() {
+            Some(mut pending) => self.master.recycle(pending.remove(&self.token)),
+            // the pending buffer is owned by the master, so it can outlive the topic until removed
+            None => self.master.dropped.lock().unwrap().push(self.token),
         }
     }
 }
-
-
-
-pub enum PinnedBuffer<'s> {
-    Borrowed(&'s mut [u8]),
-    Owned(Vec<u8>),
-}
-impl Deref for PinnedBuffer<'_> {
-    type Target = [u8];
-    fn deref(&self) -> &Self::Target {
-        match self {
-            Self::Borrowed(slice) => slice,
-            Self::Owned(vec) => vec.deref(),
-        }
-    }
-}
-impl DerefMut for PinnedBuffer<'_> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        match self {
-            Self::Borrowed(slice) => slice,
-            Self::Owned(vec) => vec.deref_mut(),
-        }
-    }
-}
-
-
@@ -0,0 +1,107 @@
+use std::{
+    path::Path,
+    pin::Pin,
+    io::ErrorKind,
+    task::{Context, Poll},
+    boxed::Box,
+    };
+use futures_io::{AsyncRead, AsyncWrite};
+use tokio::io::ReadBuf;
+use serial2_tokio::{SerialPort, CharSize};
+
+use crate::command::TRAILER;
+use super::networking::{Master, SerialConfig, DataBits, Parity, StopBits, ReconnectPolicy, Receiver, Transmitter};
+
+
+impl Master {
+    /// initialize a master on the given serial port file and with the given baud rate
+    pub fn new(path: impl AsRef<Path>, rate: u32) -> Result<Self, std::io::Error> {
+        Self::with_config(path, SerialConfig::new(rate))
+    }
+    /**
+        initialize a master on the given serial port file with custom serial settings
+        
+        settings not supported by the serial port are rejected here with an `InvalidInput` IO error, rather than failing later on the bus
+    */
+    pub fn with_config(path: impl AsRef<Path>, config: SerialConfig) -> Result<Self, std::io::Error> {
+        let (receive, transmit) = open(path.as_ref(), config)?;
+        Ok(Self::with_streams(receive, transmit, config))
+    }
+    /**
+        initialize a master like [Self::new], that reopens the serial port if it disappears
+        
+        see [Self::set_reconnect] for the behavior on disconnection
+    */
+    pub fn with_reconnect(path: impl AsRef<Path>, rate: u32, policy: ReconnectPolicy) -> Result<Self, std::io::Error> {
+        let config = SerialConfig::new(rate);
+        let mut master = Self::with_config(path.as_ref(), config)?;
+        let path = path.as_ref().to_path_buf();
+        master.set_reconnect(policy, move || open(&path, config));
+        Ok(master)
+    }
+}
+
+/**
+    adapter allowing to run a master on tokio streams, like the serial ports opened by [Master::new]
+    
+    the streams must be polled within a tokio runtime with its IO driver enabled
+*/
+pub struct TokioStream<S>(pub S);
+
+impl<S: tokio::io::AsyncRead + Unpin> AsyncRead for TokioStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, context: &mut Context<'_>, buffer: &mut [u8]) -> Poll<Result<usize, std::io::Error>> {
+        let mut buffer = ReadBuf::new(buffer);
+        Pin::new(&mut self.0).poll_read(context, &mut buffer)
+            .map_ok(|()| buffer.filled().len())
+    }
+}
+impl<S: tokio::io::AsyncWrite + Unpin> AsyncWrite for TokioStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, context: &mut Context<'_>, data: &[u8]) -> Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.0).poll_write(context, data)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.0).poll_flush(context)
+    }
+    fn poll_close(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.0).poll_shutdown(context)
+    }
+}
+
+/// open the serial port with the given settings, and clone it for independent RX and TX
+fn open(path: &Path, config: SerialConfig) -> Result<(Receiver, Transmitter), std::io::Error> {
+    let unsupported = |message| std::io::Error::new(ErrorKind::InvalidInput, message);
+    if config.data_bits != DataBits::Eight {
+        return Err(unsupported("uartcat frames need 8 data bits per character"));
+    }
+    if config.parity == Parity::None && TRAILER == 0 {
+        return Err(unsupported("without parity, the frame-crc feature is needed to detect corrupted bytes"));
+    }
+    let parity = match config.parity {
+        Parity::None => serial2_tokio::Parity::None,
+        Parity::Odd => serial2_tokio::Parity::Odd,
+        Parity::Even => serial2_tokio::Parity::Even,
+    };
+    let stop_bits = match config.stop_bits {
+        StopBits::One => serial2_tokio::StopBits::One,
+        StopBits::Two => serial2_tokio::StopBits::Two,
+        StopBits::OnePointFive => return Err(unsupported("1.5 stop bits are not supported by the serial port backend")),
+    };
+    let bus1 = SerialPort::open(path, |mut settings: serial2_tokio::Settings| {
+            settings.set_raw();
+            settings.set_baud_rate(config.rate)?;
+            settings.set_char_size(CharSize::Bits8);
+            settings.set_stop_bits(stop_bits);
+            settings.set_parity(parity);
+            Ok(settings)
+            })?;
+    // the port silently ignores settings it does not support
+    let applied = bus1.get_configuration()?;
+    if applied.get_parity()? != parity {
+        return Err(unsupported("parity is not supported by the serial port"));
+    }
+    if applied.get_stop_bits()? != stop_bits {
+        return Err(unsupported("stop bits are not supported by the serial port"));
+    }
+    let bus2 = bus1.try_clone()?;
+    Ok((Box::new(TokioStream(bus1)), Box::new(TokioStream(bus2))))
+}
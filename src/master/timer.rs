@@ -0,0 +1,59 @@
+/*!
+    timers used by the master, so the executor's timer is only pulled in one place
+    
+    they are based on tokio's timer with feature `tokio`, which needs the tokio runtime's time driver. Without it, or with feature `futures-timer`, they are based on the `futures-timer` crate, which needs no time driver.
+*/
+
+use core::{
+    future::{Future, poll_fn},
+    pin::pin,
+    task::Poll,
+    };
+use std::time::{Duration, Instant};
+
+
+/// error returned by [timeout] when the duration elapsed before the future completed
+#[derive(Copy, Clone, Debug)]
+pub struct Elapsed;
+
+/// wait for the given duration
+pub async fn sleep(duration: Duration) {
+    #[cfg(all(feature = "tokio", not(feature = "futures-timer")))]
+    tokio::time::sleep(duration).await;
+    #[cfg(not(all(feature = "tokio", not(feature = "futures-timer"))))]
+    futures_timer::Delay::new(duration).await;
+}
+
+/// run the given future, or give up if it does not complete within the given duration
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, Elapsed> {
+    let mut future = pin!(future);
+    let mut delay = pin!(sleep(duration));
+    poll_fn(|context| {
+        if let Poll::Ready(output) = future.as_mut().poll(context)
+            {return Poll::Ready(Ok(output))}
+        delay.as_mut().poll(context).map(|()| Err(Elapsed))
+    }).await
+}
+
+/// periodic ticks, skipping the ticks missed when not waited in time
+pub struct Interval {
+    period: Duration,
+    next: Instant,
+}
+impl Interval {
+    /// the first tick is immediate. The period must not be zero
+    pub fn new(period: Duration) -> Self {
+        assert!(!period.is_zero(), "interval period must be non-zero");
+        Self {period, next: Instant::now()}
+    }
+    /// wait for the next tick
+    pub async fn tick(&mut self) {
+        let now = Instant::now();
+        if self.next > now {
+            sleep(self.next - now).await;
+        }
+        let late = Instant::now().saturating_duration_since(self.next);
+        let missed = late.as_nanos() / self.period.as_nanos();
+        self.next += self.period * u32::try_from(missed + 1).unwrap_or(u32::MAX);
+    }
+}
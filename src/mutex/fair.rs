@@ -0,0 +1,252 @@
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicBool, AtomicUsize},
+    sync::atomic::Ordering::*,
+    future::poll_fn,
+    task::Poll,
+    ops::{Deref, DerefMut},
+    };
+
+/**
+    maximum number of tickets given at the same time by a [FairMutex]
+    
+    tickets share `abandoned` flags modulo this number, so no more tickets are given until the oldest one is served. More tasks can wait but they only get served in FIFO order once they got a ticket
+*/
+const WAITERS: usize = 32;
+
+/**
+    ticket mutex, serving waiters in the order they started waiting
+
+    each call to [Self::lock] takes a ticket and the lock is given to tickets in order. A waiting future that is dropped gives up its ticket so it does not block the following ones
+*/
+pub struct FairMutex<T> {
+    value: UnsafeCell<T>,
+    /// next ticket to give
+    next: AtomicUsize,
+    /// ticket currently owning the lock
+    serving: AtomicUsize,
+    /// tickets given up by cancelled waiters
+    abandoned: [AtomicBool; WAITERS],
+}
+// SAFETY: access to the value is exclusive thanks to the lock
+unsafe impl<T: Send> Sync for FairMutex<T> {}
+
+impl<T> From<T> for FairMutex<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+impl<T> FairMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            value: UnsafeCell::new(value),
+            next: AtomicUsize::new(0),
+            serving: AtomicUsize::new(0),
+            abandoned: [const {AtomicBool::new(false)}; WAITERS],
+        }
+    }
+    /// acquire lock if free and no task is waiting for it, otherwise return None
+    pub fn try_lock(&self) -> Option<FairMutexGuard<'_, T>> {
+        let serving = self.serving.load(Acquire);
+        self.next.compare_exchange(serving, serving.wrapping_add(1), Acquire, Relaxed).ok()?;
+        Some(FairMutexGuard {mutex: self})
+    }
+    /// busy polling future until lock is acquired, in FIFO order with other waiters
+    pub async fn lock(&self) -> FairMutexGuard<'_, T> {
+        let mut ticket = None;
+        poll_fn(|cx| {
+            if ticket.is_none() {
+                ticket = self.take_ticket().map(|ticket| Ticket {
+                    mutex: self,
+                    ticket,
+                    served: false,
+                });
+            }
+            if let Some(ticket) = &mut ticket
+            && self.serving.load(Acquire) == ticket.ticket {
+                ticket.served = true;
+                return Poll::Ready(FairMutexGuard {mutex: self})
+            }
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }).await
+    }
+    /// give the next ticket, unless [WAITERS] tickets are already given and not served
+    fn take_ticket(&self) -> Option<usize> {
+        loop {
+            // serving is loaded first so it cannot be past the next ticket loaded
+            let serving = self.serving.load(Acquire);
+            let next = self.next.load(Relaxed);
+            if next.wrapping_sub(serving) >= WAITERS
+                {return None}
+            if self.next.compare_exchange_weak(next, next.wrapping_add(1), Relaxed, Relaxed).is_ok()
+                {return Some(next)}
+        }
+    }
+    /// give the lock to the next ticket not abandoned, must be called by the owner of the lock
+    fn release(&self) {
+        loop {
+            let serving = self.serving.fetch_add(1, Release).wrapping_add(1);
+            // the abandoned ticket is now ours to release
+            if ! self.abandoned[serving % WAITERS].swap(false, AcqRel) 
+                {break}
+        }
+    }
+}
+
+/// ticket of a waiting task, giving it up if the wait is cancelled
+struct Ticket<'m, T> {
+    mutex: &'m FairMutex<T>,
+    ticket: usize,
+    served: bool,
+}
+impl<T> Drop for Ticket<'_, T> {
+    fn drop(&mut self) {
+        if self.served 
+            {return}
+        let mutex = self.mutex;
+        mutex.abandoned[self.ticket % WAITERS].store(true, Release);
+        // the lock may have reached this ticket before it was marked abandoned, then nobody else will release it
+        if mutex.serving.load(Acquire) == self.ticket 
+        && mutex.abandoned[self.ticket % WAITERS].swap(false, AcqRel) {
+            mutex.release();
+        }
+    }
+}
+
+pub struct FairMutexGuard<'m, T> {
+    mutex: &'m FairMutex<T>,
+}
+impl<T> Deref for FairMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe {& *self.mutex.value.get()}
+    }
+}
+impl<T> DerefMut for FairMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe {&mut *self.mutex.value.get()}
+    }
+}
+impl<T> Drop for FairMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.release();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{vec::Vec, boxed::Box};
+    use core::{
+        future::Future,
+        pin::Pin,
+        task::{Context, Waker},
+        };
+    use super::*;
+
+    type Waiting<'m> = Pin<Box<dyn Future<Output=FairMutexGuard<'m, usize>> + 'm>>;
+
+    fn poll<'m>(waiting: &mut Waiting<'m>) -> Option<FairMutexGuard<'m, usize>> {
+        match waiting.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(guard) => Some(guard),
+            Poll::Pending => None,
+        }
+    }
+
+    #[test]
+    fn no_starvation() {
+        const TASKS: usize = 4;
+        let mutex = FairMutex::new(0);
+        let mut seed = 0x2545_f491_u32;
+        let mut random = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as usize
+        };
+        // waiting tasks in the order they started waiting
+        let mut queue: Vec<(usize, Waiting)> = Vec::new();
+        let mut served = [0; TASKS];
+        for iteration in 0 .. 10_000 {
+            let mut guard = None;
+            // every task not waiting starts waiting, taking its ticket on first poll
+            for task in 0 .. TASKS {
+                if queue.iter().all(|&(waiting, _)| waiting != task) {
+                    queue.push((task, Box::pin(mutex.lock())));
+                    if let Some(acquired) = poll(&mut queue.last_mut().unwrap().1) {
+                        assert_eq!(queue.len(), 1, "lock not given in FIFO order");
+                        guard = Some(acquired);
+                    }
+                }
+            }
+            // a task sometimes gives up waiting
+            if guard.is_none() && random() % 8 == 0 {
+                drop(queue.remove(random() % queue.len()));
+            }
+            // poll tasks in random order, only the first one waiting must get the lock
+            for _ in 0 .. TASKS {
+                let index = random() % queue.len();
+                if index == 0 && guard.is_some()
+                    {continue}
+                if let Some(acquired) = poll(&mut queue[index].1) {
+                    assert_eq!(index, 0, "lock not given in FIFO order");
+                    guard = Some(acquired);
+                }
+            }
+            if guard.is_none() {
+                guard = poll(&mut queue[0].1);
+            }
+            let mut guard = guard.expect("first waiter starved");
+            assert!(mutex.try_lock().is_none());
+            *guard = iteration;
+            let (task, _) = queue.remove(0);
+            served[task] += 1;
+        }
+        drop(queue);
+        assert_eq!(mutex.try_lock().map(|guard| *guard), Some(9_999));
+        assert!(served.iter().all(|&count| count > 10_000 / TASKS / 2));
+    }
+    
+    #[test]
+    fn many_waiters() {
+        const TASKS: usize = 3 * WAITERS;
+        let mutex = FairMutex::new(0_usize);
+        let mut seed = 0x9e37_79b9_u32;
+        let mut random = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as usize
+        };
+        for round in 0 .. 100 {
+            let holder = mutex.try_lock().expect("lock not released");
+            // waiters in the order they started waiting, all polled once
+            let mut queue: Vec<Waiting> = (0 .. TASKS).map(|_| Box::pin(mutex.lock()) as Waiting).collect();
+            for waiting in &mut queue {
+                assert!(poll(waiting).is_none());
+            }
+            // the last one always gives up, for ticket sharing flags with an other
+            drop(queue.pop());
+            for _ in 0 .. random() % TASKS {
+                drop(queue.remove(random() % queue.len()));
+            }
+            drop(holder);
+            // all remaining waiters must be served in order
+            while ! queue.is_empty() {
+                let mut guard = None;
+                for (index, waiting) in queue.iter_mut().enumerate() {
+                    if let Some(acquired) = poll(waiting) {
+                        assert_eq!(index, 0, "lock not given in FIFO order");
+                        guard = Some(acquired);
+                        break;
+                    }
+                }
+                let mut guard = guard.expect("waiter starved");
+                *guard = round;
+                drop(guard);
+                drop(queue.remove(0));
+            }
+        }
+        assert_eq!(mutex.try_lock().map(|guard| *guard), Some(99));
+    }
+}
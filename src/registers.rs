@@ -4,7 +4,10 @@
     each standard is described by a serializable data type and a constant of type [SlaveRegister] defining its standard position in slaves' memory.
 */
 
-use core::marker::PhantomData;
+use core::{
+    marker::PhantomData,
+    ops::Range,
+    };
 use packbytes::{FromBytes, ToBytes, ByteArray};
 use bilge::prelude::*;
 use crate::pack_enum;
@@ -17,28 +20,54 @@ use crate::pack_enum;
     
     depending on the target memory, address size can vary. See [SlaveRegister]  and [VirtualRegister]
 */
-#[derive(PartialEq, Hash)]
 pub struct Register<T, A> {
     addr: A,
+    name: Option<&'static str>,
     ty: PhantomData<T>,
 }
 impl<T, A:Copy> Register<T, A> {
     /// create a register from its starting byte
     pub const fn new(address: A) -> Self {
-        Self{addr: address, ty: PhantomData}
+        Self{addr: address, name: None, ty: PhantomData}
+    }
+    /// give a name to this register, only used to display it in diagnostics
+    pub const fn named(self, name: &'static str) -> Self {
+        Self{addr: self.addr, name: Some(name), ty: PhantomData}
     }
     /// starting byte in memory
     pub const fn address(&self) -> A {self.addr}
+    /// name given with [Self::named]
+    pub const fn name(&self) -> Option<&'static str> {self.name}
 }
 impl<T: FromBytes, A> Register<T, A> {
     pub const fn size(&self) -> SlaveSize {T::Bytes::SIZE as SlaveSize}
 }
 impl<T, A:Copy> Clone for Register<T, A> {
     fn clone(&self) -> Self {
-        Self::new(self.address())
+        *self
     }
 }
 impl<T, A:Copy> Copy for Register<T, A> {}
+/// registers are equal when at the same address, whatever their name
+impl<T, A:PartialEq> PartialEq for Register<T, A> {
+    fn eq(&self, other: &Self) -> bool {
+        self.addr == other.addr
+    }
+}
+impl<T, A:core::hash::Hash> core::hash::Hash for Register<T, A> {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        self.addr.hash(state)
+    }
+}
+/// display the register name if any, and its address
+impl<T, A:core::fmt::LowerHex> core::fmt::Display for Register<T, A> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.name {
+            Some(name) => write!(f, "{} ({:#x})", name, self.addr),
+            None => write!(f, "{:#x}", self.addr),
+        }
+    }
+}
 
 
 /// integer used for addressing slave memory
@@ -53,27 +82,119 @@ pub type VirtualRegister<T> = Register<T, VirtualSize>;
 
 
 
-/// slave fixed address
-pub const ADDRESS: SlaveRegister<SlaveSize> = Register::new(0x0);
+/// slave fixed address. An exchange answers the address before the write, so a topological exchange assigns an address and tells the previous one in the same frame
+pub const ADDRESS: SlaveRegister<SlaveSize> = Register::new(0x0).named("ADDRESS");
+/// value of [ADDRESS] for a slave that has no fixed address yet. Such slave can only be reached by topological addressing
+pub const UNASSIGNED: SlaveSize = SlaveSize::MAX;
 /// first communication error raise by slave, write to 0 to reset
-pub const ERROR: SlaveRegister<CommandError> = Register::new(0x2);
-/// count the number of loss sequences detected since last reset, write to 0 to reset
-pub const LOSS: SlaveRegister<u16> = Register::new(0x3);
-/// protocol version
-pub const VERSION: SlaveRegister<u8> = Register::new(0x5);
-/// slave standard informations
-pub const DEVICE: SlaveRegister<Device> = Register::new(0x20);
-/// slave clock value when reading
-pub const CLOCK: SlaveRegister<u64> = Register::new(0x86);
-/// mapping between registers and virtual memory
-pub const MAPPING: SlaveRegister<MappingTable> = Register::new(0xff);
+pub const ERROR: SlaveRegister<CommandError> = Register::new(0x2).named("ERROR");
+/// count the number of loss sequences detected since last reset, write to 0 to reset. Their causes are counted in [LOSS_REASONS]
+pub const LOSS: SlaveRegister<u16> = Register::new(0x3).named("LOSS");
+/// protocol version, [PROTOCOL_VERSION] for slaves of this crate. Read-only
+pub const VERSION: SlaveRegister<u8> = Register::new(0x5).named("VERSION");
+/**
+    version of the protocol implemented by this crate, masters refuse slaves reporting an other [VERSION]
+    
+    version 2 is incompatible with version 1 slaves:
+    - [CLOCK] moved from `0x86`, where it overlapped [DEVICE], to `0xa0`
+    - [BUFFER_SIZE], [CONTROL], [MAPPING_CAPACITY] and [LOSS_REASONS] were added
+    - [ADDRESS] is [UNASSIGNED] instead of 0 until a fixed address is given
+    - errors of a command are reported in its answer header, see [Access::command_error](crate::command::Access::command_error)
+    - [MAPPING] can be written partially
+    - the low bits of command tokens hold a sequence number
+*/
+pub const PROTOCOL_VERSION: u8 = 2;
+/// size of the slave buffer in bytes (not counting segments), read-only. It saturates to `u16::MAX` for a buffer covering the whole slave memory
+pub const BUFFER_SIZE: SlaveRegister<u16> = Register::new(0x6).named("BUFFER_SIZE");
+/// actions requested to the slave, each bit triggers an action when written and is cleared once done
+pub const CONTROL: SlaveRegister<u8> = Register::new(0x8).named("CONTROL");
+/// bit of [CONTROL] resetting [LOSS], [LOSS_REASONS], [ERROR], the [MAPPING] table and [ADDRESS] to [UNASSIGNED]
+pub const CONTROL_RESET: u8 = 0b1;
+/// nonzero if the slave executes commands with extended addressing, see [Access::extended](crate::command::Access::extended). It is cleared by [CONTROL_RESET]
+#[cfg(feature = "extended-address")]
+pub const SELECT: SlaveRegister<u8> = Register::new(0x9).named("SELECT");
+/// slave standard informations. Read-only
+pub const DEVICE: SlaveRegister<Device> = Register::new(0x20).named("DEVICE");
+/// slave clock value when reading. It was at `0x86`, overlapping [DEVICE], before [PROTOCOL_VERSION] 2. Read-only
+pub const CLOCK: SlaveRegister<u64> = Register::new(0xa0).named("CLOCK");
+/// number of entries a slave can apply from [MAPPING], at most [MAPPING_ENTRIES]. Read-only
+pub const MAPPING_CAPACITY: SlaveRegister<u8> = Register::new(0xa8).named("MAPPING_CAPACITY");
+/// number of lost commands by reason, counted along [LOSS] but always saturating. It is reset by [CONTROL_RESET]
+pub const LOSS_REASONS: SlaveRegister<LossReasons> = Register::new(0xaa).named("LOSS_REASONS");
+/**
+    mapping between registers and virtual memory
+    
+    its layout is the same for all slaves, with [MAPPING_ENTRIES] entries, so the registers after it stay at the same addresses. But a slave can only apply tables of at most [MAPPING_CAPACITY] non-empty entries, bigger tables are rejected with [CommandError::InvalidMapping] and leave the slave without mapping
+*/
+pub const MAPPING: SlaveRegister<MappingTable> = Register::new(0xff).named("MAPPING");
 
 /// end of standard mendatory section of slave buffer
 pub const USER: usize = 0x500;
 
+/// description of a standard register, to handle registers generically at runtime. See [STANDARD]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RegisterMeta {
+    /// name of the register constant
+    pub name: &'static str,
+    /// starting byte in slave memory
+    pub address: SlaveSize,
+    /// size in bytes
+    pub size: SlaveSize,
+    /// the register is only meant to be read by the master, slaves reject writes to it
+    pub read_only: bool,
+}
+impl RegisterMeta {
+    /// bytes covered in slave memory
+    pub const fn range(&self) -> Range<usize> {
+        self.address as usize .. self.address as usize + self.size as usize
+    }
+}
+/// describe a register constant, so its meta stays in sync with it
+macro_rules! meta {
+    ($register:ident, $read_only:expr) => {
+        RegisterMeta {
+            name: stringify!($register),
+            address: $register.address(),
+            size: $register.size(),
+            read_only: $read_only,
+        }
+    };
+}
+/// all standard registers, sorted by address
+pub const STANDARD: &[RegisterMeta] = &[
+    meta!(ADDRESS, false),
+    meta!(ERROR, false),
+    meta!(LOSS, false),
+    meta!(VERSION, true),
+    meta!(BUFFER_SIZE, true),
+    meta!(CONTROL, false),
+    #[cfg(feature = "extended-address")]
+    meta!(SELECT, false),
+    meta!(DEVICE, true),
+    meta!(CLOCK, true),
+    meta!(MAPPING_CAPACITY, true),
+    meta!(LOSS_REASONS, false),
+    meta!(MAPPING, false),
+];
+// fail the build if a standard register is moved over an other one
+const _: () = assert!(layout_valid(STANDARD, USER), "standard registers overlap or exceed the standard section");
+
+/// true if the given registers are sorted by address, do not overlap, and all end before `end`
+pub const fn layout_valid(registers: &[RegisterMeta], end: usize) -> bool {
+    let mut i = 0;
+    while i < registers.len() {
+        let stop = registers[i].address as usize + registers[i].size as usize;
+        if stop > end 
+        || (i+1 < registers.len() && stop > registers[i+1].address as usize)
+            {return false}
+        i += 1;
+    }
+    true
+}
+
 
 /// slave standard informations
-#[derive(Clone, FromBytes, ToBytes, Debug)]
+#[derive(Clone, Default, FromBytes, ToBytes, Debug)]
 pub struct Device {
     /// model name
     pub model: StringArray,
@@ -84,11 +205,74 @@ pub struct Device {
     /// serial number of this specific hardware item
     pub serial: StringArray,
 }
+impl Device {
+    /// start building device informations, all strings are empty by default
+    pub fn builder() -> DeviceBuilder {
+        DeviceBuilder::default()
+    }
+}
+/**
+    builder for [Device], failing on strings too long for a [StringArray]
+    
+    ```
+    # use uartcat::registers::Device;
+    # fn main() -> Result<(), &'static str> {
+    let device = Device::builder()
+        .model("my-board")?
+        .hardware_version("0.1")?
+        .software_version("0.2")?
+        .build();
+    # Ok(())
+    # }
+    ```
+*/
+#[derive(Clone, Default, Debug)]
+pub struct DeviceBuilder {
+    device: Device,
+}
+impl DeviceBuilder {
+    /// set [Device::model]
+    pub fn model(mut self, value: &str) -> Result<Self, &'static str> {
+        self.device.model = value.try_into()?;
+        Ok(self)
+    }
+    /// set [Device::hardware_version]
+    pub fn hardware_version(mut self, value: &str) -> Result<Self, &'static str> {
+        self.device.hardware_version = value.try_into()?;
+        Ok(self)
+    }
+    /// set [Device::software_version]
+    pub fn software_version(mut self, value: &str) -> Result<Self, &'static str> {
+        self.device.software_version = value.try_into()?;
+        Ok(self)
+    }
+    /// set [Device::serial]
+    pub fn serial(mut self, value: &str) -> Result<Self, &'static str> {
+        self.device.serial = value.try_into()?;
+        Ok(self)
+    }
+    /// finish the device informations
+    pub fn build(self) -> Device {
+        self.device
+    }
+}
+/// content of [LOSS_REASONS]
+#[derive(Copy, Clone, Default, FromBytes, ToBytes, Debug, PartialEq, Eq)]
+pub struct LossReasons {
+    /// errors of the serial port, like framing or parity errors
+    pub bus: u16,
+    /// commands to execute whose data checksum or frame CRC was wrong
+    pub checksum: u16,
+    /// commands to execute bigger than the slave frame size
+    pub oversized: u16,
+}
+/// number of entries in [MappingTable]
+pub const MAPPING_ENTRIES: usize = 128;
 /// slave config for mapping between slave and virtual memory
 #[derive(Clone, FromBytes, ToBytes, Debug)]
 pub struct MappingTable {
     pub size: u8,
-    pub map: [Mapping; 128],
+    pub map: [Mapping; MAPPING_ENTRIES],
 }
 /// setting for mapping a range of memory between slave and virtual memory
 #[derive(Copy, Clone, Default, FromBytes, ToBytes, Debug, PartialEq)]
@@ -97,11 +281,40 @@ pub struct Mapping {
     pub slave_start: u16,
     pub size: u16,
 }
+impl Mapping {
+    /// range of virtual memory covered by this mapping
+    pub fn virtual_range(&self) -> Range<VirtualSize> {
+        self.virtual_start .. self.virtual_start.saturating_add(u32::from(self.size))
+    }
+}
+/** 
+    return the ranges where a mapping intersects the given range of virtual memory (typically the data of a frame), if they share any byte
+    
+    result is a couple (offsets in the given range, addresses in the slave). This is the mapping math of slaves, so masters can predict what slaves will do
+*/
+pub fn intersect_mapping(mapped: Mapping, frame: Range<VirtualSize>) -> Option<(Range<usize>, Range<usize>)> {
+    let mapped_range = mapped.virtual_range();
+    let intersection = Range {
+        start: mapped_range.start.max(frame.start),
+        end: mapped_range.end.min(frame.end),
+        };
+    if intersection.end <= intersection.start
+        {return None}
+    
+    let offset = |start: VirtualSize, base: usize| {
+        usize::try_from(intersection.start - start).unwrap() + base
+        .. usize::try_from(intersection.end - start).unwrap() + base
+    };
+    Some((
+        offset(frame.start, 0),
+        offset(mapped.virtual_start, usize::from(mapped.slave_start)),
+    ))
+}
 impl Default for MappingTable {
     fn default() -> Self {
         Self {
             size: 0,
-            map: [Default::default(); 128],
+            map: [Default::default(); MAPPING_ENTRIES],
             }
     }
 }
@@ -113,7 +326,7 @@ impl MappingTable {
                 return Err("too many items for table");
             }
             table.map[i] = item;
-            table.size = u8::try_from(i).unwrap();
+            table.size = u8::try_from(i + 1).unwrap();
         }
         Ok(table)
     }
@@ -138,6 +351,10 @@ pub enum CommandError {
     InvalidRegister = 4,
     /// register set in mapping doesn't exist
     InvalidMapping = 5,
+    /// data of a write command was corrupted, the write was not executed
+    Checksum = 6,
+    /// the slave lost as many commands as its loss threshold, see [LOSS]
+    LossThreshold = 7,
 }
 pack_enum!(CommandError);
 
@@ -151,11 +368,12 @@ impl TryFrom<&str> for StringArray {
     type Error = &'static str;
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         let value = value.as_bytes();
-        let size = u8::try_from(value.len()) .map_err(|_|  "input string exceeds maximum size")?;
-        let mut dst = Self {size, .. Default::default()};
+        let mut dst = Self::default();
         if value.len() > dst.buffer.len()
             {return Err("input string too long");}
         dst.buffer[..value.len()] .copy_from_slice(value);
+        // cannot fail since buffer is smaller than u8::MAX
+        dst.size = value.len() as u8;
         Ok(dst)
     }
 }
@@ -164,3 +382,84 @@ impl StringArray {
         str::from_utf8(&self.buffer[.. usize::from(self.size)])
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    #[test]
+    fn string_array_size() {
+        let string = StringArray::try_from("").unwrap();
+        assert_eq!(string.size, 0);
+        assert_eq!(string.as_str().unwrap(), "");
+        
+        let text = "abcdefghijklmnopqrstuvwxyz01234";
+        assert_eq!(text.len(), 31);
+        let string = StringArray::try_from(text).unwrap();
+        assert_eq!(string.size, 31);
+        assert_eq!(string.as_str().unwrap(), text);
+        
+        let text = "abcdefghijklmnopqrstuvwxyz012345";
+        assert_eq!(text.len(), 32);
+        assert!(StringArray::try_from(text).is_err());
+    }
+    
+    #[test]
+    fn mapping_intersection() {
+        let mapped = Mapping {virtual_start: 10, slave_start: 0x100, size: 4};
+        // frame fully inside the mapping
+        assert_eq!(intersect_mapping(mapped, 11 .. 13), Some((0 .. 2, 0x101 .. 0x103)));
+        // mapping fully inside the frame
+        assert_eq!(intersect_mapping(mapped, 8 .. 20), Some((2 .. 6, 0x100 .. 0x104)));
+        // partial overlaps on both sides
+        assert_eq!(intersect_mapping(mapped, 12 .. 20), Some((0 .. 2, 0x102 .. 0x104)));
+        assert_eq!(intersect_mapping(mapped, 5 .. 11), Some((5 .. 6, 0x100 .. 0x101)));
+        // mapping exactly at a frame boundary
+        assert_eq!(intersect_mapping(mapped, 14 .. 20), None);
+        assert_eq!(intersect_mapping(mapped, 4 .. 10), None);
+        assert_eq!(intersect_mapping(mapped, 10 .. 14), Some((0 .. 4, 0x100 .. 0x104)));
+        // zero sizes
+        assert_eq!(intersect_mapping(Mapping {size: 0, ..mapped}, 8 .. 20), None);
+        assert_eq!(intersect_mapping(mapped, 11 .. 11), None);
+        // mapping at the end of virtual memory
+        let last = Mapping {virtual_start: u32::MAX - 2, slave_start: 0, size: 2};
+        assert_eq!(intersect_mapping(last, u32::MAX - 1 .. u32::MAX), Some((0 .. 1, 1 .. 2)));
+    }
+    
+    #[test]
+    fn device_builder() {
+        let device = Device::builder()
+            .model("model").unwrap()
+            .serial("1234").unwrap()
+            .build();
+        assert_eq!(device.model.as_str().unwrap(), "model");
+        assert_eq!(device.serial.as_str().unwrap(), "1234");
+        assert_eq!(device.hardware_version.as_str().unwrap(), "");
+        assert!(Device::builder().model("abcdefghijklmnopqrstuvwxyz012345").is_err());
+    }
+    
+    #[test]
+    fn register_name() {
+        use std::string::ToString;
+        assert_eq!(ERROR.to_string(), "ERROR (0x2)");
+        let unnamed = SlaveRegister::<u16>::new(0x500);
+        assert_eq!(unnamed.to_string(), "0x500");
+        // the name is only for display
+        assert!(unnamed == unnamed.named("COUNTER"));
+    }
+    
+    #[test]
+    fn standard_registers() {
+        for pair in STANDARD.windows(2) {
+            assert!(pair[0].address + pair[0].size <= pair[1].address, "{} overlaps {}", pair[0].name, pair[1].name);
+        }
+        let last = STANDARD.last().unwrap();
+        assert!(usize::from(last.address + last.size) <= USER);
+        
+        let overlapping = [meta!(DEVICE, true), RegisterMeta {address: CLOCK.address() - 1, ..meta!(CLOCK, true)}];
+        assert!(layout_valid(STANDARD, USER));
+        assert!(!layout_valid(&overlapping, USER));
+        assert!(!layout_valid(STANDARD, usize::from(MAPPING.address())));
+    }
+}
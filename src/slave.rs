@@ -1,15 +1,20 @@
 /*!
     implement a asynchronous uartcat slave in a ` no-std`  and ` no-alloc` environment.
 */
-use core::ops::{Deref, DerefMut, Range};
+use core::{
+    ops::{Deref, DerefMut, Range},
+    task::{Context, Poll, Waker},
+    pin::pin,
+    future::poll_fn,
+    };
 use packbytes::{FromBytes, ToBytes, ByteArray};
-use embedded_io_async::{Read, Write, ReadExactError};
+use embedded_io_async::{Read, Write, ReadExactError, ErrorType};
 use log::*;
 
 use crate::{
     mutex::*,
     command::*,
-    registers::{SlaveRegister, self},
+    registers::{SlaveRegister, SlaveSize, self},
     };
 
 
@@ -17,52 +22,202 @@ use crate::{
     uartcat slave async implementation for bare-metal `no-std` and `no-alloc` environment
     
     A slave owns a local data buffer of `MEM` bytes, that is shared between bus coroutine and user task using a sync mutex.
-    This buffer stores communication config of the slave as well as user data the slave wants to share with the master
+    This buffer stores communication config of the slave as well as user data the slave wants to share with the master.
+    Additional user memory with independent locks can be placed after this buffer, see [Self::with_segments]
+    
+    `FRAME` is the maximum data size of commands the slave can process, it sizes the slave's reception and emission buffers. It cannot exceed [MAX_COMMAND]. Since every command is passing through all slaves, the master must not send bigger commands than the smallest `FRAME` on the bus, see [Master::set_max_command](crate::master::Master::set_max_command). Bigger commands are relayed without being processed, and reported with [registers::CommandError::InvalidSize] by the slave they address
+    
+    `MAP` is the number of mapping entries the slave can apply, which sizes its mapping storage. It cannot exceed [registers::MAPPING_ENTRIES]. The [registers::MAPPING] register keeps the same layout whatever `MAP`, the slave reports its capacity in [registers::MAPPING_CAPACITY] and rejects bigger tables with [registers::CommandError::InvalidMapping]
+    
+    The bus task only locks the buffer to copy a command's data between the buffer and its own frame buffers, never while receiving or transmitting, and validators run before locking. So the user task waits at most for one copy of `FRAME` bytes in each direction, plus the `on_read`/`on_write` reactions of the registers accessed. Memory needing shorter or independent locks can be placed in [segments](Self::with_segments)
 */
-pub struct Slave<B, const MEM: usize> {
+pub struct Slave<B, const MEM: usize, const FRAME: usize = MAX_COMMAND, const MAP: usize = {registers::MAPPING_ENTRIES}> {
     buffer: BusyMutex<SlaveBuffer<MEM>>,
-    control: BusyMutex<SlaveControl<B>>,
+    control: BusyMutex<SlaveControl<B, FRAME, MAP>>,
 }
 /// buffer of `MEM` bytes data shared between slave tasks an the bus communication
 pub struct SlaveBuffer<const MEM: usize> {
     buffer: [u8; MEM],
+    /// how [registers::LOSS] is counted
+    loss: LossPolicy,
+}
+/// how a slave counts lost commands in [registers::LOSS], see [Slave::with_loss_policy]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum LossPolicy {
+    /// the counter stops at its maximum
+    #[default]
+    Saturate,
+    /// the counter restarts from zero after its maximum, so the master can compute a loss rate from successive reads
+    Wrap,
+    /**
+        when the counter reaches the given count, it restarts from zero and [registers::CommandError::LossThreshold] is set in [registers::ERROR] if no error is pending
+        
+        the command whose loss reached the threshold also reports this error in its answer, if it could be answered
+    */
+    Threshold(u16),
+}
+/// cause of a lost command, counted in [registers::LOSS_REASONS]
+#[derive(Copy, Clone, Debug)]
+enum LossReason {
+    Bus,
+    Checksum,
+    Oversized,
 }
-struct SlaveControl<B> {
+/**
+    state of the bus communication, exclusively owned by the task running the slave
+    
+    [Slave::run] and [Slave::poll_once] hold its lock for as long as they process commands and panic if it is already held, so only one command is processed at a time. This is what makes the mapping rebuild atomic: it is mutated in `on_write` while the buffer lock is held, but no virtual exchange can read it meanwhile since both need this struct mutably
+*/
+struct SlaveControl<B, const FRAME: usize, const MAP: usize> {
     bus: B,
-    mapping: heapless::Vec<registers::Mapping, 128>,
+    /// mappings applied by virtual exchanges, only consistent between two commands
+    mapping: heapless::Vec<registers::Mapping, MAP>,
+    validators: &'static [RegisterSpec],
+    computed: &'static [ComputedRegister],
+    segments: &'static [&'static dyn Segment],
+    /// time source for the clock register
+    clock: Option<fn() -> u64>,
+    /// clock value when the current command was received
+    arrival: u64,
+    /// switch of the RS-485 driver, see [Slave::with_direction_control]
+    direction: Option<fn(bool)>,
+    /// whether the slave is selected for extended addressing, always false without the `extended-address` feature
+    selected: bool,
+    /// whether the trailer of the current command was good, always true without the `frame-crc` feature
+    intact: bool,
     address: u16,
-    receive: [u8; MAX_COMMAND],
-    send: [u8; MAX_COMMAND],
+    receive: [u8; FRAME],
+    send: [u8; FRAME],
     send_header: Command,
 }
 
-// TODO: implement separated TX and RX
-impl<B: Read + Write, const MEM: usize> Slave<B, MEM> {
+// TODO: implement separated TX and RX, the mapping would then need its own lock since TX and RX tasks would not share a single owner of the control
+impl<B: Read + Write, const MEM: usize, const FRAME: usize, const MAP: usize> Slave<B, MEM, FRAME, MAP> {
     /// initialize the slave on the given UART bus, with the given slave identification infos
     pub fn new(bus: B, device: registers::Device) -> Self {
         assert!(MEM >= registers::USER, "buffer is too small for standard registers");
+        assert!(FRAME <= MAX_COMMAND, "frame size is bigger than the protocol allows");
+        assert!(MAP <= registers::MAPPING_ENTRIES, "mapping capacity is bigger than the mapping table");
     
-        let mut buffer = SlaveBuffer {buffer: [0; MEM]};
-        buffer.set(registers::VERSION, 1);
+        let mut buffer = SlaveBuffer {buffer: [0; MEM], loss: LossPolicy::Saturate};
+        buffer.set(registers::VERSION, registers::PROTOCOL_VERSION);
+        buffer.set(registers::BUFFER_SIZE, u16::try_from(MEM).unwrap_or(u16::MAX));
         buffer.set(registers::DEVICE, device);
         buffer.set(registers::LOSS, 0);
-        buffer.set(registers::ADDRESS, 0);
+        buffer.set(registers::LOSS_REASONS, registers::LossReasons::default());
+        buffer.set(registers::ADDRESS, registers::UNASSIGNED);
+        buffer.set(registers::MAPPING_CAPACITY, MAP as u8);
         
         let new = Self {
             buffer: BusyMutex::from(buffer),
             control: BusyMutex::from(SlaveControl {
                 bus,
-                address: 0,
+                address: registers::UNASSIGNED,
                 mapping: heapless::Vec::new(),
-                receive: [0; MAX_COMMAND],
-                send: [0; MAX_COMMAND],
+                validators: &[],
+                computed: &[],
+                segments: &[],
+                clock: None,
+                arrival: 0,
+                direction: None,
+                selected: false,
+                intact: true,
+                receive: [0; FRAME],
+                send: [0; FRAME],
                 send_header: Command::default(),
             }),
         };
         new
     }
+    /**
+        initialize the slave with a mapping table already set, so virtual exchanges work without the master configuring the slave
+        
+        The table is checked the same way as when written by the master, and this function panics if a mapping is out of the slave buffer. Since segments are not known yet, the mappings can only target the slave buffer. The master can still overwrite the table later.
+    */
+    pub fn new_with_mapping(bus: B, device: registers::Device, table: registers::MappingTable) -> Self {
+        let new = Self::new(bus, device);
+        {
+            let mut buffer = new.buffer.try_lock().unwrap();
+            let mut control = new.control.try_lock().unwrap();
+            assert!(usize::from(table.size) <= table.map.len(), "mapping table size is bigger than its capacity");
+            assert!(control.set_mapping(&table, MEM), "mapping table has mappings out of the slave buffer");
+            buffer.set(registers::MAPPING, table);
+        }
+        new
+    }
+    /**
+        set registers whose value must be validated before being written by the master
+        
+        a write that is not passing validation is rejected with [registers::CommandError::InvalidAccess] and the register keeps its previous value
+    */
+    pub fn with_validators(self, validators: &'static [RegisterSpec]) -> Self {
+        self.control.try_lock().unwrap().validators = validators;
+        self
+    }
+    /**
+        set registers whose value is computed when the master reads them, rather than stored in advance
+        
+        they are computed while the slave buffer is locked by the bus task, so the compute functions must be short. Writes and exchanges of the master on these registers are rejected with [registers::CommandError::InvalidAccess] without computing them. This function panics if a register is not in the user section of the slave buffer.
+    */
+    pub fn with_computed(self, computed: &'static [ComputedRegister]) -> Self {
+        for register in computed {
+            assert!(usize::from(register.address) >= registers::USER, "computed register is in the standard section");
+            assert!(usize::from(register.address) + usize::from(register.size) <= MEM, "computed register is out of the slave buffer");
+        }
+        self.control.try_lock().unwrap().computed = computed;
+        self
+    }
     
-    /// wait until getting access to the slave's buffer
+    /**
+        add memory segments after the slave buffer, each with its own lock
+        
+        Commands are only locking the segments they access, so a user task holding a segment does not delay the bus accessing other segments or the slave buffer. A command accessing multiple segments is locking them one after the other, so its access is not atomic over the whole command.
+        
+        Segments must be placed after the slave buffer and must not overlap, otherwise this function panics. They must fit in 16 bit addresses, unless the `extended-address` feature allows to reach them, see [Access::extended]
+    */
+    pub fn with_segments(self, segments: &'static [&'static dyn Segment]) -> Self {
+        for (i, segment) in segments.iter().enumerate() {
+            let range = segment.range();
+            assert!(range.start >= MEM, "segment overlaps slave buffer");
+            let addressable = if cfg!(feature = "extended-address") {usize::MAX} else {usize::from(SlaveSize::MAX) + 1};
+            assert!(range.end <= addressable, "segment exceeds slave addressable memory");
+            assert!(segments[.. i].iter().all(|other| !overlaps(&range, &other.range())), "segments overlap");
+        }
+        self.control.try_lock().unwrap().segments = segments;
+        self
+    }
+    /// set how lost commands are counted, the default is [LossPolicy::Saturate]
+    pub fn with_loss_policy(self, policy: LossPolicy) -> Self {
+        self.buffer.try_lock().unwrap().loss = policy;
+        self
+    }
+    /**
+        set the time source used to answer reads of [registers::CLOCK]
+        
+        The clock is sampled when a command header has been fully received, so the value read by the master corresponds to the reception of the header of its read command, not to the time of processing. To estimate the start of the frame, the master should substract the transmission time of a header (12 bytes) at the bus baud rate.
+        
+        Its unit is up to the slave, typically microseconds as given by `embassy_time::Instant::now().as_micros()`. Its resolution is the one of the underlying timer, with embassy it is the `tick-hz` feature selected, which should be finer than a byte transmission time on the bus to be meaningful.
+    */
+    pub fn with_clock(self, clock: fn() -> u64) -> Self {
+        self.control.try_lock().unwrap().clock = Some(clock);
+        self
+    }
+    
+    /**
+        set a function switching the driver of a half-duplex RS-485 transceiver, typically toggling its DE/RE GPIO
+        
+        It is called with `true` before transmitting each frame and with `false` once the bus is flushed, so the bus implementation must only return from `flush` once the last byte left the UART. Any turnaround delay needed by the transceiver must be waited by this function. Frames too big for this slave are relayed while receiving them, so the driver stays enabled during their whole reception
+    */
+    pub fn with_direction_control(self, control: fn(bool)) -> Self {
+        self.control.try_lock().unwrap().direction = Some(control);
+        self
+    }
+    
+    /**
+        wait until getting access to the slave's buffer
+        
+        While it is held, commands accessing this slave's buffer are waiting, but commands addressed to other slaves or to virtual memory not mapped here are still forwarded without delay
+    */
     pub async fn lock(&self) -> BusyMutexGuard<'_, SlaveBuffer<MEM>> {self.buffer.lock().await}
     /// try to get access to the slave's buffer, immediately abort if the buffer is being used by other tasks
     pub fn try_lock(&self) -> Option<BusyMutexGuard<'_, SlaveBuffer<MEM>>> {self.buffer.try_lock()}
@@ -70,21 +225,217 @@ impl<B: Read + Write, const MEM: usize> Slave<B, MEM> {
     /** 
         coroutine reacting to uartcat commands received on the bus. it is responsible of all communications with the master.
         
-        It **must** run in order to communicate with the master
+        It **must** run in order to communicate with the master, and must be called exactly once. It panics if it is already running
     */
     pub async fn run(&self) {
-        let Some(mut control) = self.control.try_lock() 
-            else {return};
+        let mut control = self.control.try_lock().expect("run function called twice");
         loop {
 //             if control.receive_command(self).await.is_err() {
             if let Err(err) = control.receive_command(self).await {
                 warn!("uartcat error {:?}", err);
-                self.buffer.lock().await.add_loss();
+                // the error is latched in the buffer, there is no answer to report it
+                self.buffer.lock().await.add_loss(LossReason::Bus).ok();
             }
         }
     }
 }
 
+impl<B, const MEM: usize, const FRAME: usize, const MAP: usize> Slave<BlockingBus<B>, MEM, FRAME, MAP> 
+where B: embedded_io::Read + embedded_io::Write + embedded_io::ReadReady
+{
+    /**
+        process one command if some data is available on the bus, without needing an async executor
+        
+        This is the blocking alternative to [Self::run], meant to be called repeatedly from a superloop or a timer interrupt. It returns `true` if a command has been processed.
+        Once the start of a command is available, this function blocks until the full command is received and answered.
+        
+        It busy waits on the slave's buffer, so it must not be called in a context interrupting a holder of [Self::lock]. It panics if [Self::run] is running
+    */
+    pub fn poll_once(&self) -> Result<bool, B::Error> {
+        let mut control = self.control.try_lock().expect("run function is already running");
+        if !control.bus.0.read_ready()? {
+            return Ok(false);
+        }
+        if let Err(err) = block_on(control.receive_command(self)) {
+            warn!("uartcat error {:?}", err);
+            block_on(self.buffer.lock()).add_loss(LossReason::Bus).ok();
+            return Err(err);
+        }
+        Ok(true)
+    }
+}
+
+/**
+    register with a validation of written values, see [Slave::with_validators]
+    
+    The validation function receives the new register content, serialized as it is in the slave buffer (big endian). Writes covering only part of the register are always rejected.
+*/
+#[derive(Copy, Clone)]
+pub struct RegisterSpec {
+    /// start of the register in slave memory
+    pub address: SlaveSize,
+    /// size of the register in bytes
+    pub size: SlaveSize,
+    /// return true if the given register content is allowed
+    pub validate: fn(&[u8]) -> bool,
+}
+impl RegisterSpec {
+    pub const fn new<T: FromBytes>(register: SlaveRegister<T>, validate: fn(&[u8]) -> bool) -> Self {
+        Self {address: register.address(), size: register.size(), validate}
+    }
+}
+
+/**
+    register computed on demand when read by the master, see [Slave::with_computed]
+    
+    The compute function receives the register content, serialized as it is in the slave buffer (big endian), and overwrites it with the current value.
+*/
+#[derive(Copy, Clone)]
+pub struct ComputedRegister {
+    /// start of the register in slave memory
+    pub address: SlaveSize,
+    /// size of the register in bytes
+    pub size: SlaveSize,
+    /// write the current register content
+    pub compute: fn(&mut [u8]),
+}
+impl ComputedRegister {
+    pub const fn new<T: FromBytes>(register: SlaveRegister<T>, compute: fn(&mut [u8])) -> Self {
+        Self {address: register.address(), size: register.size(), compute}
+    }
+    fn range(&self) -> Range<usize> {
+        usize::from(self.address) .. usize::from(self.address) + usize::from(self.size)
+    }
+}
+
+/// memory segment of a slave with its own lock, see [Slave::with_segments]
+pub trait Segment: Sync {
+    /// range of slave addresses covered by this segment
+    fn range(&self) -> Range<usize>;
+    /// run the given access on the segment's bytes if it is not locked, return false if it is locked
+    fn try_access(&self, access: &mut dyn FnMut(&mut [u8])) -> bool;
+}
+/** 
+    memory segment of `N` bytes starting at a given slave address, see [Slave::with_segments]
+    
+    it is meant to be placed in a static, so it can be shared between the slave and the user tasks. Its start can be beyond 16 bit addresses, to be reached with the `extended-address` feature
+*/
+pub struct SlaveSegment<const N: usize> {
+    start: usize,
+    buffer: BusyMutex<SegmentBuffer<N>>,
+}
+/// start address of a [SlaveSegment], beyond 16 bits only with the `extended-address` feature
+#[cfg(feature = "extended-address")]
+pub type SegmentAddress = u32;
+/// start address of a [SlaveSegment], beyond 16 bits only with the `extended-address` feature
+#[cfg(not(feature = "extended-address"))]
+pub type SegmentAddress = SlaveSize;
+/// buffer of a [SlaveSegment], accessed using absolute slave addresses
+pub struct SegmentBuffer<const N: usize> {
+    start: usize,
+    buffer: [u8; N],
+}
+impl<const N: usize> SlaveSegment<N> {
+    pub const fn new(start: SegmentAddress) -> Self {
+        // widening conversion, `From` is not usable in const functions
+        let start = start as usize;
+        Self {
+            start,
+            buffer: BusyMutex::new(SegmentBuffer {start, buffer: [0; N]}),
+        }
+    }
+    /// wait until getting access to the segment's buffer
+    pub async fn lock(&self) -> BusyMutexGuard<'_, SegmentBuffer<N>> {self.buffer.lock().await}
+    /// try to get access to the segment's buffer, immediately abort if the buffer is being used by other tasks
+    pub fn try_lock(&self) -> Option<BusyMutexGuard<'_, SegmentBuffer<N>>> {self.buffer.try_lock()}
+}
+impl<const N: usize> Segment for SlaveSegment<N> {
+    fn range(&self) -> Range<usize> {
+        self.start .. self.start + N
+    }
+    fn try_access(&self, access: &mut dyn FnMut(&mut [u8])) -> bool {
+        match self.buffer.try_lock() {
+            Some(mut buffer) => {
+                access(&mut buffer.buffer);
+                true
+            },
+            None => false,
+        }
+    }
+}
+impl<const N: usize> SegmentBuffer<N> {
+    /// get the current register's value, the register must be in this segment. Segments beyond 16 bit addresses are accessed as bytes instead
+    pub fn get<T: FromBytes>(&self, register: SlaveRegister<T>) -> T {
+        let mut dst = T::Bytes::zeroed();
+        dst.as_mut().copy_from_slice(&self.buffer[usize::from(register.address()) - self.start ..][.. T::Bytes::SIZE]);
+        T::from_be_bytes(dst)
+    }
+    /// set the given register's value, the register must be in this segment
+    pub fn set<T: ToBytes>(&mut self, register: SlaveRegister<T>, value: T) {
+        let src = value.to_be_bytes();
+        self.buffer[usize::from(register.address()) - self.start ..][.. T::Bytes::SIZE].copy_from_slice(src.as_ref());
+    }
+}
+impl<const N: usize> Deref for SegmentBuffer<N> {
+    type Target = [u8; N];
+    fn deref(&self) -> &Self::Target {
+        &self.buffer
+    }
+}
+impl<const N: usize> DerefMut for SegmentBuffer<N> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buffer
+    }
+}
+
+/// adapter allowing to run a slave on a blocking bus, see [Slave::poll_once]
+pub struct BlockingBus<B>(pub B);
+
+impl<B: embedded_io::ErrorType> ErrorType for BlockingBus<B> {
+    type Error = B::Error;
+}
+impl<B: embedded_io::Read> Read for BlockingBus<B> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.0.read(buf)
+    }
+}
+impl<B: embedded_io::Write> Write for BlockingBus<B> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.0.write(buf)
+    }
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.0.flush()
+    }
+}
+
+/**
+    adapter allowing to run a slave on a tokio stream, like a TCP socket or a serial port on a host
+    
+    a reader and a writer can be combined with `tokio::io::join`
+*/
+#[cfg(feature = "tokio-io")]
+pub struct TokioBus<B>(pub B);
+
+#[cfg(feature = "tokio-io")]
+impl<B> ErrorType for TokioBus<B> {
+    type Error = std::io::Error;
+}
+#[cfg(feature = "tokio-io")]
+impl<B: tokio::io::AsyncRead + Unpin> Read for TokioBus<B> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        tokio::io::AsyncReadExt::read(&mut self.0, buf).await
+    }
+}
+#[cfg(feature = "tokio-io")]
+impl<B: tokio::io::AsyncWrite + Unpin> Write for TokioBus<B> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        tokio::io::AsyncWriteExt::write(&mut self.0, buf).await
+    }
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        tokio::io::AsyncWriteExt::flush(&mut self.0).await
+    }
+}
+
 impl<const MEM: usize> SlaveBuffer<MEM> {
     /// get the current register's value
     pub fn get<T: FromBytes>(&self, register: SlaveRegister<T>) -> T {
@@ -97,15 +448,52 @@ impl<const MEM: usize> SlaveBuffer<MEM> {
         let src = value.to_be_bytes();
         self.buffer[usize::try_from(register.address()).unwrap() ..][.. T::Bytes::SIZE].copy_from_slice(src.as_ref());
     }
+    /**
+        set the given register's value, only if it is a user register
+        
+        this is rejecting registers in the standard section of the buffer (before [registers::USER]) and registers exceeding the buffer
+    */
+    pub fn try_set<T: ToBytes>(&mut self, register: SlaveRegister<T>, value: T) -> Result<(), registers::CommandError> {
+        let start = usize::from(register.address());
+        if start < registers::USER
+            {return Err(registers::CommandError::InvalidAccess)}
+        if start + T::Bytes::SIZE > MEM
+            {return Err(registers::CommandError::InvalidRegister)}
+        self.set(register, value);
+        Ok(())
+    }
     /// set current command error, if not already set
     fn set_error(&mut self, error: registers::CommandError) {
         if self.get(registers::ERROR) == registers::CommandError::None {
             self.set(registers::ERROR, error);
         }
     }
-    fn add_loss(&mut self) {
+    /// count a lost command, return the error to report if this reaches the loss threshold
+    fn add_loss(&mut self, reason: LossReason) -> Result<(), registers::CommandError> {
+        warn!("command lost: {:?}", reason);
+        let mut reasons = self.get(registers::LOSS_REASONS);
+        let counter = match reason {
+            LossReason::Bus => &mut reasons.bus,
+            LossReason::Checksum => &mut reasons.checksum,
+            LossReason::Oversized => &mut reasons.oversized,
+        };
+        *counter = counter.saturating_add(1);
+        self.set(registers::LOSS_REASONS, reasons);
+        
         let count = self.get(registers::LOSS);
-        self.set(registers::LOSS, count.saturating_add(1));
+        match self.loss {
+            LossPolicy::Saturate => self.set(registers::LOSS, count.saturating_add(1)),
+            LossPolicy::Wrap => self.set(registers::LOSS, count.wrapping_add(1)),
+            LossPolicy::Threshold(threshold) => {
+                if count.saturating_add(1) >= threshold {
+                    self.set(registers::LOSS, 0);
+                    self.set_error(registers::CommandError::LossThreshold);
+                    return Err(registers::CommandError::LossThreshold)
+                }
+                self.set(registers::LOSS, count + 1);
+            },
+        }
+        Ok(())
     }
 }
 impl<const MEM: usize> Deref for SlaveBuffer<MEM> {
@@ -120,47 +508,153 @@ impl<const MEM: usize> DerefMut for SlaveBuffer<MEM> {
     }
 }
 
-impl<B: Read + Write> SlaveControl<B> {
+/// enables the RS-485 driver while alive, if controlled, so it is released whatever way the transmission ends
+struct Driver(Option<fn(bool)>);
+impl Driver {
+    fn enable(control: Option<fn(bool)>) -> Self {
+        if let Some(control) = control {
+            control(true);
+        }
+        Self(control)
+    }
+}
+impl Drop for Driver {
+    fn drop(&mut self) {
+        if let Some(control) = self.0 {
+            control(false);
+        }
+    }
+}
+
+/*
+    The slave buffer lock is not reentrant, so the bus task must never lock it while already holding it.
+    Every function below takes the lock in a scope ending before returning or awaiting another lock, segments included. Hence the lock taken in `receive_command` to report an error is never nested in the one of `process_command`.
+*/
+impl<B: Read + Write, const FRAME: usize, const MAP: usize> SlaveControl<B, FRAME, MAP> {
     /// process one command on the bus, block until a command is found and executed
-    async fn receive_command<const MEM: usize>(&mut self, slave: &Slave<B, MEM>) -> Result<(), B::Error> {
+    async fn receive_command<const MEM: usize>(&mut self, slave: &Slave<B, MEM, FRAME, MAP>) -> Result<(), B::Error> {
         let recv_header = self.catch_header().await?;
         let size = usize::from(recv_header.size);
-        if size > MAX_COMMAND {
-            return Ok(());
+        if size > FRAME {
+            return self.relay_oversized(slave, recv_header).await;
         }
         // receive data
         no_eof(self.bus.read_exact(&mut self.receive[..size]).await)?;
+        let mut trailer = [0; TRAILER];
+        no_eof(self.bus.read_exact(&mut trailer).await)?;
         // try to process it
+        self.intact = frame_crc(header_crc(&recv_header), &self.receive[..size]).to_be_bytes()[.. TRAILER] == trailer;
         self.send_header = recv_header.clone();
         if let Err(err) = self.process_command(slave, recv_header).await {
+            // all locks of the processing are released at this point
             slave.lock().await.set_error(err);
-            self.send_header.access.set_error(true);
+            self.send_header.access.set_command_error(err);
         }
         // transmit anyway
+        let _driver = Driver::enable(self.direction);
         let header = self.send_header.to_be_bytes();
         self.bus.write_all(&header).await?;
         self.bus.write_all(&checksum(&header).to_be_bytes()).await?;
         self.bus.write_all(&self.send[.. size]).await?;
+        if TRAILER != 0 {
+            // a frame received corrupted is transmitted corrupted, so the master knows
+            let mut crc = frame_crc(header_crc(&self.send_header), &self.send[.. size]);
+            if !self.intact {
+                crc = !crc;
+            }
+            self.bus.write_all(&crc.to_be_bytes()[.. TRAILER]).await?;
+        }
+        self.end_transmission().await
+    }
+    /// wait for the frame to be transmitted, before the RS-485 driver is released
+    async fn end_transmission(&mut self) -> Result<(), B::Error> {
+        if self.direction.is_some() {
+            self.bus.flush().await?;
+        }
         Ok(())
     }
+    /**
+        pass a command too big for this slave's buffers to the next slave, so the bus stays aligned on frames
+        
+        its data is relayed unchanged in chunks. If the command was addressed to this slave, it is counted as a loss and reported with [registers::CommandError::InvalidSize]
+    */
+    async fn relay_oversized<const MEM: usize>(&mut self, slave: &Slave<B, MEM, FRAME, MAP>, recv_header: Command) -> Result<(), B::Error> {
+        let mut header = recv_header;
+        // extended commands carry a register address in place of the slave index
+        if header.access.topological() && !header.access.fixed() {
+            header.address.set_slave(header.address.slave().wrapping_sub(1));
+        }
+        let addressed = if header.access.fixed() && header.access.topological() 
+            {self.selected}
+        else if header.access.fixed()
+            {recv_header.address.slave() == self.address && self.address != registers::UNASSIGNED}
+        else if header.access.topological()
+            {recv_header.address.slave() == 0}
+        else 
+            {self.mapping[self.concerned(recv_header)].iter().any(|&mapped| map_frame_slave(mapped, recv_header).is_some())};
+        if addressed {
+            let mut buffer = slave.lock().await;
+            buffer.add_loss(LossReason::Oversized).ok();
+            buffer.set_error(registers::CommandError::InvalidSize);
+            header.access.set_command_error(registers::CommandError::InvalidSize);
+        }
+        let bytes = header.to_be_bytes();
+        let _driver = Driver::enable(self.direction);
+        self.bus.write_all(&bytes).await?;
+        self.bus.write_all(&checksum(&bytes).to_be_bytes()).await?;
+        let mut remain = usize::from(header.size);
+        let (mut received, mut sent) = (header_crc(&recv_header), header_crc(&header));
+        while remain != 0 {
+            let chunk = remain.min(FRAME);
+            no_eof(self.bus.read_exact(&mut self.receive[.. chunk]).await)?;
+            self.bus.write_all(&self.receive[.. chunk]).await?;
+            received = frame_crc(received, &self.receive[.. chunk]);
+            sent = frame_crc(sent, &self.receive[.. chunk]);
+            remain -= chunk;
+        }
+        // the header changed, so the trailer is recomputed, and kept wrong if it was received wrong
+        let mut trailer = [0; TRAILER];
+        no_eof(self.bus.read_exact(&mut trailer).await)?;
+        if received.to_be_bytes()[.. TRAILER] != trailer {
+            sent = !sent;
+        }
+        self.bus.write_all(&sent.to_be_bytes()[.. TRAILER]).await?;
+        self.end_transmission().await
+    }
+    /// whether the data received for the given command does not match its checksum or the frame trailer
+    fn corrupted(&self, header: Command) -> bool {
+        !self.intact || header.checksum != checksum(&self.receive[.. usize::from(header.size)])
+    }
     /// wait until a command header is found
     async fn catch_header(&mut self) -> Result<Command, B::Error> {
-        const HEADER: usize = <Command as FromBytes>::Bytes::SIZE;
         // receive an amount that can be a header and its checksum
         no_eof(self.bus.read_exact(&mut self.receive[.. HEADER+1]).await)?;
-        // loop until checksum is good to catch up new command
-        while checksum(&self.receive[.. HEADER]) != self.receive[HEADER] {
-            self.receive[.. HEADER+1].rotate_left(1);
-            no_eof(self.bus.read_exact(&mut self.receive[HEADER .. HEADER+1]).await)?;
+        // loop until a header is decoded to catch up new command
+        loop {
+            match decode_frame(&self.receive[.. HEADER+1]) {
+                DecodeOutcome::Frame(header, _) | DecodeOutcome::Partial(header, _) => {
+                    if let Some(clock) = self.clock {
+                        self.arrival = clock();
+                    }
+                    break Ok(header)
+                },
+                DecodeOutcome::Skip(skip) => {
+                    self.receive[.. HEADER+1].rotate_left(skip);
+                    no_eof(self.bus.read_exact(&mut self.receive[HEADER+1-skip .. HEADER+1]).await)?;
+                },
+                DecodeOutcome::Incomplete(_) => unreachable!("a header is always received"),
+            }
         }
-        Ok(Command::from_be_bytes(self.receive[.. HEADER].try_into().unwrap()))
     }
     /// execute a given command is this slaved is concerned
-    async fn process_command<const MEM: usize>(&mut self, slave: &Slave<B, MEM>, recv_header: Command) -> Result<(), registers::CommandError> {
+    async fn process_command<const MEM: usize>(&mut self, slave: &Slave<B, MEM, FRAME, MAP>, recv_header: Command) -> Result<(), registers::CommandError> {
         let size = usize::from(recv_header.size);
         
         // check command consistency
         if recv_header.access.fixed() && recv_header.access.topological() {
+            #[cfg(feature = "extended-address")]
+            return self.process_extended(slave, recv_header).await;
+            #[cfg(not(feature = "extended-address"))]
             return Err(registers::CommandError::InvalidCommand);
         }
         // logic for topologial addresses
@@ -169,31 +663,42 @@ impl<B: Read + Write> SlaveControl<B> {
             self.send_header.address.set_slave(slave.wrapping_sub(1));
         }
         // direct access to slave buffer
-        if recv_header.access.fixed() && recv_header.address.slave() == self.address
+        if recv_header.access.fixed() && recv_header.address.slave() == self.address && self.address != registers::UNASSIGNED
         || recv_header.access.topological() && recv_header.address.slave() == 0 
         {
             // check data integrity, only useful if data was expected
-            if recv_header.access.write() && recv_header.checksum != checksum(&self.receive[..size]) {
-                slave.buffer.lock().await.add_loss();
-                return Ok(());
+            if recv_header.access.write() && self.corrupted(recv_header) {
+                self.send[..size] .copy_from_slice(&self.receive[..size]);
+                slave.buffer.lock().await.add_loss(LossReason::Checksum)?;
+                return Err(registers::CommandError::Checksum);
             }
             // exchange requested chunk of data
             // mark the command executed
             self.send_header.executed += 1;
-            return self.exchange_slave(slave, recv_header).await;
+            if recv_header.access.operation() == Some(Operation::CompareExchange) {
+                return self.compare_exchange_slave(slave, recv_header).await;
+            }
+            return self.exchange_slave(slave, recv_header, usize::from(recv_header.address.register())).await;
         }
         // access to bus virtual memory
         else if !recv_header.access.fixed() && !recv_header.access.topological() {
-            // check data integrity, only useful if data was expected
-            if recv_header.access.write() && recv_header.checksum != checksum(&self.receive[..size]) {
-                slave.buffer.lock().await.add_loss();
+            // frames not concerning this slave at all are passed without locking anything, nor checking them
+            let concerned = self.concerned(recv_header);
+            if !self.mapping[concerned.clone()].iter().any(|&mapped| map_frame_slave(mapped, recv_header).is_some()) {
+                self.send[..size] .copy_from_slice(&self.receive[..size]);
+                self.send_header.executed += 1;
                 return Ok(());
             }
+            // check data integrity, only useful if data was expected
+            if recv_header.access.write() && self.corrupted(recv_header) {
+                self.send[..size] .copy_from_slice(&self.receive[..size]);
+                slave.buffer.lock().await.add_loss(LossReason::Checksum)?;
+                return Err(registers::CommandError::Checksum);
+            }
             // exchange data according to local mapping
             // mark the command executed
             self.send_header.executed += 1;
-            self.exchange_virtual(slave, recv_header).await;
-            return Ok(());
+            return self.exchange_virtual(slave, recv_header, concerned).await;
         }
         // any other command
         else {
@@ -202,77 +707,252 @@ impl<B: Read + Write> SlaveControl<B> {
             return Ok(());
         }
     }
-    /// exchange directly with slave buffer, executing special operations on reading and writing special registers
-    async fn exchange_slave<const MEM: usize>(&mut self, slave: &Slave<B, MEM>, header: Command) -> Result<(), registers::CommandError> {
+    /// execute a command with extended addressing if this slave is selected, see [Access::extended]
+    #[cfg(feature = "extended-address")]
+    async fn process_extended<const MEM: usize>(&mut self, slave: &Slave<B, MEM, FRAME, MAP>, recv_header: Command) -> Result<(), registers::CommandError> {
+        let size = usize::from(recv_header.size);
+        self.send[..size] .copy_from_slice(&self.receive[..size]);
+        if !self.selected
+            {return Ok(())}
+        if recv_header.access.operation() != Some(Operation::Access)
+            {return Err(registers::CommandError::InvalidCommand)}
+        let Some(start) = usize::try_from(u32::from(recv_header.address)).ok()
+            .filter(|start| start.checked_add(size).is_some())
+            else {return Err(registers::CommandError::InvalidRegister)};
+        // check data integrity, only useful if data was expected
+        if recv_header.access.write() && self.corrupted(recv_header) {
+            slave.buffer.lock().await.add_loss(LossReason::Checksum)?;
+            return Err(registers::CommandError::Checksum);
+        }
+        self.send_header.executed += 1;
+        self.exchange_slave(slave, recv_header, start).await
+    }
+    /**
+        exchange directly with slave buffer and segments, executing special operations on reading and writing special registers
+        
+        A command both reading and writing is an atomic fetch-and-store on the slave buffer: under one lock, `on_read` refreshes the registers read, the old content is copied to the answer, the new content is written, and then `on_write` reacts to it. Writes rejected by validation (including computed registers) are rejected before any of these steps.
+    */
+    async fn exchange_slave<const MEM: usize>(&mut self, slave: &Slave<B, MEM, FRAME, MAP>, header: Command, start: usize) -> Result<(), registers::CommandError> {
         // get memory range in slave buffer
         let size = usize::from(header.size);
-        let register = header.address.register();
+        let range = start .. start + size;
+        let (read, write) = (header.access.read(), header.access.write());
+        // rejected commands are answered with their own data, and reads overwrite it
+        self.send[..size] .copy_from_slice(&self.receive[..size]);
         
-        // request specifically addressed to this slave is always locking its buffer
-        {
+        if !self.covered(range.clone(), MEM) {
+            warn!("invalid size");
+            return Err(registers::CommandError::InvalidRegister);
+        }
+        if write && !self.validate(range.start, &self.receive[..size]) {
+            return Err(registers::CommandError::InvalidAccess);
+        }
+        
+        // part in slave buffer
+        let main = range.start .. range.end.min(MEM);
+        if !main.is_empty() {
+            let local = main.start - range.start .. main.end - range.start;
             // lock slave's buffer only once
             let mut buffer = slave.buffer.lock().await;
-            
-            if usize::from(register).saturating_add(size) > buffer.len() {
-                warn!("invalid size");
-                return Err(registers::CommandError::InvalidRegister);
-            }
-            
-            // read buffer before writing it
-            if header.access.read() {
-                self.on_read(&mut buffer, register);
-                self.send[..size] .copy_from_slice(&buffer[usize::from(register) ..][.. size]);
-                self.send_header.checksum = checksum(&self.send[..size]);
+            // read buffer before writing it, so exchanges answer the previous content (like the previous fixed address)
+            if read {
+                self.on_read(&mut buffer, main.clone());
+                self.send[local.clone()] .copy_from_slice(&buffer[main.clone()]);
             }
-            else {
-                self.send[..size] .copy_from_slice(&self.receive[..size]);
+            if write {
+                buffer[main] .copy_from_slice(&self.receive[local]);
+                // standard registers all have 16 bit addresses
+                if let Ok(register) = u16::try_from(range.start) {
+                    self.on_write(&mut buffer, register);
+                }
             }
-            if header.access.write() {
-                buffer[usize::from(register) ..][.. size] .copy_from_slice(&self.receive[..size]);
+        }
+        // parts in segments, locking each one after the other
+        for segment in self.segments {
+            let covered = segment.range();
+            let common = range.start.max(covered.start) .. range.end.min(covered.end);
+            if common.is_empty() 
+                {continue}
+            let local = common.start - range.start .. common.end - range.start;
+            let inner = common.start - covered.start .. common.end - covered.start;
+            let (send, receive) = (&mut self.send, &self.receive);
+            access_segment(*segment, |data| {
+                if read {
+                    send[local.clone()] .copy_from_slice(&data[inner.clone()]);
+                }
+                if write {
+                    data[inner.clone()] .copy_from_slice(&receive[local.clone()]);
+                }
+            }).await;
+        }
+        if read {
+            self.send_header.checksum = checksum(&self.send[..size]);
+        }
+        Ok(())
+    }
+    /// compare a register with the expected value and write the new value if equal, see [Operation::CompareExchange]
+    async fn compare_exchange_slave<const MEM: usize>(&mut self, slave: &Slave<B, MEM, FRAME, MAP>, header: Command) -> Result<(), registers::CommandError> {
+        let size = usize::from(header.size);
+        let half = size / 2;
+        let register = header.address.register();
+        let range = usize::from(register) .. usize::from(register) + half;
+        // answer the new value, the current value is answered in place of the expected one
+        self.send[..size] .copy_from_slice(&self.receive[..size]);
+        
+        if !self.validate(range.start, &self.receive[half .. size]) {
+            return Err(registers::CommandError::InvalidAccess);
+        }
+        // the register must be in one piece of memory, so it is compared and written under one lock
+        if range.end <= MEM {
+            let mut buffer = slave.buffer.lock().await;
+            self.on_read(&mut buffer, range.clone());
+            self.send[.. half] .copy_from_slice(&buffer[range.clone()]);
+            if buffer[range.clone()] == self.receive[.. half] {
+                buffer[range] .copy_from_slice(&self.receive[half .. size]);
                 self.on_write(&mut buffer, register);
             }
         }
+        else if let Some(segment) = self.segments.iter().find(|segment| 
+                segment.range().start <= range.start && range.end <= segment.range().end) {
+            let inner = range.start - segment.range().start .. range.end - segment.range().start;
+            let (send, receive) = (&mut self.send, &self.receive);
+            access_segment(*segment, |data| {
+                send[.. half] .copy_from_slice(&data[inner.clone()]);
+                if data[inner.clone()] == receive[.. half] {
+                    data[inner.clone()] .copy_from_slice(&receive[half .. size]);
+                }
+            }).await;
+        }
+        else {
+            return Err(registers::CommandError::InvalidRegister);
+        }
+        self.send_header.checksum = checksum(&self.send[..size]);
         Ok(())
     }
+    /// range of mappings that may intersect the requested area, some may still not intersect it
+    fn concerned(&self, header: Command) -> Range<usize> {
+        // mappings are only sorted by start, so the lower bound is the first that could end in the requested area given the maximum mapping size
+        let start = bisect_slice(&self.mapping, |item| item.virtual_start.saturating_add(u32::from(SlaveSize::MAX)) > u32::from(header.address));
+        // upper bound is the first that starts after requested area
+        let stop = start + bisect_slice(&self.mapping[start ..], |item| item.virtual_start >= u32::from(header.address).saturating_add(u32::from(header.size)));
+        start .. stop
+    }
     /// iterate over mappings inside the requested area and exchange with registers
-    async fn exchange_virtual<const MEM: usize>(&mut self, slave: &Slave<B, MEM>, header: Command) {
-        // get concerned mapping
+    /// the mappings failing validation are skipped and reported after all others are exchanged
+    async fn exchange_virtual<const MEM: usize>(&mut self, slave: &Slave<B, MEM, FRAME, MAP>, header: Command, concerned: Range<usize>) -> Result<(), registers::CommandError> {
         let size = usize::from(header.size);
-        // lower bound os the first that ends in the requested area
-        let start = bisect_slice(&self.mapping, |item| item.virtual_start + u32::from(item.size) > u32::from(header.address));
-        // upper bound is the first that starts after requested area
-        let stop = bisect_slice(&self.mapping[start ..], |item| item.virtual_start > u32::from(header.address) + u32::from(header.size));
+        let (read, write) = (header.access.read(), header.access.write());
+        let (start, stop) = (concerned.start, concerned.end);
         
         // transmit all unless altered by mapping
         self.send[..size] .copy_from_slice(&self.receive[..size]);
+        let mut result = Ok(());
+        
+        // only lock if concerned by this frame
+        if stop <= start 
+            {return result}
+        
+        // validate before locking, so the user task only waits for the copies. Mappings are at most 128 so one bit each is enough
+        let mut rejected = 0u128;
+        if write {
+            for (index, &mapped) in self.mapping[start .. stop].iter().enumerate() {
+                if let Some((src, dst)) = map_frame_slave(mapped, header)
+                && !self.validate(dst.start, &self.receive[src]) {
+                    rejected |= 1 << index;
+                    result = Err(registers::CommandError::InvalidAccess);
+                }
+            }
+        }
         
-        // only lock if concerned by this frame (frames not concerning this slave at all will never lock the slave task)
-        if stop > start {
+        // mappings in slave buffer
+        if self.mapping[start .. stop].iter().any(|mapped| usize::from(mapped.slave_start) < MEM) {
             // lock slave's buffer only once
             let mut buffer = slave.buffer.lock().await;
             
             // read buffer before writing it
-            if header.access.read() {
+            if read {
                 for &mapped in &self.mapping[start .. stop] {
+                    if usize::from(mapped.slave_start) >= MEM
+                        {continue}
                     if let Some((dst, src)) = map_frame_slave(mapped, header) {
+                        self.on_read(&mut buffer, src.clone());
                         self.send[dst].copy_from_slice(&buffer[src]);
                     }
                 }
-                self.send_header.checksum = checksum(&self.send[..size]);
             }
-            if header.access.write() {
-                for &mapped in &self.mapping[start .. stop] {
+            if write {
+                for (index, &mapped) in self.mapping[start .. stop].iter().enumerate() {
+                    if usize::from(mapped.slave_start) >= MEM || rejected & (1 << index) != 0
+                        {continue}
                     if let Some((src, dst)) = map_frame_slave(mapped, header) {
                         buffer[dst].copy_from_slice(&self.receive[src]);
                     }
                 }
             }
         }
+        // mappings in segments, locking each one after the other
+        for (index, &mapped) in self.mapping[start .. stop].iter().enumerate() {
+            if usize::from(mapped.slave_start) < MEM
+                {continue}
+            let Some((frame, local)) = map_frame_slave(mapped, header)
+                else {continue};
+            let Some(segment) = self.segments.iter().find(|segment| segment.range().contains(&local.start))
+                else {continue};
+            let inner = local.start - segment.range().start .. local.end - segment.range().start;
+            let valid = rejected & (1 << index) == 0;
+            let (send, receive) = (&mut self.send, &self.receive);
+            access_segment(*segment, |data| {
+                if read {
+                    send[frame.clone()].copy_from_slice(&data[inner.clone()]);
+                }
+                if write && valid {
+                    data[inner.clone()].copy_from_slice(&receive[frame.clone()]);
+                }
+            }).await;
+        }
+        if read {
+            self.send_header.checksum = checksum(&self.send[..size]);
+        }
+        result
     }
     
+    /// check that the given range is fully covered by the slave buffer and segments
+    fn covered(&self, range: Range<usize>, mem: usize) -> bool {
+        let mut count = range.end.min(mem).saturating_sub(range.start);
+        for segment in self.segments {
+            let covered = segment.range();
+            count += range.end.min(covered.end).saturating_sub(range.start.max(covered.start));
+        }
+        count == range.len()
+    }
+    /// check that the given data written at the given address is passing all validators
+    fn validate(&self, address: usize, data: &[u8]) -> bool {
+        let written = address .. address + data.len();
+        // read-only registers
+        if registers::STANDARD.iter().any(|meta| meta.read_only && overlaps(&written, &meta.range()))
+        || self.computed.iter().any(|computed| overlaps(&written, &computed.range()))
+            {return false}
+        self.validators.iter().all(|spec| {
+            let register = usize::from(spec.address) .. usize::from(spec.address) + usize::from(spec.size);
+            // register not concerned by this write
+            if register.end <= written.start || written.end <= register.start
+                {return true}
+            // register must be fully written
+            if register.start < written.start || written.end < register.end
+                {return false}
+            (spec.validate)(&data[register.start - written.start ..][.. register.len()])
+        })
+    }
     /// special actions when reading special registers
-    fn on_read<const MEM: usize>(&mut self, _buffer: &mut SlaveBuffer<MEM>, _address: u16) {
-        // TODO clock interrogation
+    fn on_read<const MEM: usize>(&self, buffer: &mut SlaveBuffer<MEM>, range: Range<usize>) {
+        if self.clock.is_some() && overlaps(&range, &register_range(registers::CLOCK)) {
+            buffer.set(registers::CLOCK, self.arrival);
+        }
+        for computed in self.computed {
+            if overlaps(&range, &computed.range()) {
+                (computed.compute)(&mut buffer[computed.range()]);
+            }
+        }
     }
     
     /// special actions when writing special registers
@@ -280,27 +960,85 @@ impl<B: Read + Write> SlaveControl<B> {
         if address == registers::ADDRESS.address() {
             self.address = buffer.get(registers::ADDRESS);
         }
-        else if address == registers::MAPPING.address() {
-            let table = buffer.get(registers::MAPPING);
-            self.mapping.clear();
-            self.mapping.extend(
-                table.map[.. usize::from(table.size)]
-                .iter().cloned().filter(|mapping|  mapping.size != 0)
-                );
-            self.mapping.sort_unstable_by_key(|item| item.virtual_start);
-            for mapped in &self.mapping {
-                if usize::from(mapped.slave_start + mapped.size) > buffer.len()
-                || usize::from(mapped.slave_start) > buffer.len()
-                || u32::MAX - mapped.virtual_start < u32::from(mapped.size) {
-                    buffer.set_error(registers::CommandError::InvalidMapping);
-                    // TODO set the error flag in the header
-                }
+        // the table is only applied when its size is written, so the entries can be written before in several commands
+        else if address == registers::MAPPING.address() 
+        && !self.set_mapping(&buffer.get(registers::MAPPING), MEM) {
+            buffer.set_error(registers::CommandError::InvalidMapping);
+            // TODO set the error flag in the header
+        }
+        else if address == registers::CONTROL.address() {
+            let control = buffer.get(registers::CONTROL);
+            if control & registers::CONTROL_RESET != 0 {
+                buffer.set(registers::LOSS, 0);
+                buffer.set(registers::LOSS_REASONS, registers::LossReasons::default());
+                buffer.set(registers::ERROR, registers::CommandError::None);
+                buffer.set(registers::MAPPING, registers::MappingTable::default());
+                buffer.set(registers::ADDRESS, registers::UNASSIGNED);
+                self.mapping.clear();
+                self.address = registers::UNASSIGNED;
+                #[cfg(feature = "extended-address")]
+                buffer.set(registers::SELECT, 0);
+                self.selected = false;
             }
+            buffer.set(registers::CONTROL, 0);
+        }
+        #[cfg(feature = "extended-address")]
+        if address == registers::SELECT.address() {
+            self.selected = buffer.get(registers::SELECT) != 0;
         }
     }
+    /// replace the current mapping by the given table, return false if some mappings were invalid and dropped
+    fn set_mapping(&mut self, table: &registers::MappingTable, size: usize) -> bool {
+        self.mapping.clear();
+        let entries = table.map[.. usize::from(table.size).min(table.map.len())]
+            .iter().cloned().filter(|mapping|  mapping.size != 0);
+        // a table bigger than the capacity cannot be partially applied
+        if entries.clone().count() > MAP
+            {return false}
+        self.mapping.extend(entries);
+        // stable sort, so mappings with the same start are applied in table order
+        for i in 1 .. self.mapping.len() {
+            let mut j = i;
+            while j > 0 && self.mapping[j-1].virtual_start > self.mapping[j].virtual_start {
+                self.mapping.swap(j-1, j);
+                j -= 1;
+            }
+        }
+        // invalid mappings are dropped so they can never be exchanged
+        let count = self.mapping.len();
+        let segments = self.segments;
+        self.mapping.retain(|mapped| valid_mapping(mapped, size, segments));
+        self.mapping.len() == count
+    }
 }
 
 
+/// memory range of a register in the slave buffer
+fn register_range<T: FromBytes>(register: SlaveRegister<T>) -> Range<usize> {
+    usize::from(register.address()) .. usize::from(register.address()) + usize::from(register.size())
+}
+/// true if the given ranges have common elements
+fn overlaps(a: &Range<usize>, b: &Range<usize>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+/// wait until the given segment is free and run the given access on its bytes
+async fn access_segment(segment: &dyn Segment, mut access: impl FnMut(&mut [u8])) {
+    poll_fn(|_| {
+        if segment.try_access(&mut access) 
+            {Poll::Ready(())}
+        else 
+            {Poll::Pending}
+        }).await
+}
+/// busy poll a future until completion, for use in blocking contexts
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut context = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(result) = future.as_mut().poll(&mut context) 
+            {break result}
+    }
+}
 /// simple helper unwrapping eof because they should not appear in bare metal uart, at least in esp32 hal
 fn no_eof<T, E>(result: Result<T, ReadExactError<E>>) -> Result<T, E> {
     result.map_err(|e| match e {
@@ -308,8 +1046,24 @@ fn no_eof<T, E>(result: Result<T, ReadExactError<E>>) -> Result<T, E> {
         ReadExactError::Other(io) => io,
         })
 }
+/// state of [frame_crc] after the given header and its checksum
+fn header_crc(header: &Command) -> u16 {
+    let bytes = header.to_be_bytes();
+    frame_crc(frame_crc(CRC_SEED, &bytes), &[checksum(&bytes)])
+}
+/** 
+    check that a mapping only covers user registers in a buffer of the given size, or in one of the given segments
+    
+    mappings to the standard section of the buffer are not allowed, because a virtual write could then corrupt the slave config
+*/
+fn valid_mapping(mapped: &registers::Mapping, size: usize, segments: &[&dyn Segment]) -> bool {
+    let range = usize::from(mapped.slave_start) .. usize::from(mapped.slave_start) + usize::from(mapped.size);
+    (range.start >= registers::USER && range.end <= size
+        || segments.iter().any(|segment| segment.range().start <= range.start && range.end <= segment.range().end))
+    && mapped.virtual_start.checked_add(u32::from(mapped.size)).is_some()
+}
 /// bisect a slice to find the first `i` at which `threshold(slice[i])` is True
-fn bisect_slice<T>(slice: &[T], threshold: impl Fn(&T) -> bool) -> usize {
+pub(crate) fn bisect_slice<T>(slice: &[T], threshold: impl Fn(&T) -> bool) -> usize {
     let (mut start, mut end) = (0, slice.len());
     while start < end {
         let mid = (start + end)/2;
@@ -325,33 +1079,996 @@ fn bisect_slice<T>(slice: &[T], threshold: impl Fn(&T) -> bool) -> usize {
 /** 
     return matching ranges in frame data buffer and slave buffer according to the given mapping
     
-    result is a couple (in frame, in slave)
+    result is a couple (in frame, in slave), see [registers::intersect_mapping]
 */
-fn map_frame_slave(mapped: registers::Mapping, frame: Command) -> Option<(Range<usize>, Range<usize>)> {
+pub(crate) fn map_frame_slave(mapped: registers::Mapping, frame: Command) -> Option<(Range<usize>, Range<usize>)> {
     let address = u32::from(frame.address);
-    let virtual_range = Range {
-        start: mapped.virtual_start,
-        end: mapped.virtual_start + u32::from(mapped.size),
+    registers::intersect_mapping(mapped, address .. address.saturating_add(u32::from(frame.size)))
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::{vec::Vec, collections::VecDeque};
+    use core::convert::Infallible;
+    use super::*;
+    use crate::command::{self, Access, Address};
+    
+    /// in-memory bus receiving from a queue and transmitting to a vector
+    #[derive(Default)]
+    struct MemoryBus {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    }
+    impl embedded_io::ErrorType for MemoryBus {
+        type Error = Infallible;
+    }
+    impl embedded_io::Read for MemoryBus {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+            let size = buf.len().min(self.input.len());
+            for (dst, src) in buf.iter_mut().zip(self.input.drain(.. size)) {
+                *dst = src;
+            }
+            Ok(size)
+        }
+    }
+    impl embedded_io::ReadReady for MemoryBus {
+        fn read_ready(&mut self) -> Result<bool, Infallible> {
+            Ok(!self.input.is_empty())
+        }
+    }
+    impl embedded_io::Write for MemoryBus {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+    
+    const MEM: usize = registers::USER + 0x10;
+    
+    fn slave() -> Slave<BlockingBus<MemoryBus>, MEM> {
+        slave_with_frame()
+    }
+    fn slave_with_frame<const FRAME: usize>() -> Slave<BlockingBus<MemoryBus>, MEM, FRAME> {
+        Slave::new(BlockingBus(MemoryBus::default()), device())
+    }
+    fn device() -> registers::Device {
+        registers::Device {
+            model: "test".try_into().unwrap(),
+            hardware_version: "0".try_into().unwrap(),
+            software_version: "0".try_into().unwrap(),
+            serial: "".try_into().unwrap(),
+        }
+    }
+    /// serialize a complete frame as sent by the master
+    fn frame(access: Access, address: Address, data: &[u8]) -> Vec<u8> {
+        let header = Command {
+            token: 42,
+            access,
+            executed: 0,
+            address,
+            size: data.len() as u16,
+            checksum: command::checksum(data),
+        }.to_be_bytes();
+        let mut frame = Vec::from(header.as_ref());
+        frame.push(command::checksum(&header));
+        frame.extend_from_slice(data);
+        let crc = command::frame_crc(command::CRC_SEED, &frame).to_be_bytes();
+        frame.extend_from_slice(&crc[.. TRAILER]);
+        frame
+    }
+    /// send a frame to the slave and return the answered header and data
+    fn exchange<const FRAME: usize, const MAP: usize>(slave: &Slave<BlockingBus<MemoryBus>, MEM, FRAME, MAP>, frame: &[u8]) -> (Command, Vec<u8>) {
+        let output = forward(slave, frame);
+        assert_eq!(checksum(&output[.. HEADER]), output[HEADER]);
+        let (output, trailer) = output.split_at(output.len() - TRAILER);
+        assert_eq!(command::frame_crc(command::CRC_SEED, output).to_be_bytes()[.. TRAILER], *trailer);
+        let header = Command::from_be_bytes(output[.. HEADER].try_into().unwrap());
+        (header, output[HEADER+1 ..].to_vec())
+    }
+    /// send a frame to the slave and return the frame it transmits to the next one
+    fn forward<const FRAME: usize, const MAP: usize>(slave: &Slave<BlockingBus<MemoryBus>, MEM, FRAME, MAP>, frame: &[u8]) -> Vec<u8> {
+        slave.control.try_lock().unwrap().bus.0.input.extend(frame);
+        assert!(slave.poll_once().unwrap());
+        core::mem::take(&mut slave.control.try_lock().unwrap().bus.0.output)
+    }
+    /// send a frame to the slave and return true if it was processed without waiting for a lock
+    fn receive_bounded(slave: &Slave<BlockingBus<MemoryBus>, MEM>, frame: &[u8]) -> bool {
+        let mut control = slave.control.try_lock().unwrap();
+        control.bus.0.input.extend(frame);
+        // a lock never released would block forever, so bound the number of polls instead of blocking
+        let done = {
+            let mut future = pin!(control.receive_command(slave));
+            let mut context = Context::from_waker(Waker::noop());
+            (0 .. 100).any(|_| future.as_mut().poll(&mut context).is_ready())
+        };
+        control.bus.0.input.clear();
+        control.bus.0.output.clear();
+        done
+    }
+    fn topological(read: bool, write: bool) -> Access {
+        let mut access = Access::default();
+        access.set_topological(true);
+        access.set_read(read);
+        access.set_write(write);
+        access
+    }
+    
+    #[test]
+    fn compare_exchange() {
+        const FLAG: SlaveRegister<u16> = registers::Register::new(registers::USER as u16);
+        let slave = slave();
+        slave.try_lock().unwrap().set(FLAG, 5);
+        let mut access = topological(true, true);
+        access.set_operation(Operation::CompareExchange);
+        let cas = |expected: u16, new: u16| exchange(&slave, &frame(
+            access, 
+            Address::new(0, FLAG.address()), 
+            &[expected.to_be_bytes(), new.to_be_bytes()].concat(),
+            ));
+        
+        // mismatch leaves the register and answers its value
+        let (header, data) = cas(4, 6);
+        assert!(!header.access.error());
+        assert_eq!(header.executed, 1);
+        assert_eq!(header.checksum, checksum(&data));
+        assert_eq!(data, [0, 5, 0, 6]);
+        assert_eq!(slave.try_lock().unwrap().get(FLAG), 5);
+        // match swaps
+        let (_, data) = cas(5, 6);
+        assert_eq!(data, [0, 5, 0, 6]);
+        assert_eq!(slave.try_lock().unwrap().get(FLAG), 6);
+        
+        // only valid as an exchange on one slave
+        let mut write = topological(false, true);
+        write.set_operation(Operation::CompareExchange);
+        assert!(!Command {access: write, size: 4, .. Default::default()}.plausible());
+        let mut virtual_access = Access::default();
+        virtual_access.set_read(true);
+        virtual_access.set_write(true);
+        virtual_access.set_operation(Operation::CompareExchange);
+        assert!(!Command {access: virtual_access, size: 4, .. Default::default()}.plausible());
+        assert!(!Command {access, size: 3, .. Default::default()}.plausible());
+    }
+
+    #[test]
+    fn gather_two_slaves() {
+        let user = registers::USER as u16;
+        let chain = [slave(), slave()];
+        // configure each slave through the chain, the second is reached with topological address 1
+        for (index, slave) in chain.iter().enumerate() {
+            slave.try_lock().unwrap()[registers::USER ..][.. 2].copy_from_slice(&[10 + index as u8, 20 + index as u8]);
+            let table = registers::MappingTable::from_iter([
+                registers::Mapping {virtual_start: 2 * index as u32, slave_start: user, size: 2},
+                ]).unwrap();
+            let request = frame(topological(false, true), Address::new(index as u16, registers::MAPPING.address()), table.to_be_bytes().as_ref());
+            let answer = chain.iter().fold(request, |frame, slave| forward(slave, &frame));
+            assert_eq!(Command::from_be_bytes(answer[.. HEADER].try_into().unwrap()).executed, 1);
+        }
+        
+        let mut access = Access::default();
+        access.set_read(true);
+        let answer = chain.iter().fold(frame(access, Address::from(0), &[0; 4]), |frame, slave| forward(slave, &frame));
+        let header = Command::from_be_bytes(answer[.. HEADER].try_into().unwrap());
+        assert_eq!(header.executed, 2);
+        assert_eq!(header.checksum, checksum(&answer[HEADER+1 .. answer.len() - TRAILER]));
+        assert_eq!(answer[HEADER+1 .. answer.len() - TRAILER], [10, 20, 11, 21]);
+    }
+
+    #[test]
+    fn validated_write() {
+        const LIMITED: SlaveRegister<u16> = registers::Register::new(registers::USER as u16);
+        static VALIDATORS: [RegisterSpec; 1] = [RegisterSpec::new(LIMITED, |data| u16::from_be_bytes(data.try_into().unwrap()) <= 100)];
+        let slave = slave().with_validators(&VALIDATORS);
+        let write = |value: u16| frame(
+            topological(false, true), 
+            Address::new(0, LIMITED.address()), 
+            &value.to_be_bytes(),
+            );
+        
+        let (header, _) = exchange(&slave, &write(42));
+        assert!(!header.access.error());
+        assert_eq!(slave.try_lock().unwrap().get(LIMITED), 42);
+        
+        let (header, _) = exchange(&slave, &write(101));
+        assert_eq!(header.access.command_error(), registers::CommandError::InvalidAccess);
+        assert_eq!(slave.try_lock().unwrap().get(LIMITED), 42);
+        
+        // partial write
+        let (header, _) = exchange(&slave, &frame(
+            topological(false, true), 
+            Address::new(0, LIMITED.address()), 
+            &[0],
+            ));
+        assert_eq!(header.access.command_error(), registers::CommandError::InvalidAccess);
+        assert_eq!(slave.try_lock().unwrap().get(LIMITED), 42);
+    }
+    
+    #[test]
+    fn computed_register() {
+        use core::sync::atomic::{AtomicU16, Ordering::Relaxed};
+        const LIVE: SlaveRegister<u16> = registers::Register::new(registers::USER as u16);
+        static SAMPLES: AtomicU16 = AtomicU16::new(0);
+        static COMPUTED: [ComputedRegister; 1] = [ComputedRegister::new(LIVE, |data| 
+            data.copy_from_slice(&(SAMPLES.fetch_add(1, Relaxed) + 1).to_be_bytes()))];
+        let slave = slave().with_computed(&COMPUTED);
+        let access = |read, write| frame(
+            topological(read, write), 
+            Address::new(0, LIVE.address()), 
+            &[0; 2],
+            );
+        
+        // computed on each read
+        let (_, data) = exchange(&slave, &access(true, false));
+        assert_eq!(data, [0, 1]);
+        let (_, data) = exchange(&slave, &access(true, false));
+        assert_eq!(data, [0, 2]);
+        // and not writable
+        let (header, _) = exchange(&slave, &access(false, true));
+        assert_eq!(header.access.command_error(), registers::CommandError::InvalidAccess);
+        assert_eq!(SAMPLES.load(Relaxed), 2);
+    }
+    
+    #[test]
+    fn exchange_fetch_and_store() {
+        use core::sync::atomic::{AtomicU16, Ordering::Relaxed};
+        const SEQUENCE: SlaveRegister<u16> = registers::Register::new(registers::USER as u16);
+        const LIVE: SlaveRegister<u16> = registers::Register::new(registers::USER as u16 + 2);
+        static SAMPLES: AtomicU16 = AtomicU16::new(0);
+        static COMPUTED: [ComputedRegister; 1] = [ComputedRegister::new(LIVE, |data| 
+            data.copy_from_slice(&SAMPLES.fetch_add(1, Relaxed).to_be_bytes()))];
+        let slave = slave().with_computed(&COMPUTED);
+        slave.try_lock().unwrap().set(SEQUENCE, 7);
+        
+        // the old value is answered and the new one stored
+        let (header, data) = exchange(&slave, &frame(topological(true, true), Address::new(0, SEQUENCE.address()), &8u16.to_be_bytes()));
+        assert!(!header.access.error());
+        assert_eq!(data, 7u16.to_be_bytes());
+        assert_eq!(slave.try_lock().unwrap().get(SEQUENCE), 8);
+        
+        // an exchange on a computed register is rejected as a whole, without computing it nor writing its neighbours
+        let (header, data) = exchange(&slave, &frame(topological(true, true), Address::new(0, SEQUENCE.address()), &[0, 9, 0, 9]));
+        assert_eq!(header.access.command_error(), registers::CommandError::InvalidAccess);
+        assert_eq!(data, [0, 9, 0, 9]);
+        assert_eq!(slave.try_lock().unwrap().get(SEQUENCE), 8);
+        assert_eq!(SAMPLES.load(Relaxed), 0);
+    }
+    
+    #[test]
+    fn separate_virtual_regions() {
+        let slave = slave_with_frame::<MAX_COMMAND>();
+        let table = registers::MappingTable::from_iter([
+            registers::Mapping {virtual_start: 0x10_0000, slave_start: registers::USER as u16 + 2, size: 2},
+            registers::Mapping {virtual_start: 0, slave_start: registers::USER as u16, size: 2},
+            ]).unwrap();
+        exchange(&slave, &frame(
+            topological(false, true),
+            Address::new(0, registers::MAPPING.address()),
+            table.to_be_bytes().as_ref(),
+            ));
+        slave.try_lock().unwrap()[registers::USER ..][.. 4].copy_from_slice(&[1, 2, 3, 4]);
+        let read = |address: u32| {
+            let mut access = Access::default();
+            access.set_read(true);
+            exchange(&slave, &frame(access, Address::from(address), &[0; 4])).1
+        };
+        assert_eq!(read(0), [1, 2, 0, 0]);
+        assert_eq!(read(0x8_0000), [0; 4]);
+        assert_eq!(read(0x10_0000), [3, 4, 0, 0]);
+        assert_eq!(read(0x0f_fffe), [0, 0, 3, 4]);
+    }
+    
+    #[test]
+    fn validated_virtual_write() {
+        const LIMITED: SlaveRegister<u16> = registers::Register::new(registers::USER as u16);
+        const FREE: SlaveRegister<u16> = registers::Register::new(registers::USER as u16 + 2);
+        static VALIDATORS: [RegisterSpec; 1] = [RegisterSpec::new(LIMITED, |data| u16::from_be_bytes(data.try_into().unwrap()) <= 100)];
+        let slave = slave().with_validators(&VALIDATORS);
+        let table = registers::MappingTable::from_iter([
+            registers::Mapping {virtual_start: 0, slave_start: LIMITED.address(), size: 2},
+            registers::Mapping {virtual_start: 2, slave_start: FREE.address(), size: 2},
+            ]).unwrap();
+        exchange(&slave, &frame(
+            topological(false, true),
+            Address::new(0, registers::MAPPING.address()),
+            table.to_be_bytes().as_ref(),
+            ));
+        
+        let mut access = Access::default();
+        access.set_write(true);
+        let (header, _) = exchange(&slave, &frame(access, Address::from(0), &[0, 101, 0, 7]));
+        // the failure is reported in the answer, other mappings are still written
+        assert_eq!(header.access.command_error(), registers::CommandError::InvalidAccess);
+        assert_eq!(header.executed, 1);
+        let buffer = slave.try_lock().unwrap();
+        assert_eq!(buffer.get(registers::ERROR), registers::CommandError::InvalidAccess);
+        assert_eq!(buffer.get(LIMITED), 0);
+        assert_eq!(buffer.get(FREE), 7);
+    }
+
+    #[test]
+    fn initial_mapping() {
+        let user = registers::USER as u16;
+        let table = registers::MappingTable::from_iter([
+            registers::Mapping {virtual_start: 4, slave_start: user + 2, size: 2},
+            registers::Mapping {virtual_start: 0, slave_start: user, size: 2},
+            ]).unwrap();
+        let slave = Slave::<_, MEM>::new_with_mapping(BlockingBus(MemoryBus::default()), device(), table.clone());
+        slave.try_lock().unwrap()[registers::USER ..][.. 4].copy_from_slice(&[1, 2, 3, 4]);
+        
+        let mut access = Access::default();
+        access.set_read(true);
+        let (header, data) = exchange(&slave, &frame(access, Address::from(0), &[0; 6]));
+        assert_eq!(header.executed, 1);
+        assert_eq!(data, [1, 2, 0, 0, 3, 4]);
+        // the table is readable by the master like a written one
+        assert_eq!(slave.try_lock().unwrap().get(registers::MAPPING).map, table.map);
+    }
+    #[test]
+    #[should_panic(expected = "mapping table has mappings out of the slave buffer")]
+    fn initial_mapping_out_of_range() {
+        let table = registers::MappingTable::from_iter([
+            registers::Mapping {virtual_start: 0, slave_start: MEM as u16 - 1, size: 2},
+            ]).unwrap();
+        Slave::<_, MEM>::new_with_mapping(BlockingBus(MemoryBus::default()), device(), table);
+    }
+
+    #[test]
+    fn virtual_overlapping_mappings() {
+        let slave = slave();
+        let user = registers::USER as u16;
+        let table = registers::MappingTable::from_iter([
+            registers::Mapping {virtual_start: 2, slave_start: user + 16, size: 1},
+            registers::Mapping {virtual_start: 0, slave_start: user + 8, size: 8},
+            registers::Mapping {virtual_start: 10, slave_start: user, size: 2},
+            registers::Mapping {virtual_start: 10, slave_start: user + 4, size: 2},
+            ]).unwrap();
+        let (header, _) = exchange(&slave, &frame(
+            topological(false, true),
+            Address::new(0, registers::MAPPING.address()),
+            table.to_be_bytes().as_ref(),
+            ));
+        assert!(!header.access.error());
+        {
+            let mut buffer = slave.try_lock().unwrap();
+            buffer[registers::USER ..][.. 6].copy_from_slice(&[1, 2, 0, 0, 3, 4]);
+            buffer[registers::USER + 8 ..][.. 8].copy_from_slice(&[10, 11, 12, 13, 14, 15, 16, 17]);
+        }
+        let read = |address: u32, size: usize| {
+            let mut access = Access::default();
+            access.set_read(true);
+            exchange(&slave, &frame(access, Address::from(address), &std::vec![0; size]))
         };
-    let requested_range = Range {
-        start: address,
-        end: address + u32::from(frame.size),
+        
+        // a long mapping starting before a shorter one is still found
+        let (header, data) = read(4, 2);
+        assert_eq!(header.executed, 1);
+        assert_eq!(data, [14, 15]);
+        // mappings with the same start are applied in table order
+        let (_, data) = read(10, 2);
+        assert_eq!(data, [3, 4]);
+        
+        let mut access = Access::default();
+        access.set_write(true);
+        exchange(&slave, &frame(access, Address::from(10), &[5, 6]));
+        let buffer = slave.try_lock().unwrap();
+        assert_eq!(buffer[registers::USER ..][.. 6], [5, 6, 0, 0, 5, 6]);
+    }
+
+    #[cfg(feature = "tokio-io")]
+    #[test]
+    fn tokio_bus() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let (bus, mut master) = tokio::io::duplex(256);
+        let slave = Slave::<_, MEM>::new(TokioBus(bus), device());
+        let request = frame(topological(true, false), Address::new(0, registers::VERSION.address()), &[0]);
+        let mut answer = std::vec![0; request.len()];
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            master.write_all(&request).await.unwrap();
+            let mut run = pin!(slave.run());
+            let mut receive = pin!(master.read_exact(&mut answer));
+            poll_fn(|context| {
+                let _ = run.as_mut().poll(context);
+                receive.as_mut().poll(context)
+            }).await.unwrap();
+        });
+        let header = Command::from_be_bytes(answer[.. HEADER].try_into().unwrap());
+        assert_eq!(header.executed, 1);
+        assert_eq!(answer[HEADER+1 .. answer.len() - TRAILER], [registers::PROTOCOL_VERSION]);
+    }
+    
+    #[test]
+    fn oversized_command() {
+        let slave = slave_with_frame::<16>();
+        let data = (0 .. 32).collect::<Vec<u8>>();
+        // commands for other slaves are relayed unchanged
+        let output = forward(&slave, &frame(topological(true, false), Address::new(1, registers::USER as u16), &data));
+        let header = Command::from_be_bytes(output[.. HEADER].try_into().unwrap());
+        assert!(!header.access.error());
+        assert_eq!(header.address.slave(), 0);
+        assert_eq!(output[HEADER+1 .. output.len() - TRAILER], data);
+        assert_eq!(slave.try_lock().unwrap().get(registers::LOSS), 0);
+        // commands for this slave are reported
+        let (header, answer) = exchange(&slave, &frame(topological(true, false), Address::new(0, registers::USER as u16), &data));
+        assert_eq!(header.access.command_error(), registers::CommandError::InvalidSize);
+        assert_eq!(answer, data);
+        assert_eq!(slave.try_lock().unwrap().get(registers::LOSS), 1);
+        assert_eq!(slave.try_lock().unwrap().get(registers::LOSS_REASONS), registers::LossReasons {oversized: 1, ..Default::default()});
+        // the next command is processed right after
+        let (header, answer) = exchange(&slave, &frame(topological(true, false), Address::new(0, registers::VERSION.address()), &[0]));
+        assert_eq!(header.executed, 1);
+        assert_eq!(answer, [registers::PROTOCOL_VERSION]);
+    }
+    
+    #[test]
+    fn huge_size_field() {
+        let slave = slave();
+        // a header claiming more than any command can hold is noise, skipped without reading its claimed data
+        let mut corrupted = Command {
+            token: 42,
+            access: topological(true, false),
+            executed: 0,
+            address: Address::new(0, registers::VERSION.address()),
+            size: u16::MAX,
+            checksum: 0,
+        }.to_be_bytes().to_vec();
+        corrupted.push(checksum(&corrupted));
+        corrupted.extend(frame(topological(true, false), Address::new(0, registers::VERSION.address()), &[0]));
+        let (header, answer) = exchange(&slave, &corrupted);
+        assert_eq!(header.executed, 1);
+        assert_eq!(answer, [registers::PROTOCOL_VERSION]);
+    }
+
+    #[cfg(feature = "frame-crc")]
+    #[test]
+    fn corrupted_trailer() {
+        let slave = slave();
+        let value = [1, 2];
+        let mut request = frame(topological(false, true), Address::new(0, registers::USER as u16), &value);
+        *request.last_mut().unwrap() ^= 1;
+        // the write is rejected, and the answer keeps a wrong trailer
+        let output = forward(&slave, &request);
+        let header = Command::from_be_bytes(output[.. HEADER].try_into().unwrap());
+        assert_eq!(header.executed, 0);
+        assert_eq!(header.access.command_error(), registers::CommandError::Checksum);
+        assert_eq!(output[HEADER+1 ..][.. 2], value);
+        assert_ne!(command::frame_crc(command::CRC_SEED, &output[.. output.len() - TRAILER]).to_be_bytes()[.. TRAILER], output[output.len() - TRAILER ..]);
+        assert_eq!(slave.try_lock().unwrap()[registers::USER ..][.. 2], [0, 0]);
+        assert_eq!(slave.try_lock().unwrap().get(registers::LOSS), 1);
+    }
+
+    #[test]
+    fn chunked_mapping() {
+        const FRAME: usize = 64;
+        const ENTRY: usize = <registers::Mapping as FromBytes>::Bytes::SIZE;
+        let slave = slave_with_frame::<FRAME>();
+        let entries = (0 .. 100u16)
+            .flat_map(|i| registers::Mapping {
+                virtual_start: u32::from(i), 
+                slave_start: registers::USER as u16 + i % 16, 
+                size: 1,
+                }.to_be_bytes())
+            .collect::<Vec<u8>>();
+        slave.try_lock().unwrap()[registers::USER ..][.. 16].copy_from_slice(&core::array::from_fn::<u8, 16, _>(|i| i as u8 + 1));
+        let read = || {
+            let mut access = Access::default();
+            access.set_read(true);
+            exchange(&slave, &frame(access, Address::from(96), &[0; 4])).1
         };
-    let intersection = Range {
-        start: virtual_range.start.max(requested_range.start),
-        end: virtual_range.end.min(requested_range.end),
+        
+        let chunk = (FRAME - 1) / ENTRY * ENTRY;
+        for (i, part) in entries.chunks(chunk).enumerate() {
+            let (header, _) = exchange(&slave, &frame(
+                topological(false, true),
+                Address::new(0, registers::MAPPING.address() + 1 + (i * chunk) as u16),
+                part,
+                ));
+            assert!(!header.access.error());
+            // table not applied until its size is written
+            assert_eq!(read(), [0; 4]);
+        }
+        exchange(&slave, &frame(
+            topological(false, true),
+            Address::new(0, registers::MAPPING.address()),
+            &[100],
+            ));
+        assert_eq!(read(), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn corrupted_write() {
+        let slave = slave();
+        let mut corrupted = frame(
+            topological(false, true),
+            Address::new(0, registers::USER as u16),
+            &[1, 2, 3, 4],
+            );
+        let end = corrupted.len() - TRAILER;
+        corrupted[end - 1] ^= 0xff;
+        let output = forward(&slave, &corrupted);
+        let header = Command::from_be_bytes(output[.. HEADER].try_into().unwrap());
+        assert_eq!(header.access.command_error(), registers::CommandError::Checksum);
+        assert_eq!(header.executed, 0);
+        assert_eq!(output[HEADER+1 ..][.. 4], corrupted[end - 4 .. end]);
+        let buffer = slave.try_lock().unwrap();
+        assert_eq!(buffer[registers::USER .. registers::USER + 4], [0; 4]);
+        assert_eq!(buffer.get(registers::LOSS), 1);
+        assert_eq!(buffer.get(registers::LOSS_REASONS), registers::LossReasons {checksum: 1, ..Default::default()});
+    }
+
+    #[test]
+    fn loss_threshold() {
+        let slave = slave().with_loss_policy(LossPolicy::Threshold(2));
+        let mut corrupted = frame(
+            topological(false, true),
+            Address::new(0, registers::USER as u16),
+            &[1, 2, 3, 4],
+            );
+        let end = corrupted.len() - TRAILER;
+        corrupted[end - 1] ^= 0xff;
+        let answer = |output: Vec<u8>| Command::from_be_bytes(output[.. HEADER].try_into().unwrap());
+        let header = answer(forward(&slave, &corrupted));
+        assert_eq!(header.access.command_error(), registers::CommandError::Checksum);
+        assert_eq!(slave.try_lock().unwrap().get(registers::LOSS), 1);
+        slave.try_lock().unwrap().set(registers::ERROR, registers::CommandError::None);
+        // reaching the threshold is reported and restarts the count
+        let header = answer(forward(&slave, &corrupted));
+        assert_eq!(header.access.command_error(), registers::CommandError::LossThreshold);
+        let buffer = slave.try_lock().unwrap();
+        assert_eq!(buffer.get(registers::LOSS), 0);
+        assert_eq!(buffer.get(registers::ERROR), registers::CommandError::LossThreshold);
+    }
+    
+    #[test]
+    fn loss_wrap() {
+        let slave = slave().with_loss_policy(LossPolicy::Wrap);
+        slave.try_lock().unwrap().set(registers::LOSS, u16::MAX);
+        slave.try_lock().unwrap().add_loss(LossReason::Bus).unwrap();
+        assert_eq!(slave.try_lock().unwrap().get(registers::LOSS), 0);
+    }
+
+    #[test]
+    fn unassigned_address() {
+        let slave = slave();
+        let mut fixed = Access::default();
+        fixed.set_fixed(true);
+        fixed.set_read(true);
+        let read = |slave: &_, address| exchange(slave, &frame(fixed, Address::new(address, registers::VERSION.address()), &[0]));
+        
+        // an unassigned slave is not reached by fixed addresses, even its default one
+        assert_eq!(read(&slave, 0).0.executed, 0);
+        assert_eq!(read(&slave, registers::UNASSIGNED).0.executed, 0);
+        
+        exchange(&slave, &frame(topological(false, true), Address::new(0, registers::ADDRESS.address()), &3u16.to_be_bytes()));
+        let (header, data) = read(&slave, 3);
+        assert_eq!(header.executed, 1);
+        assert_eq!(data, [registers::PROTOCOL_VERSION]);
+    }
+    
+    #[test]
+    fn address_exchange() {
+        let slave = slave();
+        let assign = |address: u16| exchange(&slave, &frame(topological(true, true), Address::new(0, registers::ADDRESS.address()), &address.to_be_bytes()));
+        let mut fixed = Access::default();
+        fixed.set_fixed(true);
+        fixed.set_read(true);
+        let read = |address| exchange(&slave, &frame(fixed, Address::new(address, registers::ADDRESS.address()), &[0; 2]));
+        
+        // the answer holds the address before the write
+        let (header, data) = assign(3);
+        assert_eq!(header.executed, 1);
+        assert_eq!(data, registers::UNASSIGNED.to_be_bytes());
+        let (header, data) = assign(5);
+        assert_eq!(header.executed, 1);
+        assert_eq!(data, 3u16.to_be_bytes());
+        // and the new address applies right after the frame
+        assert_eq!(read(3).0.executed, 0);
+        let (header, data) = read(5);
+        assert_eq!(header.executed, 1);
+        assert_eq!(data, 5u16.to_be_bytes());
+    }
+
+    #[test]
+    fn mapping_capacity() {
+        let slave = Slave::<_, MEM, MAX_COMMAND, 2>::new(BlockingBus(MemoryBus::default()), device());
+        assert_eq!(slave.try_lock().unwrap().get(registers::MAPPING_CAPACITY), 2);
+        let entry = |i: u16| registers::Mapping {virtual_start: u32::from(i) * 2, slave_start: registers::USER as u16 + i * 2, size: 2};
+        let write = |count: u16| {
+            let table = registers::MappingTable::from_iter((0 .. count).map(entry)).unwrap();
+            exchange(&slave, &frame(topological(false, true), Address::new(0, registers::MAPPING.address()), table.to_be_bytes().as_ref())).0
         };
-    if intersection.end <= intersection.start
-        {return None}
-    
-    Some((
-        Range {
-            start: usize::try_from(intersection.start - address).unwrap(),
-            end: usize::try_from(intersection.end - address).unwrap(),
-        },
-        Range {
-            start: usize::try_from(intersection.start - mapped.virtual_start).unwrap() + usize::from(mapped.slave_start),
-            end: usize::try_from(intersection.end - mapped.virtual_start).unwrap() + usize::from(mapped.slave_start),
-        },
-    ))
+        assert!(!write(2).access.error());
+        assert_eq!(slave.control.try_lock().unwrap().mapping.len(), 2);
+        // a bigger table is rejected as a whole
+        write(3);
+        assert_eq!(slave.try_lock().unwrap().get(registers::ERROR), registers::CommandError::InvalidMapping);
+        assert!(slave.control.try_lock().unwrap().mapping.is_empty());
+    }
+    
+    #[test]
+    fn control_reset() {
+        let slave = slave();
+        let table = registers::MappingTable::from_iter([
+            registers::Mapping {virtual_start: 0, slave_start: registers::USER as u16, size: 2},
+            ]).unwrap();
+        let write = |address, data: &[u8]| exchange(&slave, &frame(topological(false, true), Address::new(0, address), data));
+        write(registers::MAPPING.address(), table.to_be_bytes().as_ref());
+        write(registers::ADDRESS.address(), &3u16.to_be_bytes());
+        {
+            let mut buffer = slave.try_lock().unwrap();
+            buffer.set(registers::LOSS, 4);
+            buffer.set(registers::LOSS_REASONS, registers::LossReasons {bus: 4, ..Default::default()});
+            buffer.set(registers::ERROR, registers::CommandError::InvalidAccess);
+            buffer[registers::USER ..][.. 2].copy_from_slice(&[1, 2]);
+        }
+        
+        let (header, _) = write(registers::CONTROL.address(), &[registers::CONTROL_RESET]);
+        assert!(!header.access.error());
+        {
+            let buffer = slave.try_lock().unwrap();
+            assert_eq!(buffer.get(registers::LOSS), 0);
+            assert_eq!(buffer.get(registers::LOSS_REASONS), registers::LossReasons::default());
+            assert_eq!(buffer.get(registers::ERROR), registers::CommandError::None);
+            assert_eq!(buffer.get(registers::ADDRESS), registers::UNASSIGNED);
+            assert_eq!(buffer.get(registers::MAPPING).size, 0);
+            assert_eq!(buffer.get(registers::CONTROL), 0);
+        }
+        // the mapping is no longer applied
+        let mut access = Access::default();
+        access.set_read(true);
+        let (_, data) = exchange(&slave, &frame(access, Address::from(0), &[0; 2]));
+        assert_eq!(data, [0, 0]);
+    }
+
+    #[test]
+    fn buffer_size() {
+        let slave = slave();
+        let (_, data) = exchange(&slave, &frame(
+            topological(true, false),
+            Address::new(0, registers::BUFFER_SIZE.address()),
+            &[0; 2],
+            ));
+        assert_eq!(data, (MEM as u16).to_be_bytes());
+
+        // read-only
+        let (header, _) = exchange(&slave, &frame(
+            topological(false, true),
+            Address::new(0, registers::BUFFER_SIZE.address()),
+            &[0; 2],
+            ));
+        assert_eq!(header.access.command_error(), registers::CommandError::InvalidAccess);
+        assert_eq!(slave.try_lock().unwrap().get(registers::BUFFER_SIZE), MEM as u16);
+        
+        // other read-only standard registers, the master relies on them
+        let (header, _) = exchange(&slave, &frame(
+            topological(false, true),
+            Address::new(0, registers::MAPPING_CAPACITY.address()),
+            &[0],
+            ));
+        assert_eq!(header.access.command_error(), registers::CommandError::InvalidAccess);
+        assert_eq!(slave.try_lock().unwrap().get(registers::MAPPING_CAPACITY), registers::MAPPING_ENTRIES as u8);
+        let (header, _) = exchange(&slave, &frame(
+            topological(false, true),
+            Address::new(0, registers::VERSION.address()),
+            &[0],
+            ));
+        assert_eq!(header.access.command_error(), registers::CommandError::InvalidAccess);
+        assert_eq!(slave.try_lock().unwrap().get(registers::VERSION), registers::PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn clock_at_arrival() {
+        let slave = slave().with_clock(|| 1234);
+        let (_, data) = exchange(&slave, &frame(
+            topological(true, false), 
+            Address::new(0, registers::CLOCK.address()), 
+            &[0; 8],
+            ));
+        assert_eq!(data, 1234u64.to_be_bytes());
+    }
+    
+    #[test]
+    fn error_code_in_reply() {
+        let slave = slave();
+        let (header, _) = exchange(&slave, &frame(
+            topological(true, false), 
+            Address::new(0, (MEM - 1) as u16), 
+            &[0, 0],
+            ));
+        assert!(header.access.error());
+        assert!(header.plausible());
+        assert_eq!(header.access.command_error(), registers::CommandError::InvalidRegister);
+        assert_eq!(slave.try_lock().unwrap().get(registers::ERROR), registers::CommandError::InvalidRegister);
+    }
+    
+    #[test]
+    fn segments() {
+        const VALUE: SlaveRegister<u32> = registers::Register::new(MEM as u16 + 4);
+        static SEGMENT: SlaveSegment<8> = SlaveSegment::new(MEM as SegmentAddress);
+        static SEGMENTS: [&dyn Segment; 1] = [&SEGMENT];
+        let slave = slave().with_segments(&SEGMENTS);
+
+        // the slave buffer being locked does not prevent accessing the segment
+        let buffer = slave.try_lock().unwrap();
+        let (header, _) = exchange(&slave, &frame(
+            topological(false, true),
+            Address::new(0, VALUE.address()),
+            &1234u32.to_be_bytes(),
+            ));
+        assert!(!header.access.error());
+        drop(buffer);
+        assert_eq!(SEGMENT.try_lock().unwrap().get(VALUE), 1234);
+
+        // command across slave buffer and segment
+        slave.try_lock().unwrap()[MEM-2 ..].copy_from_slice(&[1, 2]);
+        let (header, data) = exchange(&slave, &frame(
+            topological(true, false),
+            Address::new(0, (MEM - 2) as u16),
+            &[0; 10],
+            ));
+        assert!(!header.access.error());
+        assert_eq!(data, [1, 2, 0, 0, 0, 0, 0, 0, 0x04, 0xd2]);
+
+        // command beyond segment
+        let (header, _) = exchange(&slave, &frame(
+            topological(true, false),
+            Address::new(0, VALUE.address()),
+            &[0; 5],
+            ));
+        assert_eq!(header.access.command_error(), registers::CommandError::InvalidRegister);
+    }
+
+    #[cfg(feature = "extended-address")]
+    #[test]
+    fn extended_address() {
+        // segment beyond 16 bit addresses
+        static FAR: SlaveSegment<8> = SlaveSegment::new(0x1_0000);
+        static SEGMENTS: [&dyn Segment; 1] = [&FAR];
+        let slave = slave().with_segments(&SEGMENTS);
+        let mut extended = Access::default();
+        extended.set_fixed(true);
+        extended.set_topological(true);
+        extended.set_write(true);
+        let write = frame(extended, Address::from(0x1_0002), &[1, 2]);
+        
+        // slaves not selected pass the command
+        let (header, _) = exchange(&slave, &write);
+        assert_eq!(header.executed, 0);
+        assert!(!header.access.error());
+        assert_eq!(FAR.try_lock().unwrap()[2 .. 4], [0, 0]);
+        // selected slaves execute it
+        exchange(&slave, &frame(topological(false, true), Address::new(0, registers::SELECT.address()), &[1]));
+        let (header, _) = exchange(&slave, &write);
+        assert_eq!(header.executed, 1);
+        assert_eq!(FAR.try_lock().unwrap()[2 .. 4], [1, 2]);
+        extended.set_write(false);
+        extended.set_read(true);
+        let (header, data) = exchange(&slave, &frame(extended, Address::from(0x1_0000), &[0; 4]));
+        assert_eq!(header.executed, 1);
+        assert_eq!(data, [0, 0, 1, 2]);
+        // reset deselects
+        exchange(&slave, &frame(topological(false, true), Address::new(0, registers::CONTROL.address()), &[registers::CONTROL_RESET]));
+        let (header, _) = exchange(&slave, &write);
+        assert_eq!(header.executed, 0);
+    }
+    
+    #[cfg(feature = "extended-address")]
+    #[test]
+    fn oversized_extended_address() {
+        let slave = slave_with_frame::<16>();
+        let mut extended = Access::default();
+        extended.set_fixed(true);
+        extended.set_topological(true);
+        extended.set_read(true);
+        let request = frame(extended, Address::from(0x1_0002), &[0; 32]);
+        // the address is relayed unchanged, by slaves selected or not
+        for select in [0, 1] {
+            exchange(&slave, &frame(topological(false, true), Address::new(0, registers::SELECT.address()), &[select]));
+            let output = forward(&slave, &request);
+            let header = Command::from_be_bytes(output[.. HEADER].try_into().unwrap());
+            assert_eq!(u32::from(header.address), 0x1_0002);
+            assert_eq!(header.access.error(), select != 0);
+        }
+    }
+
+    /// serialize a header with a valid checksum
+    fn header(header: Command) -> Vec<u8> {
+        let header = header.to_be_bytes();
+        let mut frame = Vec::from(header.as_ref());
+        frame.push(command::checksum(&header));
+        frame
+    }
+    
+    #[test]
+    fn resync_rejects_implausible_headers() {
+        let slave = slave();
+        let request = frame(
+            topological(true, false), 
+            Address::new(0, registers::VERSION.address()), 
+            &[0],
+            );
+        let mut input = Vec::new();
+        let garbage = Command {
+            token: 0x5a5a, 
+            address: Address::new(0x1234, 0x5678), 
+            checksum: 0x99,
+            .. Default::default()
+            };
+        // reserved access bits set
+        input.extend(header(Command {access: Access::from(0b0001_1001), size: 1, .. garbage}));
+        // size exceeding any possible command
+        input.extend(header(Command {access: topological(true, false), size: u16::MAX, .. garbage}));
+        input.extend(&request);
+        
+        let (header, data) = exchange(&slave, &input);
+        assert_eq!(header.token, 42);
+        assert_eq!(header.executed, 1);
+        assert_eq!(data, [registers::PROTOCOL_VERSION]);
+        assert_eq!(slave.try_lock().unwrap().get(registers::LOSS), 0);
+    }
+    
+    #[test]
+    fn error_does_not_relock() {
+        const LIMITED: SlaveRegister<u16> = registers::Register::new(registers::USER as u16);
+        static VALIDATORS: [RegisterSpec; 1] = [RegisterSpec::new(LIMITED, |data| data == [0, 0])];
+        let slave = slave().with_validators(&VALIDATORS);
+        let table = registers::MappingTable::from_iter([
+            registers::Mapping {virtual_start: 0, slave_start: LIMITED.address(), size: 2},
+            ]).unwrap();
+        exchange(&slave, &frame(topological(false, true), Address::new(0, registers::MAPPING.address()), table.to_be_bytes().as_ref()));
+        
+        let mut corrupted = frame(topological(false, true), Address::new(0, LIMITED.address()), &[0, 0]);
+        *corrupted.last_mut().unwrap() = 1;
+        let mut invalid = topological(false, true);
+        invalid.set_fixed(true);
+        let mut virtual_write = Access::default();
+        virtual_write.set_write(true);
+        let frames = [
+            corrupted,
+            frame(invalid, Address::new(0, LIMITED.address()), &[0, 0]),
+            frame(topological(false, true), Address::new(0, MEM as u16), &[0, 0]),
+            frame(topological(false, true), Address::new(0, LIMITED.address()), &[0, 1]),
+            frame(virtual_write, Address::from(0), &[0, 1]),
+            ];
+        for frame in frames {
+            assert!(receive_bounded(&slave, &frame), "slave buffer locked twice");
+        }
+        assert!(slave.try_lock().is_some());
+    }
+    
+    #[test]
+    fn forward_while_locked() {
+        let slave = slave();
+        let table = registers::MappingTable::from_iter([
+            registers::Mapping {virtual_start: 0, slave_start: registers::USER as u16, size: 2},
+            ]).unwrap();
+        exchange(&slave, &frame(topological(false, true), Address::new(0, registers::MAPPING.address()), table.to_be_bytes().as_ref()));
+        exchange(&slave, &frame(topological(false, true), Address::new(0, registers::ADDRESS.address()), &3u16.to_be_bytes()));
+        
+        let mut fixed = Access::default();
+        fixed.set_fixed(true);
+        fixed.set_write(true);
+        let mut virtual_write = Access::default();
+        virtual_write.set_write(true);
+        let mut corrupted = frame(virtual_write, Address::from(8), &[1, 2]);
+        let end = corrupted.len() - TRAILER;
+        corrupted[end - 1] = 0;
+        
+        let _guard = slave.try_lock().unwrap();
+        // commands for other slaves or unmapped virtual memory do not wait for the user task
+        assert!(receive_bounded(&slave, &frame(topological(false, true), Address::new(1, registers::USER as u16), &[1, 2])));
+        assert!(receive_bounded(&slave, &frame(fixed, Address::new(4, registers::USER as u16), &[1, 2])));
+        assert!(receive_bounded(&slave, &frame(virtual_write, Address::from(2), &[1, 2])));
+        assert!(receive_bounded(&slave, &corrupted));
+        // commands for this slave wait
+        assert!(!receive_bounded(&slave, &frame(virtual_write, Address::from(0), &[1, 2])));
+    }
+
+    #[test]
+    fn poll_once() {
+        let slave = slave();
+        assert!(!slave.poll_once().unwrap());
+        
+        let (header, data) = exchange(&slave, &frame(
+            topological(true, false), 
+            Address::new(0, registers::VERSION.address()), 
+            &[0],
+            ));
+        assert_eq!(header.executed, 1);
+        assert_eq!(data, [registers::PROTOCOL_VERSION]);
+        assert!(!slave.poll_once().unwrap());
+    }
+    
+    #[test]
+    fn zero_size_read() {
+        let slave = slave();
+        let (header, data) = exchange(&slave, &frame(topological(true, false), Address::new(0, registers::VERSION.address()), &[]));
+        assert_eq!(header.executed, 1);
+        assert!(!header.access.error());
+        assert!(data.is_empty());
+        // nothing is read, so the probe does not wait for the user task
+        let _guard = slave.try_lock().unwrap();
+        assert!(receive_bounded(&slave, &frame(topological(true, false), Address::new(0, registers::VERSION.address()), &[])));
+    }
+    
+    #[test]
+    #[should_panic(expected = "run function is already running")]
+    fn single_bus_owner() {
+        let slave = slave();
+        // the control, including the mapping, cannot be used by two tasks processing commands
+        let _running = slave.control.try_lock().unwrap();
+        slave.poll_once().ok();
+    }
+    
+    #[test]
+    fn direction_control() {
+        use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed};
+        static DRIVING: AtomicBool = AtomicBool::new(false);
+        static SWITCHES: AtomicUsize = AtomicUsize::new(0);
+        let slave = slave().with_direction_control(|transmit| {
+            DRIVING.store(transmit, Relaxed);
+            SWITCHES.fetch_add(1, Relaxed);
+        });
+        let (header, data) = exchange(&slave, &frame(topological(true, false), Address::new(0, registers::VERSION.address()), &[0]));
+        assert_eq!(header.executed, 1);
+        assert_eq!(data, [registers::PROTOCOL_VERSION]);
+        // enabled for the answer, then released
+        assert_eq!(SWITCHES.load(Relaxed), 2);
+        assert!(!DRIVING.load(Relaxed));
+    }
+    
+    /// bus receiving from a queue and failing all transmissions
+    #[derive(Default)]
+    struct FailingBus {
+        input: VecDeque<u8>,
+    }
+    impl embedded_io::ErrorType for FailingBus {
+        type Error = embedded_io::ErrorKind;
+    }
+    impl embedded_io::Read for FailingBus {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let size = buf.len().min(self.input.len());
+            for (dst, src) in buf.iter_mut().zip(self.input.drain(.. size)) {
+                *dst = src;
+            }
+            Ok(size)
+        }
+    }
+    impl embedded_io::ReadReady for FailingBus {
+        fn read_ready(&mut self) -> Result<bool, Self::Error> {
+            Ok(!self.input.is_empty())
+        }
+    }
+    impl embedded_io::Write for FailingBus {
+        fn write(&mut self, _buf: &[u8]) -> Result<usize, Self::Error> {
+            Err(embedded_io::ErrorKind::BrokenPipe)
+        }
+        fn flush(&mut self) -> Result<(), Self::Error> {
+            Err(embedded_io::ErrorKind::BrokenPipe)
+        }
+    }
+    
+    #[test]
+    fn direction_released_on_error() {
+        use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed};
+        static DRIVING: AtomicBool = AtomicBool::new(false);
+        static SWITCHES: AtomicUsize = AtomicUsize::new(0);
+        let slave = Slave::<_, MEM, 16>::new(BlockingBus(FailingBus::default()), device())
+            .with_direction_control(|transmit| {
+                DRIVING.store(transmit, Relaxed);
+                SWITCHES.fetch_add(1, Relaxed);
+            });
+        // an answer, then an oversized command relayed
+        for data in [&[0][..], &[0; 32]] {
+            slave.control.try_lock().unwrap().bus.0.input
+                .extend(frame(topological(true, false), Address::new(0, registers::VERSION.address()), data));
+            assert!(slave.poll_once().is_err());
+            slave.control.try_lock().unwrap().bus.0.input.clear();
+            assert!(!DRIVING.load(Relaxed));
+        }
+        assert_eq!(SWITCHES.load(Relaxed), 4);
+    }
 }
@@ -0,0 +1,80 @@
+/*!
+    run slaves in the master's process, to test master code without hardware.
+
+    Each simulated slave is a real [Slave] running in a tokio task, chained to the others through in-memory pipes. So the whole master and slave frame processing is exercised, only the electrical link is missing.
+    
+    The master only drives serial ports, so it is linked to the chain through a pseudo-terminal. This module hence needs a unix system providing pseudo-terminals
+*/
+use std::{
+    sync::Arc,
+    vec::Vec,
+    pin::Pin,
+    boxed::Box,
+    };
+use serial2_tokio::SerialPort;
+use tokio::{
+    io::{Join, AsyncRead, AsyncWrite},
+    task::JoinHandle,
+    };
+
+use crate::{
+    registers::Device,
+    slave::{Slave, TokioBus},
+    master::{Master, SerialConfig, TokioStream},
+    };
+
+
+/**
+    create a master connected to a chain of `count` simulated slaves, in topological order
+
+    the slaves all get the given device informations. This must be called from within a tokio runtime, and the returned master must be [run](Master::run) like a master on a real bus
+*/
+pub fn simulate<const MEM: usize>(count: usize, device: Device) -> Result<(Master, Vec<SimulatedSlave<MEM>>), std::io::Error> {
+    assert!(count > 0, "a bus needs at least one slave");
+    let (master, first) = SerialPort::pair()?;
+    // each slave transmits to the next one, and the last slave answers back to the master
+    let mut receives: Vec<Pin<Box<dyn AsyncRead + Send + Sync>>> = std::vec![Box::pin(first.try_clone()?)];
+    let mut transmits: Vec<Pin<Box<dyn AsyncWrite + Send + Sync>>> = Vec::with_capacity(count);
+    for _ in 1 .. count {
+        let (receive, transmit) = tokio::io::simplex(PIPE_CAPACITY);
+        transmits.push(Box::pin(transmit));
+        receives.push(Box::pin(receive));
+    }
+    transmits.push(Box::pin(first));
+    let slaves = receives.into_iter().zip(transmits)
+        .map(|(receive, transmit)| SimulatedSlave::new(TokioBus(tokio::io::join(receive, transmit)), device.clone()))
+        .collect();
+    let clone = master.try_clone()?;
+    Ok((Master::with_streams(Box::new(TokioStream(master)), Box::new(TokioStream(clone)), SerialConfig::new(0)), slaves))
+}
+
+/// slave running in a tokio task, stopped when dropped
+pub struct SimulatedSlave<const MEM: usize> {
+    slave: Arc<Slave<SimulatedBus, MEM>>,
+    task: JoinHandle<()>,
+}
+impl<const MEM: usize> SimulatedSlave<MEM> {
+    fn new(bus: SimulatedBus, device: Device) -> Self {
+        let slave = Arc::new(Slave::new(bus, device));
+        let task = tokio::spawn({
+            let slave = slave.clone();
+            async move {slave.run().await}
+        });
+        Self {slave, task}
+    }
+    /// the slave being simulated, to access its buffer like a slave program would
+    pub fn slave(&self) -> &Slave<SimulatedBus, MEM> {
+        &self.slave
+    }
+}
+impl<const MEM: usize> Drop for SimulatedSlave<MEM> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// bytes buffered in the links between simulated slaves, enough for a few maximum size frames
+const PIPE_CAPACITY: usize = 4 * crate::MAX_COMMAND;
+
+/// link of a simulated slave, receiving from the previous device on the bus and transmitting to the next one
+pub type SimulatedBus = TokioBus<Join<Pin<Box<dyn AsyncRead + Send + Sync>>, Pin<Box<dyn AsyncWrite + Send + Sync>>>>;